@@ -0,0 +1,186 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::auth;
+use crate::auth::AuthData;
+use crate::client::MatrixClient;
+use crate::threepid;
+use crate::threepid::{ThreepidCredentials, ThreepidSession, TokenPurpose};
+
+/*
+Account
+Management of the logged in user's account
+
+docs: https://matrix.org/docs/spec/client_server/latest#account-registration-and-management
+*/
+
+pub static PASSWORD_ENDPOINT: &str = "/_matrix/client/r0/account/password";
+pub static DEACTIVATE_ENDPOINT: &str = "/_matrix/client/r0/account/deactivate";
+pub static WHOAMI_ENDPOINT: &str = "/_matrix/client/r0/account/whoami";
+
+#[derive(Serialize, Debug)]
+pub struct ChangePasswordRequest {
+  pub new_password: String,
+  // Whether other access tokens and devices should be revoked, defaults to true
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub logout_devices: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub auth: Option<AuthData>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeactivateRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub auth: Option<AuthData>,
+  // Identity server to unbind all 3PIDs from
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_server: Option<String>,
+  // Whether the user's messages should be hidden from future users
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub erase: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum UnbindResult {
+  #[serde(rename = "success")]
+  Success,
+  #[serde(rename = "no-support")]
+  NoSupport,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeactivateResponse {
+  pub id_server_unbind_result: UnbindResult,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WhoAmIResponse {
+  pub user_id: String,
+  pub device_id: Option<String>,
+  pub is_guest: Option<bool>,
+}
+
+pub fn change_password(client: &MatrixClient, request: ChangePasswordRequest) -> Result<()> {
+  let response = api::post(client, PASSWORD_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::UNAUTHORIZED => Err(auth::interactive_auth_error(response)),
+    StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn deactivate(client: &MatrixClient, request: DeactivateRequest) -> Result<DeactivateResponse> {
+  let mut response = api::post(client, DEACTIVATE_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(auth::interactive_auth_error(response)),
+    StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn whoami(client: &MatrixClient) -> Result<WhoAmIResponse> {
+  let mut response = api::get(client, WHOAMI_ENDPOINT)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Set a previously saved access token on the client, keeping it only if the server still accepts it
+pub fn restore_session(client: &mut MatrixClient, access_token: String) -> Result<WhoAmIResponse> {
+  client.set_access_token(access_token);
+
+  match whoami(client) {
+    Ok(whoami) => {
+      client.set_user(whoami.user_id.clone(), whoami.device_id.clone());
+      Ok(whoami)
+    }
+    Err(e) => {
+      client.remove_access_token();
+      Err(e)
+    }
+  }
+}
+
+// Email a password reset link to the address, recording the validation session
+pub fn request_password_reset(
+  client: &MatrixClient,
+  session: &mut ThreepidSession,
+  email: &str,
+) -> Result<()> {
+  let request = session.email_request(email, None);
+  let response = threepid::request_email_token(client, TokenPurpose::PasswordReset, request)?;
+  session.update(response);
+
+  Ok(())
+}
+
+// Set a new password once the user has clicked the link from request_password_reset.
+// Until the email is validated the server rejects the stage, so it is retried every
+// `interval` until `timeout` has passed.
+pub fn reset_password(
+  client: &MatrixClient,
+  creds: ThreepidCredentials,
+  new_password: String,
+  logout_devices: Option<bool>,
+  interval: Duration,
+  timeout: Duration,
+) -> Result<()> {
+  let deadline = Instant::now() + timeout;
+  let mut attempted = false;
+
+  auth::with_interactive_auth(
+    |auth| {
+      let request = ChangePasswordRequest {
+        new_password: new_password.clone(),
+        logout_devices,
+        auth,
+      };
+      change_password(client, request)
+    },
+    |uia| {
+      // Only the email stage can be completed here, so give up once it's done or no flow
+      // still needs it, e.g. the server wants a further stage or rejected the auth
+      let validated = uia
+        .completed
+        .iter()
+        .any(|stage| stage == auth::EMAIL_IDENTITY_STAGE);
+      let offered = uia.flows.iter().any(|flow| {
+        flow
+          .stages
+          .iter()
+          .any(|stage| stage == auth::EMAIL_IDENTITY_STAGE)
+          && uia.completed.iter().all(|done| flow.stages.contains(done))
+      });
+      if validated || !offered || Instant::now() >= deadline {
+        return None;
+      }
+      // Servers differ in the error they give before the link is clicked, so retry for as
+      // long as the stage hasn't been completed
+      if attempted {
+        thread::sleep(interval);
+      }
+      attempted = true;
+      Some(AuthData::email_identity(uia, creds.clone()))
+    },
+  )
+}
@@ -0,0 +1,60 @@
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Account Data
+Per user data which is synced to all of the user's devices, e.g. secret storage keys. Changes
+also arrive in the account_data section of sync.
+
+docs: https://matrix.org/docs/spec/client_server/latest#client-config
+*/
+
+pub fn endpoint(user_id: &str, event_type: &str) -> String {
+  format!(
+    "/_matrix/client/r0/user/{}/account_data/{}",
+    api::encode(user_id),
+    api::encode(event_type)
+  )
+}
+
+// Content of the event, None if it has never been set
+pub fn get_account_data(
+  client: &MatrixClient,
+  user_id: &str,
+  event_type: &str,
+) -> Result<Option<Value>> {
+  let mut response = api::get(client, &endpoint(user_id, event_type))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(Some(success))
+    }
+    StatusCode::NOT_FOUND => Ok(None),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn set_account_data(
+  client: &MatrixClient,
+  user_id: &str,
+  event_type: &str,
+  content: &Value,
+) -> Result<()> {
+  let response = api::put(client, &endpoint(user_id, event_type), content)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::BAD_REQUEST
+    | StatusCode::UNAUTHORIZED
+    | StatusCode::FORBIDDEN
+    | StatusCode::METHOD_NOT_ALLOWED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
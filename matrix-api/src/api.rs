@@ -1,16 +1,22 @@
-use reqwest::header::AUTHORIZATION;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
+use std::io;
 
+use crate::auth::UserInteractiveAuthResponse;
 use crate::client::MatrixClient;
+use crate::refresh;
 
 #[derive(Deserialize, Debug, Clone)]
 pub enum Kind {
   Http,
   Redirect,
   Timeout,
+  Io,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -79,6 +85,11 @@ pub enum MatrixErrorCode {
   ResourceLimitExceeded,
   #[serde(rename = "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM")]
   CannotLeaveServerNoticeRoom,
+  #[serde(rename = "M_WRONG_ROOM_KEYS_VERSION")]
+  WrongRoomKeysVersion,
+  // Codes added to the spec later, or custom ones from a server
+  #[serde(other)]
+  Other,
 }
 
 #[derive(Deserialize, Debug)]
@@ -91,12 +102,31 @@ pub struct MatrixErrorResponse {
   params: HashMap<String, Value>,
 }
 
+impl MatrixErrorResponse {
+  // Whether the access token was invalidated in a way a refresh token can recover from
+  pub fn is_soft_logout(&self) -> bool {
+    match self.code {
+      MatrixErrorCode::UnknownToken => self.params.get("soft_logout") == Some(&Value::Bool(true)),
+      _ => false,
+    }
+  }
+
+  // How long a rate limited client was asked to wait before retrying
+  pub fn retry_after_ms(&self) -> Option<u64> {
+    match self.code {
+      MatrixErrorCode::LimitExceeded => self.params.get("retry_after_ms").and_then(Value::as_u64),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 pub enum ApiError {
   Network { kind: Kind, message: String },
   Http(u16, Option<&'static str>),
   Response(u16, MatrixErrorResponse),
+  InteractiveAuth(Box<UserInteractiveAuthResponse>),
   Serialization,
   Unknown,
 }
@@ -115,6 +145,7 @@ impl fmt::Display for crate::api::ApiError {
       } => write!(f, "Network error occurred: {}", message),
       ApiError::Http(code, _) => write!(f, "Http error: {}", code),
       ApiError::Response(_, r) => write!(f, "Response error: {}", r.message),
+      ApiError::InteractiveAuth(_) => write!(f, "Additional authentication required"),
       ApiError::Serialization => write!(f, "Serialization error occured"),
       ApiError::Unknown => write!(f, "Unknown error occured"),
     }
@@ -129,6 +160,24 @@ impl error::Error for ApiError {
   }
 }
 
+impl ApiError {
+  // Whether the request was refused because the user is a guest
+  pub fn is_guest_access_forbidden(&self) -> bool {
+    match self {
+      ApiError::Response(_, error) => matches!(error.code, MatrixErrorCode::GuestAccessForbidden),
+      _ => false,
+    }
+  }
+
+  // Whether keys were sent to a key backup which is no longer the current one
+  pub fn is_wrong_room_keys_version(&self) -> bool {
+    match self {
+      ApiError::Response(_, error) => matches!(error.code, MatrixErrorCode::WrongRoomKeysVersion),
+      _ => false,
+    }
+  }
+}
+
 impl From<reqwest::Error> for ApiError {
   fn from(error: reqwest::Error) -> ApiError {
     if error.is_http() {
@@ -159,6 +208,15 @@ impl From<reqwest::Error> for ApiError {
   }
 }
 
+impl From<io::Error> for ApiError {
+  fn from(error: io::Error) -> ApiError {
+    ApiError::Network {
+      kind: Kind::Io,
+      message: error.to_string(),
+    }
+  }
+}
+
 impl From<reqwest::StatusCode> for ApiError {
   fn from(status: reqwest::StatusCode) -> ApiError {
     ApiError::Http(status.as_u16(), status.canonical_reason())
@@ -176,17 +234,53 @@ impl From<reqwest::Response> for ApiError {
 
 pub type Result<T> = ::std::result::Result<T, ApiError>;
 
+// Percent encode an identifier (room id, event id, user id) for use as a path segment
+pub fn encode(segment: &str) -> String {
+  utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string()
+}
+
 pub fn add_request_authorization(
   api_client: &MatrixClient,
   request: reqwest::RequestBuilder,
 ) -> reqwest::RequestBuilder {
   // Adds request authorization to any reqwest request builder
-  match &api_client.access_token {
+  match api_client.get_access_token() {
     Some(access_token) => request.header(AUTHORIZATION, access_token),
     None => request,
   }
 }
 
+// Sends a request built by `build`. If the server soft logged out the access token and the
+// client has a refresh token, the token is renewed and the request is sent again.
+fn send<F>(api_client: &MatrixClient, build: F) -> Result<reqwest::Response>
+where
+  F: Fn() -> reqwest::RequestBuilder,
+{
+  let mut response = add_request_authorization(api_client, build()).send()?;
+  if response.status() != StatusCode::UNAUTHORIZED || !api_client.has_refresh_token() {
+    return Ok(response);
+  }
+
+  let status = response.status();
+  let body = response.text()?;
+  let soft_logout = match serde_json::from_str::<MatrixErrorResponse>(&body) {
+    Ok(error) => error.is_soft_logout(),
+    Err(_) => false,
+  };
+
+  if soft_logout && refresh::refresh_client(api_client).unwrap_or(false) {
+    let response = add_request_authorization(api_client, build()).send()?;
+    return Ok(response);
+  }
+
+  // The body has been read, so hand back a copy of the original response
+  let original = http::Response::builder()
+    .status(status)
+    .body(body)
+    .map_err(|_| ApiError::Unknown)?;
+  Ok(reqwest::Response::from(original))
+}
+
 pub fn post<TBody: serde::Serialize + ?Sized>(
   api_client: &MatrixClient,
   endpoint: &str,
@@ -194,11 +288,7 @@ pub fn post<TBody: serde::Serialize + ?Sized>(
 ) -> Result<reqwest::Response> {
   let client = reqwest::Client::new();
   let url = format!("{}{}", api_client.get_base_url(), endpoint);
-  let mut request = client.post(&url).json(body);
-  request = add_request_authorization(api_client, request);
-  let response = request.send()?;
-
-  Ok(response)
+  send(api_client, || client.post(&url).json(body))
 }
 
 pub fn post_query<TBody: serde::Serialize + ?Sized, TQuery: serde::Serialize + ?Sized>(
@@ -209,21 +299,33 @@ pub fn post_query<TBody: serde::Serialize + ?Sized, TQuery: serde::Serialize + ?
 ) -> Result<reqwest::Response> {
   let client = reqwest::Client::new();
   let url = format!("{}{}", api_client.get_base_url(), endpoint);
-  let mut request = client.post(&url).query(query).json(body);
-  request = add_request_authorization(api_client, request);
-  let response = request.send()?;
+  send(api_client, || client.post(&url).query(query).json(body))
+}
 
+// Post a raw body, e.g. a file being uploaded. The body may be a stream which can only be read
+// once, so unlike the other requests this isn't sent again after a token refresh.
+pub fn post_body_query<TQuery: serde::Serialize + ?Sized>(
+  api_client: &MatrixClient,
+  endpoint: &str,
+  content_type: &str,
+  body: reqwest::Body,
+  query: &TQuery,
+) -> Result<reqwest::Response> {
+  let client = reqwest::Client::new();
+  let url = format!("{}{}", api_client.get_base_url(), endpoint);
+  let request = client
+    .post(&url)
+    .query(query)
+    .header(CONTENT_TYPE, content_type)
+    .body(body);
+  let response = add_request_authorization(api_client, request).send()?;
   Ok(response)
 }
 
 pub fn get(api_client: &MatrixClient, endpoint: &str) -> Result<reqwest::Response> {
   let client = reqwest::Client::new();
   let url = format!("{}{}", api_client.get_base_url(), endpoint);
-  let mut request = client.get(&url);
-  request = add_request_authorization(api_client, request);
-  let response = request.send()?;
-
-  Ok(response)
+  send(api_client, || client.get(&url))
 }
 
 pub fn get_query<TQuery: serde::Serialize + ?Sized>(
@@ -233,10 +335,32 @@ pub fn get_query<TQuery: serde::Serialize + ?Sized>(
 ) -> Result<reqwest::Response> {
   let client = reqwest::Client::new();
   let url = format!("{}{}", api_client.get_base_url(), endpoint);
+  send(api_client, || client.get(&url).query(model))
+}
+
+pub fn put<TBody: serde::Serialize + ?Sized>(
+  api_client: &MatrixClient,
+  endpoint: &str,
+  body: &TBody,
+) -> Result<reqwest::Response> {
+  let client = reqwest::Client::new();
+  let url = format!("{}{}", api_client.get_base_url(), endpoint);
+  send(api_client, || client.put(&url).json(body))
+}
 
-  let mut request = client.get(&url).query(model);
-  request = add_request_authorization(api_client, request);
-  let response = request.send()?;
+pub fn put_query<TBody: serde::Serialize + ?Sized, TQuery: serde::Serialize + ?Sized>(
+  api_client: &MatrixClient,
+  endpoint: &str,
+  body: &TBody,
+  query: &TQuery,
+) -> Result<reqwest::Response> {
+  let client = reqwest::Client::new();
+  let url = format!("{}{}", api_client.get_base_url(), endpoint);
+  send(api_client, || client.put(&url).query(query).json(body))
+}
 
-  Ok(response)
+pub fn delete(api_client: &MatrixClient, endpoint: &str) -> Result<reqwest::Response> {
+  let client = reqwest::Client::new();
+  let url = format!("{}{}", api_client.get_base_url(), endpoint);
+  send(api_client, || client.delete(&url))
 }
@@ -1,6 +1,12 @@
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fmt::{self, Debug};
 
+use crate::api::{ApiError, MatrixErrorCode, Result};
+use crate::login::UserIdentifier;
+use crate::threepid::ThreepidCredentials;
+
 #[derive(Deserialize, Serialize, Debug)]
 pub enum AuthenticationTypes {
   #[serde(rename = "m.login.password")]
@@ -15,6 +21,8 @@ pub enum AuthenticationTypes {
   Msisdn,
   #[serde(rename = "m.login.token")]
   Token,
+  #[serde(rename = "m.login.sso")]
+  Sso,
   #[serde(rename = "m.login.dummy")]
   Dummy,
 }
@@ -24,3 +32,107 @@ impl fmt::Display for AuthenticationTypes {
     write!(f, "{:?}", self)
   }
 }
+
+/*
+User-Interactive Authentication
+Endpoints which require extra authentication respond with 401 and the stages left to complete
+
+docs: https://matrix.org/docs/spec/client_server/latest#user-interactive-authentication-api
+*/
+
+pub static EMAIL_IDENTITY_STAGE: &str = "m.login.email.identity";
+pub static REGISTRATION_TOKEN_STAGE: &str = "m.login.registration_token";
+
+#[derive(Deserialize, Debug)]
+pub struct AuthFlow {
+  pub stages: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserInteractiveAuthResponse {
+  pub session: Option<String>,
+  pub flows: Vec<AuthFlow>,
+  #[serde(default)]
+  pub params: HashMap<String, Value>,
+  // Stages of the current session which have already been completed
+  #[serde(default)]
+  pub completed: Vec<String>,
+  // Set when the previous attempt at a stage failed
+  pub errcode: Option<MatrixErrorCode>,
+  pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub enum AuthStage {
+  #[serde(rename = "m.login.password")]
+  Password {
+    identifier: UserIdentifier,
+    password: String,
+  },
+  #[serde(rename = "m.login.email.identity")]
+  EmailIdentity { threepid_creds: ThreepidCredentials },
+  #[serde(rename = "m.login.dummy")]
+  Dummy,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthData {
+  #[serde(flatten)]
+  pub stage: AuthStage,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub session: Option<String>,
+}
+
+impl AuthData {
+  // Password stage for the given user, continuing the session of a previous 401
+  pub fn password(uia: &UserInteractiveAuthResponse, user_id: &str, password: String) -> AuthData {
+    AuthData {
+      stage: AuthStage::Password {
+        identifier: UserIdentifier::User {
+          user: user_id.to_string(),
+        },
+        password,
+      },
+      session: uia.session.clone(),
+    }
+  }
+
+  // Email stage using a validation session the user has followed the link for
+  pub fn email_identity(uia: &UserInteractiveAuthResponse, creds: ThreepidCredentials) -> AuthData {
+    AuthData {
+      stage: AuthStage::EmailIdentity {
+        threepid_creds: creds,
+      },
+      session: uia.session.clone(),
+    }
+  }
+}
+
+// Map a 401 response to the stages the user still has to complete
+pub fn interactive_auth_error(mut response: reqwest::Response) -> ApiError {
+  match response.json() {
+    Ok(uia) => ApiError::InteractiveAuth(Box::new(uia)),
+    Err(_) => ApiError::from(response.status()),
+  }
+}
+
+// Run a request that may require user-interactive auth. Whenever the server asks for
+// another stage, `complete_stage` is given the challenge and returns the auth to retry with,
+// or None to give up and return the challenge as an error.
+pub fn with_interactive_auth<T, F, A>(mut request: F, mut complete_stage: A) -> Result<T>
+where
+  F: FnMut(Option<AuthData>) -> Result<T>,
+  A: FnMut(&UserInteractiveAuthResponse) -> Option<AuthData>,
+{
+  let mut auth = None;
+  loop {
+    match request(auth) {
+      Err(ApiError::InteractiveAuth(uia)) => match complete_stage(&uia) {
+        Some(next) => auth = Some(next),
+        None => return Err(ApiError::InteractiveAuth(uia)),
+      },
+      result => return result,
+    }
+  }
+}
@@ -1,6 +1,19 @@
+use std::cell::RefCell;
+
+use crate::handlers::{EventHandler, EventHandlerId};
+
+// Called with the new access token and refresh token whenever they are renewed
+pub type TokenRefreshCallback = Box<dyn Fn(&str, Option<&str>)>;
+
 pub struct MatrixClient {
   pub base_url: String,
-  pub access_token: Option<String>,
+  access_token: RefCell<Option<String>>,
+  refresh_token: RefCell<Option<String>>,
+  user_id: Option<String>,
+  device_id: Option<String>,
+  on_token_refresh: Option<TokenRefreshCallback>,
+  event_handlers: Vec<(EventHandlerId, EventHandler)>,
+  next_handler_id: usize,
 }
 
 impl MatrixClient {
@@ -8,7 +21,13 @@ impl MatrixClient {
   pub fn new(base_url: &str) -> MatrixClient {
     MatrixClient {
       base_url: base_url.to_string(),
-      access_token: None,
+      access_token: RefCell::new(None),
+      refresh_token: RefCell::new(None),
+      user_id: None,
+      device_id: None,
+      on_token_refresh: None,
+      event_handlers: Vec::new(),
+      next_handler_id: 0,
     }
   }
 
@@ -18,16 +37,91 @@ impl MatrixClient {
 
   // Set the users access token (e.g. login)
   pub fn set_access_token(&mut self, access_token: String) {
-    self.access_token = Some(format!("Bearer {}", access_token));
+    self
+      .access_token
+      .replace(Some(format!("Bearer {}", access_token)));
   }
 
   // Return access token as an Option
-  pub fn get_access_token(&self) -> &Option<String> {
-    &self.access_token
+  pub fn get_access_token(&self) -> Option<String> {
+    self.access_token.borrow().clone()
+  }
+
+  // Return the access token as it was given by the server, without the authorization scheme
+  pub fn get_raw_access_token(&self) -> Option<String> {
+    self
+      .get_access_token()
+      .map(|access_token| access_token.trim_start_matches("Bearer ").to_string())
   }
 
   // Remove the users access token (e.g. logout)
   pub fn remove_access_token(&mut self) {
-    self.access_token = None;
+    self.access_token.replace(None);
+    self.refresh_token.replace(None);
+    self.user_id = None;
+    self.device_id = None;
+  }
+
+  // Who the access token belongs to, from the login response or whoami, so it's known
+  // without asking the server again
+  pub fn set_user(&mut self, user_id: String, device_id: Option<String>) {
+    self.user_id = Some(user_id);
+    self.device_id = device_id;
+  }
+
+  pub fn user_id(&self) -> Option<&str> {
+    self.user_id.as_deref()
+  }
+
+  pub fn device_id(&self) -> Option<&str> {
+    self.device_id.as_deref()
+  }
+
+  // Set the refresh token used to renew the access token once it expires
+  pub fn set_refresh_token(&mut self, refresh_token: Option<String>) {
+    self.refresh_token.replace(refresh_token);
+  }
+
+  pub fn has_refresh_token(&self) -> bool {
+    self.refresh_token.borrow().is_some()
+  }
+
+  // Register a callback to persist tokens after they have been refreshed
+  pub fn set_token_refresh_callback(&mut self, callback: TokenRefreshCallback) {
+    self.on_token_refresh = Some(callback);
+  }
+
+  // Register a handler to be called for events of its kind in each sync response
+  pub fn add_event_handler(&mut self, handler: EventHandler) -> EventHandlerId {
+    let id = EventHandlerId(self.next_handler_id);
+    self.next_handler_id += 1;
+    self.event_handlers.push((id, handler));
+    id
+  }
+
+  pub fn remove_event_handler(&mut self, id: EventHandlerId) {
+    self
+      .event_handlers
+      .retain(|(handler_id, _)| *handler_id != id);
+  }
+
+  pub(crate) fn event_handlers(&self) -> &[(EventHandlerId, EventHandler)] {
+    &self.event_handlers
+  }
+
+  // The refresh token is only replaced by update_tokens once a refresh succeeds
+  pub(crate) fn refresh_token(&self) -> Option<String> {
+    self.refresh_token.borrow().clone()
+  }
+
+  // Store renewed tokens and let the caller know about them
+  pub(crate) fn update_tokens(&self, access_token: String, refresh_token: Option<String>) {
+    if let Some(callback) = &self.on_token_refresh {
+      callback(&access_token, refresh_token.as_deref());
+    }
+    self
+      .access_token
+      .replace(Some(format!("Bearer {}", access_token)));
+    self.refresh_token.replace(refresh_token);
   }
 }
@@ -0,0 +1,313 @@
+use aes::cipher::StreamCipher;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use vodozemac::base64_encode;
+
+use crate::api;
+use crate::client::MatrixClient;
+use crate::crypto::cipher;
+use crate::crypto::cipher::Aes256Ctr;
+use crate::crypto::{CryptoError, Result};
+use crate::media;
+
+/*
+Encrypted Attachments
+Files sent to encrypted rooms are encrypted with a new AES-256-CTR key before uploading. The
+key, IV and SHA-256 hash of the ciphertext go in the event's `file` in place of `url`, so
+only room members can read them.
+
+docs: https://matrix.org/docs/spec/client_server/latest#sending-encrypted-attachments
+*/
+
+pub static VERSION: &str = "v2";
+pub static KEY_ALGORITHM: &str = "A256CTR";
+pub static SHA256: &str = "sha256";
+
+// An AES key as a JSON Web Key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonWebKey {
+  pub kty: String,
+  pub key_ops: Vec<String>,
+  pub alg: String,
+  // The key in unpadded base64url
+  pub k: String,
+  pub ext: bool,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#extensions-to-m-message-msgtypes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedFile {
+  pub url: String,
+  pub key: JsonWebKey,
+  pub iv: String,
+  // Hashes of the ciphertext by algorithm
+  pub hashes: HashMap<String, String>,
+  pub v: String,
+}
+
+// The key and IV of an attachment, and the hash of what's been encrypted so far. It's shared
+// with the encryptor, so the hash can be read once the encryptor was given away to upload.
+#[derive(Clone)]
+pub struct AttachmentKeys {
+  key: [u8; 32],
+  iv: [u8; 16],
+  hasher: Arc<Mutex<Sha256>>,
+}
+
+impl AttachmentKeys {
+  // The file for the event, call once everything has been encrypted and uploaded to url
+  pub fn encrypted_file(&self, url: &str) -> EncryptedFile {
+    let hash = match self.hasher.lock() {
+      Ok(hasher) => hasher.clone().finalize().to_vec(),
+      Err(poisoned) => poisoned.into_inner().clone().finalize().to_vec(),
+    };
+    let mut hashes = HashMap::new();
+    hashes.insert(SHA256.to_string(), base64_encode(hash));
+
+    EncryptedFile {
+      url: url.to_string(),
+      key: JsonWebKey {
+        kty: String::from("oct"),
+        key_ops: vec![String::from("encrypt"), String::from("decrypt")],
+        alg: KEY_ALGORITHM.to_string(),
+        k: base64url_encode(&self.key),
+        ext: true,
+      },
+      iv: base64_encode(self.iv),
+      hashes,
+      v: VERSION.to_string(),
+    }
+  }
+}
+
+// Encrypts a file as it's read, e.g. while it's being uploaded
+pub struct AttachmentEncryptor<R> {
+  inner: R,
+  cipher: Aes256Ctr,
+  keys: AttachmentKeys,
+}
+
+impl<R: Read> AttachmentEncryptor<R> {
+  // Encrypt with a new random key. The IV's counter half starts at zero, as v2 requires.
+  pub fn new(inner: R) -> AttachmentEncryptor<R> {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&cipher::random_bytes(32));
+    let mut iv = [0u8; 16];
+    iv[..8].copy_from_slice(&cipher::random_bytes(8));
+
+    AttachmentEncryptor {
+      inner,
+      cipher: cipher::aes_ctr_stream(&key, &iv),
+      keys: AttachmentKeys {
+        key,
+        iv,
+        hasher: Arc::new(Mutex::new(Sha256::new())),
+      },
+    }
+  }
+
+  pub fn keys(&self) -> AttachmentKeys {
+    self.keys.clone()
+  }
+}
+
+impl<R: Read> Read for AttachmentEncryptor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.cipher.apply_keystream(&mut buf[..read]);
+    if let Ok(mut hasher) = self.keys.hasher.lock() {
+      hasher.update(&buf[..read]);
+    }
+    Ok(read)
+  }
+}
+
+// Decrypts a file as it's read, e.g. while it's being downloaded. The hash can only be
+// checked at the end, so nothing read should be trusted until a read returns 0 bytes; a
+// file which was changed gives an InvalidData error instead. decrypt_verified checks first.
+pub struct AttachmentDecryptor<R> {
+  inner: R,
+  cipher: Aes256Ctr,
+  hasher: Sha256,
+  expected_hash: Vec<u8>,
+  verified: bool,
+}
+
+impl<R: Read> AttachmentDecryptor<R> {
+  pub fn new(inner: R, file: &EncryptedFile) -> Result<AttachmentDecryptor<R>> {
+    let (key, iv, expected_hash) = file_keys(file)?;
+    Ok(AttachmentDecryptor {
+      inner,
+      cipher: cipher::aes_ctr_stream(&key, &iv),
+      hasher: Sha256::new(),
+      expected_hash,
+      verified: false,
+    })
+  }
+}
+
+impl<R: Read> Read for AttachmentDecryptor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    if read == 0 && !buf.is_empty() {
+      if !self.verified {
+        if self.hasher.clone().finalize().as_slice() != self.expected_hash.as_slice() {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The attachment's hash doesn't match",
+          ));
+        }
+        self.verified = true;
+      }
+      return Ok(0);
+    }
+
+    self.hasher.update(&buf[..read]);
+    self.cipher.apply_keystream(&mut buf[..read]);
+    Ok(read)
+  }
+}
+
+// The key, IV and expected SHA-256 hash of the ciphertext
+fn file_keys(file: &EncryptedFile) -> Result<([u8; 32], [u8; 16], Vec<u8>)> {
+  if file.v != VERSION {
+    return Err(CryptoError::UnsupportedAlgorithm(format!(
+      "Encrypted attachment {}",
+      file.v
+    )));
+  }
+  if file.key.alg != KEY_ALGORITHM {
+    return Err(CryptoError::UnsupportedAlgorithm(file.key.alg.clone()));
+  }
+
+  let key = base64url_decode(&file.key.k)
+    .filter(|key| key.len() == 32)
+    .ok_or_else(|| CryptoError::Key(String::from("The attachment's key isn't 32 bytes")))?;
+  let iv = cipher::decode_base64(&file.iv)
+    .filter(|iv| iv.len() == 16)
+    .ok_or_else(|| CryptoError::Key(String::from("The attachment's IV isn't 16 bytes")))?;
+  let expected_hash = file
+    .hashes
+    .get(SHA256)
+    .and_then(|hash| cipher::decode_base64(hash))
+    .ok_or_else(|| CryptoError::Key(String::from("The attachment has no SHA-256 hash")))?;
+
+  let mut aes_key = [0u8; 32];
+  aes_key.copy_from_slice(&key);
+  let mut aes_iv = [0u8; 16];
+  aes_iv.copy_from_slice(&iv);
+  Ok((aes_key, aes_iv, expected_hash))
+}
+
+// Read the whole ciphertext and check its hash before decrypting any of it, so nothing from
+// a changed file is ever handed out. The file is held in memory.
+pub fn decrypt_verified<R: Read>(mut reader: R, file: &EncryptedFile) -> Result<Vec<u8>> {
+  let (key, iv, expected_hash) = file_keys(file)?;
+  let mut data = Vec::new();
+  reader
+    .read_to_end(&mut data)
+    .map_err(|e| CryptoError::Api(api::ApiError::from(e)))?;
+  if cipher::sha256(&data) != expected_hash {
+    return Err(CryptoError::Mismatch(String::from(
+      "The attachment's hash doesn't match",
+    )));
+  }
+  cipher::aes_ctr(&key, &iv, &mut data);
+  Ok(data)
+}
+
+// Encrypt and upload the file as it's read, length is the number of bytes the reader will
+// give. The returned file goes in the event.
+pub fn upload_encrypted<R: Read + Send + 'static>(
+  client: &MatrixClient,
+  reader: R,
+  length: u64,
+) -> api::Result<EncryptedFile> {
+  let encryptor = AttachmentEncryptor::new(reader);
+  let keys = encryptor.keys();
+  // The type and name would be seen by the server, they go in the encrypted event instead
+  let url = media::upload_reader(client, "application/octet-stream", None, encryptor, length)?;
+  Ok(keys.encrypted_file(&url))
+}
+
+// Download the file and decrypt it once its hash has been checked
+pub fn download_verified(client: &MatrixClient, file: &EncryptedFile) -> Result<Vec<u8>> {
+  let response = media::download(client, &file.url)?;
+  decrypt_verified(response, file)
+}
+
+// Download the file, decrypting it as it's read
+pub fn download_encrypted(
+  client: &MatrixClient,
+  file: &EncryptedFile,
+) -> Result<AttachmentDecryptor<reqwest::Response>> {
+  let response = media::download(client, &file.url)?;
+  AttachmentDecryptor::new(response, file)
+}
+
+fn base64url_encode(bytes: &[u8]) -> String {
+  base64_encode(bytes).replace('+', "-").replace('/', "_")
+}
+
+fn base64url_decode(input: &str) -> Option<Vec<u8>> {
+  cipher::decode_base64(&input.replace('-', "+").replace('_', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static PLAINTEXT: &[u8] = b"An attachment long enough to go over more than one AES block";
+
+  fn encrypt(plaintext: &[u8]) -> (Vec<u8>, EncryptedFile) {
+    let mut encryptor = AttachmentEncryptor::new(plaintext);
+    let mut ciphertext = Vec::new();
+    encryptor.read_to_end(&mut ciphertext).unwrap();
+    let file = encryptor.keys().encrypted_file("mxc://example.org/file");
+    (ciphertext, file)
+  }
+
+  #[test]
+  fn encrypted_attachment_decrypts() {
+    let (ciphertext, file) = encrypt(PLAINTEXT);
+    assert_ne!(ciphertext, PLAINTEXT);
+
+    assert_eq!(decrypt_verified(&ciphertext[..], &file).unwrap(), PLAINTEXT);
+    let mut decrypted = Vec::new();
+    AttachmentDecryptor::new(&ciphertext[..], &file)
+      .unwrap()
+      .read_to_end(&mut decrypted)
+      .unwrap();
+    assert_eq!(decrypted, PLAINTEXT);
+  }
+
+  #[test]
+  fn changed_attachment_is_rejected() {
+    let (mut ciphertext, file) = encrypt(PLAINTEXT);
+    ciphertext[0] ^= 1;
+
+    match decrypt_verified(&ciphertext[..], &file) {
+      Err(CryptoError::Mismatch(_)) => {}
+      result => panic!("expected a mismatch, got {:?}", result),
+    }
+    let mut decrypted = Vec::new();
+    let error = AttachmentDecryptor::new(&ciphertext[..], &file)
+      .unwrap()
+      .read_to_end(&mut decrypted)
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn iv_counter_starts_at_zero() {
+    let (_, file) = encrypt(PLAINTEXT);
+    let iv = cipher::decode_base64(&file.iv).unwrap();
+
+    assert_eq!(iv.len(), 16);
+    assert_eq!(iv[8..], [0u8; 8]);
+  }
+}
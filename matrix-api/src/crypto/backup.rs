@@ -0,0 +1,293 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use vodozemac::pk_encryption::{Message, PkDecryption, PkEncryption};
+use vodozemac::{base64_encode, Curve25519PublicKey, Curve25519SecretKey};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::crypto;
+use crate::crypto::cipher;
+use crate::crypto::keys::Signatures;
+use crate::crypto::secret_storage;
+use crate::crypto::CryptoError;
+
+/*
+Server-side Key Backup
+Room keys are encrypted to the backup's curve25519 public key and uploaded, so they can be
+downloaded on a new device with the private key. The private key is given to the user as a
+recovery key, derived from a passphrase, or kept in secret storage.
+
+docs: https://matrix.org/docs/spec/client_server/latest#server-side-key-backups
+*/
+
+pub static VERSION_ENDPOINT: &str = "/_matrix/client/r0/room_keys/version";
+pub static KEYS_ENDPOINT: &str = "/_matrix/client/r0/room_keys/keys";
+
+pub static BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+// Name of the backup's private key in secret storage
+pub static BACKUP_SECRET: &str = "m.megolm_backup.v1";
+
+pub fn version_endpoint(version: &str) -> String {
+  format!("{}/{}", VERSION_ENDPOINT, api::encode(version))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackupAuthData {
+  pub public_key: String,
+  // Set when the private key was derived from a passphrase
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub private_key_salt: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub private_key_iterations: Option<u32>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub signatures: Signatures,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreateVersionRequest {
+  pub algorithm: String,
+  pub auth_data: BackupAuthData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateVersionResponse {
+  pub version: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BackupVersion {
+  pub algorithm: String,
+  pub auth_data: BackupAuthData,
+  pub count: u64,
+  pub etag: String,
+  pub version: String,
+}
+
+// A room key encrypted to the backup's public key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedSessionData {
+  pub ephemeral: String,
+  pub ciphertext: String,
+  pub mac: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyBackupData {
+  pub first_message_index: u32,
+  pub forwarded_count: u32,
+  pub is_verified: bool,
+  pub session_data: EncryptedSessionData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomKeyBackup {
+  // Keyed by session id
+  pub sessions: HashMap<String, KeyBackupData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeysBackup {
+  // Keyed by room id
+  pub rooms: HashMap<String, RoomKeyBackup>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysBackupResponse {
+  pub count: u64,
+  pub etag: String,
+}
+
+#[derive(Serialize, Debug)]
+struct VersionQuery<'a> {
+  version: &'a str,
+}
+
+pub fn create_version(client: &MatrixClient, request: &CreateVersionRequest) -> Result<String> {
+  let mut response = api::post(client, VERSION_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success: CreateVersionResponse = response.json()?;
+      Ok(success.version)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// The current backup, None if there isn't one
+pub fn latest_version(client: &MatrixClient) -> Result<Option<BackupVersion>> {
+  fetch_version(client, VERSION_ENDPOINT)
+}
+
+pub fn get_version(client: &MatrixClient, version: &str) -> Result<Option<BackupVersion>> {
+  fetch_version(client, &version_endpoint(version))
+}
+
+fn fetch_version(client: &MatrixClient, endpoint: &str) -> Result<Option<BackupVersion>> {
+  let mut response = api::get(client, endpoint)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(Some(success))
+    }
+    StatusCode::NOT_FOUND => Ok(None),
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Delete the backup and the keys in it
+pub fn delete_version(client: &MatrixClient, version: &str) -> Result<()> {
+  let response = api::delete(client, &version_endpoint(version))?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::UNAUTHORIZED | StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Add keys to the backup, the server keeps whichever copy of a session is better
+pub fn upload_keys(
+  client: &MatrixClient,
+  version: &str,
+  keys: &KeysBackup,
+) -> Result<KeysBackupResponse> {
+  let query = VersionQuery { version };
+  let mut response = api::put_query(client, KEYS_ENDPOINT, keys, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn download_keys(client: &MatrixClient, version: &str) -> Result<KeysBackup> {
+  let query = VersionQuery { version };
+  let mut response = api::get_query(client, KEYS_ENDPOINT, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Encrypt a room key to the backup's public key
+pub fn encrypt(public_key: &str, session: &Value) -> crypto::Result<EncryptedSessionData> {
+  let public_key =
+    Curve25519PublicKey::from_base64(public_key).map_err(|e| CryptoError::Key(e.to_string()))?;
+  let message =
+    PkEncryption::from_key(public_key).encrypt(serde_json::to_string(session)?.as_bytes());
+  Ok(EncryptedSessionData {
+    ephemeral: message.ephemeral_key.to_base64(),
+    ciphertext: base64_encode(message.ciphertext),
+    mac: base64_encode(message.mac),
+  })
+}
+
+// The backup's private key
+pub struct BackupKey {
+  decryption: PkDecryption,
+}
+
+impl BackupKey {
+  pub fn new() -> BackupKey {
+    BackupKey {
+      decryption: PkDecryption::new(),
+    }
+  }
+
+  pub fn from_bytes(key: &[u8; 32]) -> BackupKey {
+    BackupKey {
+      decryption: PkDecryption::from_key(Curve25519SecretKey::from_slice(key)),
+    }
+  }
+
+  // A key derived from a passphrase, returned with its salt and iteration count for the
+  // backup's auth data
+  pub fn from_new_passphrase(passphrase: &str) -> (BackupKey, String, u32) {
+    let salt = base64_encode(cipher::random_bytes(32));
+    let iterations = secret_storage::PASSPHRASE_ITERATIONS;
+    let key = BackupKey::from_passphrase(passphrase, &salt, iterations);
+    (key, salt, iterations)
+  }
+
+  pub fn from_passphrase(passphrase: &str, salt: &str, iterations: u32) -> BackupKey {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&cipher::pbkdf2_sha512(
+      passphrase,
+      salt.as_bytes(),
+      iterations,
+      32,
+    ));
+    BackupKey::from_bytes(&key)
+  }
+
+  pub fn from_recovery_key(recovery_key: &str) -> crypto::Result<BackupKey> {
+    let key = secret_storage::decode_recovery_key(recovery_key)?;
+    Ok(BackupKey::from_bytes(&key))
+  }
+
+  pub fn to_recovery_key(&self) -> String {
+    secret_storage::encode_recovery_key(&self.decryption.secret_key().to_bytes())
+  }
+
+  // Base64 of the private key, as kept in secret storage
+  pub fn from_base64(key: &str) -> crypto::Result<BackupKey> {
+    let bytes = cipher::decode_base64(key)
+      .filter(|bytes| bytes.len() == 32)
+      .ok_or_else(|| CryptoError::Key(String::from("Not a backup key")))?;
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(BackupKey::from_bytes(&key))
+  }
+
+  pub fn to_base64(&self) -> String {
+    base64_encode(self.decryption.secret_key().to_bytes().as_ref())
+  }
+
+  pub fn public_key(&self) -> String {
+    self.decryption.public_key().to_base64()
+  }
+
+  // The room key in the session data
+  pub fn decrypt(&self, session_data: &EncryptedSessionData) -> crypto::Result<Value> {
+    let decode = |value: &str| {
+      cipher::decode_base64(value)
+        .ok_or_else(|| CryptoError::Key(format!("Invalid base64 {}", value)))
+    };
+    let message = Message {
+      ciphertext: decode(&session_data.ciphertext)?,
+      mac: decode(&session_data.mac)?,
+      ephemeral_key: Curve25519PublicKey::from_base64(&session_data.ephemeral)
+        .map_err(|e| CryptoError::Key(e.to_string()))?,
+    };
+    let plaintext = self
+      .decryption
+      .decrypt(&message)
+      .map_err(|e| CryptoError::Key(e.to_string()))?;
+    Ok(serde_json::from_slice(&plaintext)?)
+  }
+}
+
+impl Default for BackupKey {
+  fn default() -> BackupKey {
+    BackupKey::new()
+  }
+}
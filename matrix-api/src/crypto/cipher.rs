@@ -0,0 +1,130 @@
+use aes::cipher::{KeyIvInit, StreamCipher};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use vodozemac::base64_decode;
+
+/*
+Ciphers
+AES-256-CTR with HMAC-SHA256, and PBKDF2 and HKDF key derivation, used to protect keys at
+rest and wherever the spec encrypts with a passphrase or a secret key
+*/
+
+pub type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+pub static IV_LENGTH: usize = 16;
+pub static MAC_LENGTH: usize = 32;
+
+pub struct Keys {
+  pub aes_key: [u8; 32],
+  pub mac_key: [u8; 32],
+}
+
+impl Keys {
+  // The first 32 bytes are the AES key, the next 32 the HMAC key
+  pub fn from_slice(derived: &[u8]) -> Keys {
+    let mut keys = Keys {
+      aes_key: [0u8; 32],
+      mac_key: [0u8; 32],
+    };
+    keys.aes_key.copy_from_slice(&derived[..32]);
+    keys.mac_key.copy_from_slice(&derived[32..64]);
+    keys
+  }
+}
+
+// PBKDF2-HMAC-SHA512 with a 512 bit output, split into the AES and HMAC keys
+pub fn derive_keys(passphrase: &str, salt: &[u8], rounds: u32) -> Keys {
+  Keys::from_slice(&pbkdf2_sha512(passphrase, salt, rounds, 64))
+}
+
+pub fn pbkdf2_sha512(passphrase: &str, salt: &[u8], rounds: u32, length: usize) -> Vec<u8> {
+  let mut derived = vec![0u8; length];
+  pbkdf2::pbkdf2_hmac::<Sha512>(passphrase.as_bytes(), salt, rounds, &mut derived);
+  derived
+}
+
+pub fn hkdf_sha256(key: &[u8], salt: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+  let mut derived = vec![0u8; length];
+  Hkdf::<Sha256>::new(Some(salt), key)
+    .expand(info, &mut derived)
+    .expect("HKDF output is at most 255 hashes long");
+  derived
+}
+
+pub fn random_bytes(length: usize) -> Vec<u8> {
+  let mut bytes = vec![0u8; length];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  bytes
+}
+
+// Random IV with bit 63 cleared, so the counter can't wrap around into the nonce
+pub fn random_iv() -> [u8; 16] {
+  let mut iv = [0u8; 16];
+  rand::thread_rng().fill_bytes(&mut iv);
+  iv[8] &= 0x7f;
+  iv
+}
+
+// Encrypts and decrypts in place
+pub fn aes_ctr(key: &[u8; 32], iv: &[u8; 16], data: &mut [u8]) {
+  aes_ctr_stream(key, iv).apply_keystream(data);
+}
+
+// For data which arrives in parts, apply_keystream carries on from where the last part ended
+pub fn aes_ctr_stream(key: &[u8; 32], iv: &[u8; 16]) -> Aes256Ctr {
+  Aes256Ctr::new(key.into(), iv.into())
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+// Constant time comparison against the expected MAC
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], expected: &[u8]) -> bool {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+  mac.update(data);
+  mac.verify_slice(expected).is_ok()
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+  Sha256::digest(data).to_vec()
+}
+
+// Other clients pad their base64, vodozemac only reads it unpadded
+pub fn decode_base64(input: &str) -> Option<Vec<u8>> {
+  base64_decode(input.trim_end_matches('=')).ok()
+}
+
+// IV, ciphertext then the MAC of both
+pub fn encrypt(keys: &Keys, plaintext: &[u8]) -> Vec<u8> {
+  let iv = random_iv();
+  let mut data = iv.to_vec();
+  data.extend_from_slice(plaintext);
+  aes_ctr(&keys.aes_key, &iv, &mut data[IV_LENGTH..]);
+
+  let mac = hmac_sha256(&keys.mac_key, &data);
+  data.extend(mac);
+  data
+}
+
+// None if the data was tampered with or the keys are wrong
+pub fn decrypt(keys: &Keys, data: &[u8]) -> Option<Vec<u8>> {
+  if data.len() < IV_LENGTH + MAC_LENGTH {
+    return None;
+  }
+  let (signed, mac) = data.split_at(data.len() - MAC_LENGTH);
+  if !verify_hmac_sha256(&keys.mac_key, signed, mac) {
+    return None;
+  }
+
+  let mut iv = [0u8; 16];
+  iv.copy_from_slice(&signed[..IV_LENGTH]);
+  let mut plaintext = signed[IV_LENGTH..].to_vec();
+  aes_ctr(&keys.aes_key, &iv, &mut plaintext);
+  Some(plaintext)
+}
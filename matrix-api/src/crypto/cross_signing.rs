@@ -0,0 +1,267 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use vodozemac::Ed25519SecretKey;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::auth;
+use crate::auth::AuthData;
+use crate::client::MatrixClient;
+use crate::crypto::keys::{DeviceKeys, Signatures};
+use crate::crypto::signatures;
+
+/*
+Cross-Signing
+Each user has a master key which signs a self-signing key and a user-signing key. The
+self-signing key signs the user's own devices and the user-signing key signs other users'
+master keys, so verifying a user once covers all of their devices.
+
+docs: https://matrix.org/docs/spec/client_server/latest#cross-signing
+*/
+
+// Only served under v3, there was never an r0 version of this endpoint
+pub static DEVICE_SIGNING_UPLOAD_ENDPOINT: &str = "/_matrix/client/v3/keys/device_signing/upload";
+pub static SIGNATURES_UPLOAD_ENDPOINT: &str = "/_matrix/client/r0/keys/signatures/upload";
+
+pub static MASTER: &str = "master";
+pub static SELF_SIGNING: &str = "self_signing";
+pub static USER_SIGNING: &str = "user_signing";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrossSigningKey {
+  pub user_id: String,
+  pub usage: Vec<String>,
+  // A single key keyed by "ed25519:<public key>"
+  pub keys: HashMap<String, String>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub signatures: Signatures,
+}
+
+impl CrossSigningKey {
+  pub fn public_key(&self) -> Option<&str> {
+    self.keys.values().next().map(String::as_str)
+  }
+
+  // Whether the key is signed by user_id's ed25519 key public_key
+  pub fn is_signed_by(&self, user_id: &str, public_key: &str) -> bool {
+    is_signed_by(self, user_id, public_key)
+  }
+}
+
+// A user's public cross-signing keys, the user-signing key is only given out for our own user
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserCrossSigningKeys {
+  pub master: Option<CrossSigningKey>,
+  pub self_signing: Option<CrossSigningKey>,
+  pub user_signing: Option<CrossSigningKey>,
+}
+
+impl UserCrossSigningKeys {
+  pub fn master_key(&self) -> Option<&str> {
+    self.master.as_ref()?.public_key()
+  }
+
+  // The self-signing key, if the master key signed it
+  pub fn self_signing_key(&self) -> Option<&str> {
+    self.signed_by_master(self.self_signing.as_ref()?)
+  }
+
+  // The user-signing key, if the master key signed it
+  pub fn user_signing_key(&self) -> Option<&str> {
+    self.signed_by_master(self.user_signing.as_ref()?)
+  }
+
+  // Whether the device was signed by the user's self-signing key
+  pub fn is_device_signed(&self, device: &DeviceKeys) -> bool {
+    match self.self_signing_key() {
+      Some(self_signing_key) => is_signed_by(device, &device.user_id, self_signing_key),
+      None => false,
+    }
+  }
+
+  fn signed_by_master<'a>(&self, key: &'a CrossSigningKey) -> Option<&'a str> {
+    let master = self.master.as_ref()?;
+    if key.user_id == master.user_id && key.is_signed_by(&master.user_id, self.master_key()?) {
+      key.public_key()
+    } else {
+      None
+    }
+  }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DeviceSigningUploadRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub master_key: Option<CrossSigningKey>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub self_signing_key: Option<CrossSigningKey>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub user_signing_key: Option<CrossSigningKey>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub auth: Option<AuthData>,
+}
+
+// Signed device keys or cross-signing keys keyed by user id, then device id or public key
+pub type SignaturesUploadRequest = HashMap<String, HashMap<String, Value>>;
+
+#[derive(Deserialize, Debug)]
+pub struct SignaturesUploadResponse {
+  // Signatures the server rejected, keyed by user id then device id or public key
+  #[serde(default)]
+  pub failures: HashMap<String, HashMap<String, Value>>,
+}
+
+// Our private cross-signing keys
+pub struct PrivateCrossSigningKeys {
+  user_id: String,
+  master: Ed25519SecretKey,
+  self_signing: Ed25519SecretKey,
+  user_signing: Ed25519SecretKey,
+}
+
+// Private keys as base64, for the crypto store
+#[derive(Serialize, Deserialize)]
+pub struct StoredCrossSigningKeys {
+  pub user_id: String,
+  pub master: String,
+  pub self_signing: String,
+  pub user_signing: String,
+}
+
+impl PrivateCrossSigningKeys {
+  // New random keys for the user
+  pub fn new(user_id: &str) -> PrivateCrossSigningKeys {
+    PrivateCrossSigningKeys {
+      user_id: user_id.to_string(),
+      master: Ed25519SecretKey::new(),
+      self_signing: Ed25519SecretKey::new(),
+      user_signing: Ed25519SecretKey::new(),
+    }
+  }
+
+  pub fn from_stored(stored: StoredCrossSigningKeys) -> Option<PrivateCrossSigningKeys> {
+    Some(PrivateCrossSigningKeys {
+      user_id: stored.user_id,
+      master: Ed25519SecretKey::from_base64(&stored.master).ok()?,
+      self_signing: Ed25519SecretKey::from_base64(&stored.self_signing).ok()?,
+      user_signing: Ed25519SecretKey::from_base64(&stored.user_signing).ok()?,
+    })
+  }
+
+  pub fn to_stored(&self) -> StoredCrossSigningKeys {
+    StoredCrossSigningKeys {
+      user_id: self.user_id.clone(),
+      master: self.master.to_base64(),
+      self_signing: self.self_signing.to_base64(),
+      user_signing: self.user_signing.to_base64(),
+    }
+  }
+
+  pub fn master_key(&self) -> String {
+    self.master.public_key().to_base64()
+  }
+
+  // The public keys, with the self-signing and user-signing keys signed by the master key
+  pub fn public_keys(&self) -> UserCrossSigningKeys {
+    let master = self.public_key(&self.master, MASTER);
+    let mut self_signing = self.public_key(&self.self_signing, SELF_SIGNING);
+    sign(&self.master, &self.user_id, &mut self_signing);
+    let mut user_signing = self.public_key(&self.user_signing, USER_SIGNING);
+    sign(&self.master, &self.user_id, &mut user_signing);
+
+    UserCrossSigningKeys {
+      master: Some(master),
+      self_signing: Some(self_signing),
+      user_signing: Some(user_signing),
+    }
+  }
+
+  // Sign one of our devices with the self-signing key
+  pub fn sign_device(&self, device: &mut DeviceKeys) {
+    sign(&self.self_signing, &self.user_id, device);
+  }
+
+  // Sign another user's master key with the user-signing key
+  pub fn sign_user(&self, master: &mut CrossSigningKey) {
+    sign(&self.user_signing, &self.user_id, master);
+  }
+
+  // Sign anything else of ours with the master key, e.g. a key backup's auth data
+  pub fn sign_with_master(&self, value: &mut Value) {
+    sign(&self.master, &self.user_id, value);
+  }
+
+  fn public_key(&self, key: &Ed25519SecretKey, usage: &str) -> CrossSigningKey {
+    let public_key = key.public_key().to_base64();
+    let mut keys = HashMap::new();
+    keys.insert(format!("ed25519:{}", public_key), public_key);
+    CrossSigningKey {
+      user_id: self.user_id.clone(),
+      usage: vec![usage.to_string()],
+      keys,
+      signatures: HashMap::new(),
+    }
+  }
+}
+
+// Add a signature by the key to a serializable object, e.g. DeviceKeys or CrossSigningKey
+fn sign<T>(key: &Ed25519SecretKey, user_id: &str, signed: &mut T)
+where
+  T: serde::Serialize + serde::de::DeserializeOwned,
+{
+  let mut value = match serde_json::to_value(&*signed) {
+    Ok(value) => value,
+    Err(_) => return,
+  };
+  let signature = key
+    .sign(signatures::canonical_json(&value).as_bytes())
+    .to_base64();
+  let key_id = format!("ed25519:{}", key.public_key().to_base64());
+  signatures::add_signature(&mut value, user_id, &key_id, signature);
+  if let Ok(with_signature) = serde_json::from_value(value) {
+    *signed = with_signature;
+  }
+}
+
+fn is_signed_by<T: serde::Serialize>(signed: &T, user_id: &str, public_key: &str) -> bool {
+  let value = match serde_json::to_value(signed) {
+    Ok(value) => value,
+    Err(_) => return false,
+  };
+  let key_id = format!("ed25519:{}", public_key);
+  signatures::verify_json(&value, user_id, &key_id, public_key).is_ok()
+}
+
+// Publish our cross-signing keys, which requires user-interactive auth
+pub fn upload_signing_keys(
+  client: &MatrixClient,
+  request: &DeviceSigningUploadRequest,
+) -> Result<()> {
+  let response = api::post(client, DEVICE_SIGNING_UPLOAD_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::UNAUTHORIZED => Err(auth::interactive_auth_error(response)),
+    StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn upload_signatures(
+  client: &MatrixClient,
+  request: &SignaturesUploadRequest,
+) -> Result<SignaturesUploadResponse> {
+  let mut response = api::post(client, SIGNATURES_UPLOAD_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,173 @@
+use vodozemac::base64_encode;
+
+use crate::crypto::cipher;
+use crate::crypto::machine::ExportedRoomKey;
+use crate::crypto::{CryptoError, Result};
+
+/*
+Key Exports
+Room keys written to a file encrypted with a passphrase, in the format other clients use so
+keys can be moved between them. The JSON list of keys is encrypted with AES-256-CTR and
+HMAC-SHA256 keys from PBKDF2, then armored as base64.
+
+docs: https://matrix.org/docs/spec/client_server/latest#key-exports
+*/
+
+pub static HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+pub static FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+// The default PBKDF2 rounds other clients use
+pub static DEFAULT_ROUNDS: u32 = 500_000;
+
+static VERSION: u8 = 1;
+static SALT_LENGTH: usize = 16;
+static LINE_LENGTH: usize = 96;
+
+// Encrypt the keys with the passphrase, in the armored format
+pub fn export_keys(keys: &[ExportedRoomKey], passphrase: &str, rounds: u32) -> Result<String> {
+  let plaintext = serde_json::to_vec(keys)?;
+  let salt = cipher::random_bytes(SALT_LENGTH);
+  let keys = cipher::derive_keys(passphrase, &salt, rounds);
+  let iv = cipher::random_iv();
+
+  let mut data = vec![VERSION];
+  data.extend_from_slice(&salt);
+  data.extend_from_slice(&iv);
+  data.extend_from_slice(&rounds.to_be_bytes());
+  let ciphertext_start = data.len();
+  data.extend_from_slice(&plaintext);
+  cipher::aes_ctr(&keys.aes_key, &iv, &mut data[ciphertext_start..]);
+  let mac = cipher::hmac_sha256(&keys.mac_key, &data);
+  data.extend(mac);
+
+  // Other clients expect padded base64
+  let mut encoded = base64_encode(data);
+  while encoded.len() % 4 != 0 {
+    encoded.push('=');
+  }
+  let lines: Vec<String> = encoded
+    .as_bytes()
+    .chunks(LINE_LENGTH)
+    .map(|line| String::from_utf8_lossy(line).into_owned())
+    .collect();
+  Ok(format!("{}\n{}\n{}\n", HEADER, lines.join("\n"), FOOTER))
+}
+
+// Decrypt an export, failing if the passphrase is wrong or the file was changed
+pub fn import_keys(export: &str, passphrase: &str) -> Result<Vec<ExportedRoomKey>> {
+  let start = export
+    .find(HEADER)
+    .ok_or_else(|| CryptoError::Key(String::from("Not a key export")))?
+    + HEADER.len();
+  let end = export[start..]
+    .find(FOOTER)
+    .ok_or_else(|| CryptoError::Key(String::from("The key export is cut short")))?
+    + start;
+  let encoded: String = export[start..end].split_whitespace().collect();
+  let data = cipher::decode_base64(&encoded)
+    .ok_or_else(|| CryptoError::Key(String::from("The key export isn't base64")))?;
+
+  let header_length = 1 + SALT_LENGTH + cipher::IV_LENGTH + 4;
+  if data.len() < header_length + cipher::MAC_LENGTH {
+    return Err(CryptoError::Key(String::from(
+      "The key export is too short",
+    )));
+  }
+  if data[0] != VERSION {
+    return Err(CryptoError::UnsupportedAlgorithm(format!(
+      "Key export version {}",
+      data[0]
+    )));
+  }
+  let salt = &data[1..1 + SALT_LENGTH];
+  let mut iv = [0u8; 16];
+  iv.copy_from_slice(&data[1 + SALT_LENGTH..1 + SALT_LENGTH + cipher::IV_LENGTH]);
+  let mut rounds = [0u8; 4];
+  rounds.copy_from_slice(&data[header_length - 4..header_length]);
+  let rounds = u32::from_be_bytes(rounds);
+
+  let keys = cipher::derive_keys(passphrase, salt, rounds);
+  let (signed, mac) = data.split_at(data.len() - cipher::MAC_LENGTH);
+  if !cipher::verify_hmac_sha256(&keys.mac_key, signed, mac) {
+    return Err(CryptoError::Key(String::from(
+      "Wrong passphrase for the key export",
+    )));
+  }
+  let mut plaintext = signed[header_length..].to_vec();
+  cipher::aes_ctr(&keys.aes_key, &iv, &mut plaintext);
+  Ok(serde_json::from_slice(&plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  // Few rounds keeps the tests fast, the rounds are read back from the export
+  static ROUNDS: u32 = 10;
+
+  fn room_key(session_id: &str) -> ExportedRoomKey {
+    let mut sender_claimed_keys = HashMap::new();
+    sender_claimed_keys.insert(String::from("ed25519"), String::from("signing key"));
+    ExportedRoomKey {
+      algorithm: String::from("m.megolm.v1.aes-sha2"),
+      room_id: String::from("!room:example.org"),
+      sender_key: String::from("sender key"),
+      session_id: session_id.to_string(),
+      session_key: String::from("session key"),
+      sender_claimed_keys,
+      forwarding_curve25519_key_chain: vec![String::from("forwarding key")],
+    }
+  }
+
+  #[test]
+  fn exported_keys_import_again() {
+    let keys = vec![room_key("first"), room_key("second")];
+    let export = export_keys(&keys, "passphrase", ROUNDS).unwrap();
+    let imported = import_keys(&export, "passphrase").unwrap();
+
+    assert_eq!(
+      serde_json::to_value(&imported).unwrap(),
+      serde_json::to_value(&keys).unwrap()
+    );
+  }
+
+  #[test]
+  fn export_is_armored() {
+    let keys: Vec<ExportedRoomKey> = (0..20).map(|i| room_key(&i.to_string())).collect();
+    let export = export_keys(&keys, "passphrase", ROUNDS).unwrap();
+    let lines: Vec<&str> = export.lines().collect();
+
+    assert_eq!(lines.first(), Some(&HEADER));
+    assert_eq!(lines.last(), Some(&FOOTER));
+    let body = &lines[1..lines.len() - 1];
+    assert!(body.len() > 1);
+    assert!(body.iter().all(|line| line.len() <= LINE_LENGTH));
+    assert_eq!(body.concat().len() % 4, 0);
+  }
+
+  #[test]
+  fn wrong_passphrase_is_rejected() {
+    let export = export_keys(&[room_key("first")], "passphrase", ROUNDS).unwrap();
+
+    match import_keys(&export, "wrong passphrase") {
+      Err(CryptoError::Key(_)) => {}
+      result => panic!("expected a key error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn changed_export_is_rejected() {
+    let export = export_keys(&[room_key("first")], "passphrase", ROUNDS).unwrap();
+    let encoded: String = export
+      .lines()
+      .filter(|line| *line != HEADER && *line != FOOTER)
+      .collect();
+    let mut data = cipher::decode_base64(&encoded).unwrap();
+    // The first byte of the encrypted keys, after the version, salt, iv and rounds
+    data[1 + SALT_LENGTH + cipher::IV_LENGTH + 4] ^= 1;
+    let changed = format!("{}\n{}\n{}\n", HEADER, base64_encode(data), FOOTER);
+
+    assert!(import_keys(&changed, "passphrase").is_err());
+    assert!(import_keys("not an export", "passphrase").is_err());
+  }
+}
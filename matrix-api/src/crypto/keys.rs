@@ -0,0 +1,169 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::crypto::cross_signing::CrossSigningKey;
+
+/*
+Keys
+Publish our device's keys and fetch other devices' keys for end-to-end encryption
+
+docs: https://matrix.org/docs/spec/client_server/latest#key-management-api
+*/
+
+pub static UPLOAD_ENDPOINT: &str = "/_matrix/client/r0/keys/upload";
+pub static QUERY_ENDPOINT: &str = "/_matrix/client/r0/keys/query";
+pub static CLAIM_ENDPOINT: &str = "/_matrix/client/r0/keys/claim";
+
+// Signatures keyed by user id then "<algorithm>:<key id>"
+pub type Signatures = HashMap<String, HashMap<String, String>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsignedDeviceInfo {
+  pub device_display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceKeys {
+  pub user_id: String,
+  pub device_id: String,
+  pub algorithms: Vec<String>,
+  // Keyed by "<algorithm>:<device id>"
+  pub keys: HashMap<String, String>,
+  #[serde(default)]
+  pub signatures: Signatures,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub unsigned: Option<UnsignedDeviceInfo>,
+}
+
+impl DeviceKeys {
+  pub fn curve25519_key(&self) -> Option<&str> {
+    self.key("curve25519")
+  }
+
+  pub fn ed25519_key(&self) -> Option<&str> {
+    self.key("ed25519")
+  }
+
+  fn key(&self, algorithm: &str) -> Option<&str> {
+    self
+      .keys
+      .get(&format!("{}:{}", algorithm, self.device_id))
+      .map(String::as_str)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedKey {
+  pub key: String,
+  #[serde(default)]
+  pub signatures: Signatures,
+  // Set on fallback keys, which are used when the one-time keys run out
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fallback: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct KeysUploadRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device_keys: Option<DeviceKeys>,
+  // Keyed by "signed_curve25519:<key id>"
+  #[serde(skip_serializing_if = "HashMap::is_empty")]
+  pub one_time_keys: HashMap<String, SignedKey>,
+  #[serde(skip_serializing_if = "HashMap::is_empty")]
+  pub fallback_keys: HashMap<String, SignedKey>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysUploadResponse {
+  // Number of unclaimed one-time keys on the server, keyed by algorithm
+  pub one_time_key_counts: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct KeysQueryRequest {
+  // Device ids to query for each user, an empty list queries all of them
+  pub device_keys: HashMap<String, Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub timeout: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysQueryResponse {
+  #[serde(default)]
+  pub device_keys: HashMap<String, HashMap<String, DeviceKeys>>,
+  // Cross-signing keys keyed by user id, user-signing keys are only returned for our own user
+  #[serde(default)]
+  pub master_keys: HashMap<String, CrossSigningKey>,
+  #[serde(default)]
+  pub self_signing_keys: HashMap<String, CrossSigningKey>,
+  #[serde(default)]
+  pub user_signing_keys: HashMap<String, CrossSigningKey>,
+  // Servers which couldn't be reached
+  #[serde(default)]
+  pub failures: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct KeysClaimRequest {
+  // Algorithm of the key to claim for each user and device
+  pub one_time_keys: HashMap<String, HashMap<String, String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub timeout: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysClaimResponse {
+  // Keyed by user id, device id then "<algorithm>:<key id>"
+  #[serde(default)]
+  pub one_time_keys: HashMap<String, HashMap<String, HashMap<String, SignedKey>>>,
+  #[serde(default)]
+  pub failures: HashMap<String, Value>,
+}
+
+pub fn upload_keys(
+  client: &MatrixClient,
+  request: &KeysUploadRequest,
+) -> Result<KeysUploadResponse> {
+  let mut response = api::post(client, UPLOAD_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn query_keys(client: &MatrixClient, request: &KeysQueryRequest) -> Result<KeysQueryResponse> {
+  let mut response = api::post(client, QUERY_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn claim_keys(client: &MatrixClient, request: &KeysClaimRequest) -> Result<KeysClaimResponse> {
+  let mut response = api::post(client, CLAIM_ENDPOINT, request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
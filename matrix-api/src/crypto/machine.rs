@@ -0,0 +1,1806 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vodozemac::megolm::{
+  ExportedSessionKey, GroupSession, InboundGroupSession, MegolmMessage,
+  SessionConfig as MegolmConfig, SessionKey,
+};
+use vodozemac::olm::{Account, OlmMessage, Session, SessionConfig as OlmConfig};
+use vodozemac::{base64_decode, base64_encode, Curve25519PublicKey};
+
+use crate::auth;
+use crate::auth::{AuthData, UserInteractiveAuthResponse};
+use crate::client::MatrixClient;
+use crate::crypto::backup;
+use crate::crypto::backup::{
+  BackupAuthData, BackupKey, BackupVersion, CreateVersionRequest, KeyBackupData, KeysBackup,
+  BACKUP_ALGORITHM,
+};
+use crate::crypto::cross_signing;
+use crate::crypto::cross_signing::{
+  DeviceSigningUploadRequest, PrivateCrossSigningKeys, SignaturesUploadRequest,
+  UserCrossSigningKeys,
+};
+use crate::crypto::keys;
+use crate::crypto::keys::{DeviceKeys, KeysClaimRequest, KeysQueryRequest, KeysUploadRequest};
+use crate::crypto::signatures;
+use crate::crypto::store::{
+  CryptoChanges, CryptoStore, StoredAccount, StoredInboundGroupSession, StoredOutboundGroupSession,
+  TrackedUser,
+};
+use crate::crypto::verification;
+use crate::crypto::verification::sas::{Identity, SasVerification};
+use crate::crypto::verification::{cancel_code, VerificationContent, VerificationFlow, REQUEST};
+use crate::crypto::{CryptoError, EncryptionSettings, Result, MEGOLM_ALGORITHM, OLM_ALGORITHM};
+use crate::events::RoomEvent;
+use crate::rooms::send;
+use crate::store;
+use crate::sync::{BasicEvent, DeviceLists, SyncResponse};
+use crate::to_device;
+use crate::to_device::{DeviceMessages, RoomKeyContent, ToDeviceContent, ToDeviceEvent};
+
+/*
+Olm Machine
+Holds this device's Olm account and every session made with it. Feed it each sync response
+with `receive_sync` to decrypt events in place, and share a room key with `share_room_key`
+before encrypting events for the room.
+*/
+
+static SIGNED_CURVE25519: &str = "signed_curve25519";
+
+// Used when the room's m.room.encryption event doesn't say
+static DEFAULT_ROTATION_PERIOD_MS: i64 = 604_800_000;
+static DEFAULT_ROTATION_PERIOD_MSGS: u64 = 100;
+
+// Room keys sent to the backup with each request
+static BACKUP_BATCH_SIZE: usize = 100;
+
+// Our Megolm session for sending to a room
+pub struct OutboundGroupSession {
+  pub session: GroupSession,
+  pub created_ts: i64,
+  pub message_count: u64,
+  // Devices which have been sent the room key, by user id
+  pub shared_with: HashMap<String, HashSet<String>>,
+}
+
+impl OutboundGroupSession {
+  fn expired(&self, settings: &EncryptionSettings) -> bool {
+    let period_ms = settings
+      .rotation_period_ms
+      .unwrap_or(DEFAULT_ROTATION_PERIOD_MS);
+    let period_msgs = settings
+      .rotation_period_msgs
+      .unwrap_or(DEFAULT_ROTATION_PERIOD_MSGS);
+
+    self.message_count >= period_msgs || now_ms() - self.created_ts >= period_ms
+  }
+}
+
+// A Megolm session for decrypting a room's events
+pub struct InboundSession {
+  pub session: InboundGroupSession,
+  pub room_id: String,
+  // Curve25519 key of the device which sent us the room key
+  pub sender_key: String,
+  // Ed25519 key the sending device claimed to own
+  pub signing_key: Option<String>,
+}
+
+// A room key as written to a key export or a key backup
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedRoomKey {
+  pub algorithm: String,
+  pub room_id: String,
+  pub sender_key: String,
+  pub session_id: String,
+  pub session_key: String,
+  #[serde(default)]
+  pub sender_claimed_keys: HashMap<String, String>,
+  #[serde(default)]
+  pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+// A to-device event decrypted from an Olm message
+#[derive(Debug, Clone)]
+pub struct DecryptedToDevice {
+  pub event: BasicEvent,
+  pub sender_key: String,
+  pub signing_key: Option<String>,
+}
+
+pub struct OlmMachine {
+  user_id: String,
+  device_id: String,
+  account: Account,
+  device_keys_uploaded: bool,
+  // Olm sessions keyed by the other device's curve25519 key, most recently used first
+  sessions: HashMap<String, Vec<Session>>,
+  // Keyed by room id
+  outbound_group_sessions: HashMap<String, OutboundGroupSession>,
+  // Keyed by room id and session id
+  inbound_group_sessions: HashMap<(String, String), InboundSession>,
+  // Event id decrypted at each session id and message index, to catch replayed messages
+  decrypted_indexes: HashMap<(String, u32), String>,
+  // Device keys by user id then device id
+  devices: HashMap<String, HashMap<String, DeviceKeys>>,
+  // Users we share rooms with, and those whose device list needs fetching again
+  tracked_users: HashSet<String>,
+  outdated_users: HashSet<String>,
+  // Device ids the user has verified, by user id
+  verified_devices: HashMap<String, HashSet<String>>,
+  // Verifications in progress, keyed by flow id. Not saved, they can't outlive the process.
+  verifications: HashMap<String, SasVerification>,
+  // Our private cross-signing keys, if this device created or was given them
+  cross_signing: Option<PrivateCrossSigningKeys>,
+  // Published cross-signing keys by user id
+  cross_signing_keys: HashMap<String, UserCrossSigningKeys>,
+  // Master keys the user has verified, by user id
+  verified_master_keys: HashMap<String, String>,
+  // The trusted key backup's version and public key. Not saved, enable_backup checks it again
+  // on each start.
+  backup: Option<(String, String)>,
+  // Room and session ids of the room keys uploaded to a backup, with the backup's version
+  backed_up_sessions: HashMap<(String, String), String>,
+}
+
+impl OlmMachine {
+  // A machine with a new account for the device
+  pub fn new(user_id: &str, device_id: &str) -> OlmMachine {
+    OlmMachine {
+      user_id: user_id.to_string(),
+      device_id: device_id.to_string(),
+      account: Account::new(),
+      device_keys_uploaded: false,
+      sessions: HashMap::new(),
+      outbound_group_sessions: HashMap::new(),
+      inbound_group_sessions: HashMap::new(),
+      decrypted_indexes: HashMap::new(),
+      devices: HashMap::new(),
+      tracked_users: HashSet::new(),
+      outdated_users: HashSet::new(),
+      verified_devices: HashMap::new(),
+      verifications: HashMap::new(),
+      cross_signing: None,
+      cross_signing_keys: HashMap::new(),
+      verified_master_keys: HashMap::new(),
+      backup: None,
+      backed_up_sessions: HashMap::new(),
+    }
+  }
+
+  // The machine saved in the store, if there is one
+  pub fn load(store: &dyn CryptoStore) -> store::Result<Option<OlmMachine>> {
+    let account = match store.account()? {
+      Some(account) => account,
+      None => return Ok(None),
+    };
+    let mut machine = OlmMachine::new(&account.user_id, &account.device_id);
+    machine.account = Account::from_pickle(account.pickle);
+    machine.device_keys_uploaded = account.device_keys_uploaded;
+
+    for (sender_key, pickles) in store.sessions()? {
+      let sessions = pickles.into_iter().map(Session::from_pickle).collect();
+      machine.sessions.insert(sender_key, sessions);
+    }
+    for stored in store.inbound_group_sessions()? {
+      let id = (stored.room_id.clone(), stored.session_id.clone());
+      if let Some(version) = stored.backed_up_to {
+        machine.backed_up_sessions.insert(id, version);
+      }
+      let session = InboundSession {
+        session: InboundGroupSession::from_pickle(stored.pickle),
+        room_id: stored.room_id.clone(),
+        sender_key: stored.sender_key,
+        signing_key: stored.signing_key,
+      };
+      machine
+        .inbound_group_sessions
+        .insert((stored.room_id, stored.session_id), session);
+    }
+    for stored in store.outbound_group_sessions()? {
+      let session = OutboundGroupSession {
+        session: GroupSession::from_pickle(stored.pickle),
+        created_ts: stored.created_ts,
+        message_count: stored.message_count,
+        shared_with: stored.shared_with,
+      };
+      machine
+        .outbound_group_sessions
+        .insert(stored.room_id, session);
+    }
+    for user in store.tracked_users()? {
+      if user.outdated {
+        machine.outdated_users.insert(user.user_id.clone());
+      }
+      machine.tracked_users.insert(user.user_id);
+    }
+    machine.devices = store
+      .devices()?
+      .into_iter()
+      .filter(|(user_id, _)| machine.tracked_users.contains(user_id))
+      .collect();
+    machine.verified_devices = store.verified_devices()?;
+    machine.cross_signing = store
+      .cross_signing()?
+      .and_then(PrivateCrossSigningKeys::from_stored);
+    machine.cross_signing_keys = store.cross_signing_keys()?;
+    machine.verified_master_keys = store.verified_master_keys()?;
+
+    Ok(Some(machine))
+  }
+
+  // Write the account and every session to the store
+  pub fn save(&self, store: &mut dyn CryptoStore) -> store::Result<()> {
+    let mut changes = CryptoChanges {
+      account: Some(StoredAccount {
+        user_id: self.user_id.clone(),
+        device_id: self.device_id.clone(),
+        pickle: self.account.pickle(),
+        device_keys_uploaded: self.device_keys_uploaded,
+      }),
+      devices: self.devices.clone(),
+      verified_devices: self.verified_devices.clone(),
+      cross_signing: self
+        .cross_signing
+        .as_ref()
+        .map(PrivateCrossSigningKeys::to_stored),
+      cross_signing_keys: self.cross_signing_keys.clone(),
+      verified_master_keys: self.verified_master_keys.clone(),
+      ..Default::default()
+    };
+
+    for (sender_key, sessions) in &self.sessions {
+      let pickles = sessions.iter().map(Session::pickle).collect();
+      changes.sessions.insert(sender_key.clone(), pickles);
+    }
+    for ((room_id, session_id), inbound) in &self.inbound_group_sessions {
+      changes
+        .inbound_group_sessions
+        .push(StoredInboundGroupSession {
+          room_id: room_id.clone(),
+          session_id: session_id.clone(),
+          sender_key: inbound.sender_key.clone(),
+          signing_key: inbound.signing_key.clone(),
+          pickle: inbound.session.pickle(),
+          backed_up_to: self
+            .backed_up_sessions
+            .get(&(room_id.clone(), session_id.clone()))
+            .cloned(),
+        });
+    }
+    for (room_id, outbound) in &self.outbound_group_sessions {
+      changes
+        .outbound_group_sessions
+        .push(StoredOutboundGroupSession {
+          room_id: room_id.clone(),
+          pickle: outbound.session.pickle(),
+          created_ts: outbound.created_ts,
+          message_count: outbound.message_count,
+          shared_with: outbound.shared_with.clone(),
+        });
+    }
+    changes.tracked_users = Some(
+      self
+        .tracked_users
+        .iter()
+        .map(|user_id| TrackedUser {
+          user_id: user_id.clone(),
+          outdated: self.outdated_users.contains(user_id),
+        })
+        .collect(),
+    );
+
+    store.save_changes(changes)
+  }
+
+  pub fn user_id(&self) -> &str {
+    &self.user_id
+  }
+
+  pub fn device_id(&self) -> &str {
+    &self.device_id
+  }
+
+  pub fn curve25519_key(&self) -> String {
+    self.account.curve25519_key().to_base64()
+  }
+
+  pub fn ed25519_key(&self) -> String {
+    self.account.ed25519_key().to_base64()
+  }
+
+  // Sign the value as this device
+  pub fn sign_json(&self, value: &mut Value) {
+    let signature = self
+      .account
+      .sign(signatures::canonical_json(value))
+      .to_base64();
+    let key_id = format!("ed25519:{}", self.device_id);
+    signatures::add_signature(value, &self.user_id, &key_id, signature);
+  }
+
+  // This device's identity keys, signed by itself
+  pub fn device_keys(&self) -> DeviceKeys {
+    let mut keys = HashMap::new();
+    keys.insert(
+      format!("curve25519:{}", self.device_id),
+      self.curve25519_key(),
+    );
+    keys.insert(format!("ed25519:{}", self.device_id), self.ed25519_key());
+
+    let device_keys = DeviceKeys {
+      user_id: self.user_id.clone(),
+      device_id: self.device_id.clone(),
+      algorithms: vec![OLM_ALGORITHM.to_string(), MEGOLM_ALGORITHM.to_string()],
+      keys,
+      signatures: HashMap::new(),
+      unsigned: None,
+    };
+
+    let mut value = serde_json::to_value(&device_keys).unwrap_or_default();
+    self.sign_json(&mut value);
+    serde_json::from_value(value).unwrap_or(device_keys)
+  }
+
+  // Upload the device keys if they haven't been yet, and top up the one-time keys on the
+  // server. one_time_key_counts is the device_one_time_keys_count from sync.
+  pub fn upload_keys(
+    &mut self,
+    client: &MatrixClient,
+    one_time_key_counts: &HashMap<String, u64>,
+  ) -> Result<()> {
+    let target = self.account.max_number_of_one_time_keys() / 2;
+    let uploaded = one_time_key_counts
+      .get(SIGNED_CURVE25519)
+      .copied()
+      .unwrap_or(0) as usize;
+    let needed = target.saturating_sub(uploaded);
+    if self.device_keys_uploaded && needed == 0 {
+      return Ok(());
+    }
+
+    self.account.generate_one_time_keys(needed);
+    let mut request = KeysUploadRequest::default();
+    if !self.device_keys_uploaded {
+      request.device_keys = Some(self.device_keys());
+    }
+    for (key_id, key) in self.account.one_time_keys() {
+      let mut signed_key = json!({ "key": key.to_base64() });
+      self.sign_json(&mut signed_key);
+      request.one_time_keys.insert(
+        format!("{}:{}", SIGNED_CURVE25519, key_id.to_base64()),
+        serde_json::from_value(signed_key)?,
+      );
+    }
+
+    keys::upload_keys(client, &request)?;
+    self.account.mark_keys_as_published();
+    self.device_keys_uploaded = true;
+    Ok(())
+  }
+
+  // Start keeping track of the users' devices, e.g. the members of an encrypted room
+  pub fn update_tracked_users<I: IntoIterator<Item = String>>(&mut self, user_ids: I) {
+    for user_id in user_ids {
+      if self.tracked_users.insert(user_id.clone()) {
+        self.outdated_users.insert(user_id);
+      }
+    }
+  }
+
+  pub fn receive_device_lists(&mut self, device_lists: &DeviceLists) {
+    for user_id in &device_lists.changed {
+      if self.tracked_users.contains(user_id) {
+        self.outdated_users.insert(user_id.clone());
+      }
+    }
+    for user_id in &device_lists.left {
+      self.tracked_users.remove(user_id);
+      self.outdated_users.remove(user_id);
+      self.devices.remove(user_id);
+    }
+  }
+
+  // Known devices of the user, by device id
+  pub fn user_devices(&self, user_id: &str) -> Option<&HashMap<String, DeviceKeys>> {
+    self.devices.get(user_id)
+  }
+
+  // Fetch the devices of users whose device list has changed
+  pub fn query_keys(&mut self, client: &MatrixClient) -> Result<()> {
+    if self.outdated_users.is_empty() {
+      return Ok(());
+    }
+
+    let request = KeysQueryRequest {
+      device_keys: self
+        .outdated_users
+        .iter()
+        .map(|user_id| (user_id.clone(), Vec::new()))
+        .collect(),
+      timeout: None,
+    };
+    let mut response = keys::query_keys(client, &request)?;
+
+    for (user_id, devices) in response.device_keys {
+      let known = self.devices.remove(&user_id).unwrap_or_default();
+      let mut verified = HashMap::new();
+
+      for (device_id, device_keys) in devices {
+        if device_keys.user_id != user_id || device_keys.device_id != device_id {
+          continue;
+        }
+        let ed25519_key = match device_keys.ed25519_key() {
+          Some(key) => key.to_string(),
+          None => continue,
+        };
+        // A device's keys never change, a different key means something is wrong
+        if let Some(previous) = known.get(&device_id) {
+          if previous.ed25519_key() != Some(&ed25519_key) {
+            continue;
+          }
+        }
+        let value = serde_json::to_value(&device_keys)?;
+        let key_id = format!("ed25519:{}", device_id);
+        if signatures::verify_json(&value, &user_id, &key_id, &ed25519_key).is_err() {
+          continue;
+        }
+        verified.insert(device_id, device_keys);
+      }
+
+      self.outdated_users.remove(&user_id);
+      self.devices.insert(user_id, verified);
+    }
+
+    for (user_id, master) in response.master_keys {
+      if master.user_id != user_id || !master.usage.iter().any(|u| u == cross_signing::MASTER) {
+        continue;
+      }
+      let user_keys = UserCrossSigningKeys {
+        master: Some(master),
+        self_signing: response.self_signing_keys.remove(&user_id),
+        user_signing: response.user_signing_keys.remove(&user_id),
+      };
+      self.cross_signing_keys.insert(user_id, user_keys);
+    }
+
+    Ok(())
+  }
+
+  // Claim one-time keys and start Olm sessions with the devices which don't have one yet
+  fn ensure_olm_sessions(&mut self, client: &MatrixClient, devices: &[DeviceKeys]) -> Result<()> {
+    let mut request = KeysClaimRequest::default();
+    for device in devices {
+      let has_session = device
+        .curve25519_key()
+        .map(|key| self.sessions.contains_key(key))
+        .unwrap_or(true);
+      if !has_session {
+        request
+          .one_time_keys
+          .entry(device.user_id.clone())
+          .or_default()
+          .insert(device.device_id.clone(), SIGNED_CURVE25519.to_string());
+      }
+    }
+    if request.one_time_keys.is_empty() {
+      return Ok(());
+    }
+
+    let response = keys::claim_keys(client, &request)?;
+    for (user_id, user_keys) in response.one_time_keys {
+      for (device_id, device_keys) in user_keys {
+        let device = match devices
+          .iter()
+          .find(|d| d.user_id == user_id && d.device_id == device_id)
+        {
+          Some(device) => device,
+          None => continue,
+        };
+        let (curve25519_key, ed25519_key) = match (device.curve25519_key(), device.ed25519_key()) {
+          (Some(curve25519_key), Some(ed25519_key)) => (curve25519_key, ed25519_key),
+          _ => continue,
+        };
+
+        for one_time_key in device_keys.values() {
+          let value = serde_json::to_value(one_time_key)?;
+          let key_id = format!("ed25519:{}", device_id);
+          if signatures::verify_json(&value, &user_id, &key_id, ed25519_key).is_err() {
+            continue;
+          }
+          let identity_key = Curve25519PublicKey::from_base64(curve25519_key)
+            .map_err(|e| CryptoError::Olm(e.to_string()))?;
+          let one_time_key = Curve25519PublicKey::from_base64(&one_time_key.key)
+            .map_err(|e| CryptoError::Olm(e.to_string()))?;
+
+          let session = self.account.create_outbound_session(
+            OlmConfig::version_1(),
+            identity_key,
+            one_time_key,
+          );
+          self
+            .sessions
+            .entry(curve25519_key.to_string())
+            .or_default()
+            .insert(0, session);
+          break;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  // Encrypt a to-device event for a device we have an Olm session with
+  pub fn olm_encrypt(
+    &mut self,
+    device: &DeviceKeys,
+    event_type: &str,
+    content: &Value,
+  ) -> Result<Value> {
+    let (curve25519_key, ed25519_key) = match (device.curve25519_key(), device.ed25519_key()) {
+      (Some(curve25519_key), Some(ed25519_key)) => (curve25519_key, ed25519_key),
+      _ => {
+        return Err(CryptoError::Olm(format!(
+          "{} has no identity keys",
+          device.device_id
+        )))
+      }
+    };
+
+    let payload = json!({
+      "type": event_type,
+      "content": content,
+      "sender": self.user_id,
+      "sender_device": self.device_id,
+      "keys": { "ed25519": self.ed25519_key() },
+      "recipient": device.user_id,
+      "recipient_keys": { "ed25519": ed25519_key },
+    });
+
+    let session = self
+      .sessions
+      .get_mut(curve25519_key)
+      .and_then(|sessions| sessions.first_mut())
+      .ok_or_else(|| CryptoError::MissingSession(curve25519_key.to_string()))?;
+    let (message_type, body) = session.encrypt(payload.to_string()).to_parts();
+
+    let mut ciphertext = serde_json::Map::new();
+    ciphertext.insert(
+      curve25519_key.to_string(),
+      json!({ "type": message_type, "body": base64_encode(body) }),
+    );
+    Ok(json!({
+      "algorithm": OLM_ALGORITHM,
+      "sender_key": self.curve25519_key(),
+      "ciphertext": ciphertext,
+    }))
+  }
+
+  // Make sure every device of the members has our current room key, starting a new session
+  // when the old one has expired or someone has left. members are joined and invited users.
+  pub fn share_room_key(
+    &mut self,
+    client: &MatrixClient,
+    room_id: &str,
+    members: &[String],
+    settings: &EncryptionSettings,
+  ) -> Result<()> {
+    if settings.algorithm != MEGOLM_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(
+        settings.algorithm.clone(),
+      ));
+    }
+
+    self.update_tracked_users(members.iter().cloned());
+    self.query_keys(client)?;
+
+    let rotate = match self.outbound_group_sessions.get(room_id) {
+      Some(outbound) => {
+        outbound.expired(settings)
+          || outbound
+            .shared_with
+            .keys()
+            .any(|user_id| !members.contains(user_id))
+      }
+      None => true,
+    };
+    if rotate {
+      self.create_outbound_group_session(room_id);
+    }
+
+    let shared_with = &self.outbound_group_sessions[room_id].shared_with;
+    let mut devices = Vec::new();
+    for user_id in members {
+      for (device_id, device) in self.devices.get(user_id).into_iter().flatten() {
+        let own_device = *user_id == self.user_id && *device_id == self.device_id;
+        let shared = shared_with
+          .get(user_id)
+          .map(|devices| devices.contains(device_id))
+          .unwrap_or(false);
+        if !own_device && !shared {
+          devices.push(device.clone());
+        }
+      }
+    }
+    if devices.is_empty() {
+      return Ok(());
+    }
+
+    self.ensure_olm_sessions(client, &devices)?;
+
+    let session = &self.outbound_group_sessions[room_id].session;
+    let room_key = serde_json::to_value(RoomKeyContent {
+      algorithm: MEGOLM_ALGORITHM.to_string(),
+      room_id: room_id.to_string(),
+      session_id: session.session_id(),
+      session_key: session.session_key().to_base64(),
+    })?;
+
+    let mut messages = DeviceMessages::new();
+    let mut sent_to = Vec::new();
+    for device in &devices {
+      // Devices without any one-time keys left can't be sent the key
+      if let Ok(content) = self.olm_encrypt(device, "m.room_key", &room_key) {
+        to_device::add_message(&mut messages, &device.user_id, &device.device_id, content);
+        sent_to.push((device.user_id.clone(), device.device_id.clone()));
+      }
+    }
+    if messages.is_empty() {
+      return Ok(());
+    }
+
+    to_device::send_to_device(client, "m.room.encrypted", &new_txn_id(), &messages)?;
+
+    let shared_with = &mut self
+      .outbound_group_sessions
+      .get_mut(room_id)
+      .expect("outbound session was created above")
+      .shared_with;
+    for (user_id, device_id) in sent_to {
+      shared_with.entry(user_id).or_default().insert(device_id);
+    }
+    Ok(())
+  }
+
+  fn create_outbound_group_session(&mut self, room_id: &str) {
+    let session = GroupSession::new(MegolmConfig::version_1());
+
+    // Keep an inbound copy so we can read our own messages
+    let inbound = InboundSession {
+      session: InboundGroupSession::new(&session.session_key(), MegolmConfig::version_1()),
+      room_id: room_id.to_string(),
+      sender_key: self.curve25519_key(),
+      signing_key: Some(self.ed25519_key()),
+    };
+    self
+      .inbound_group_sessions
+      .insert((room_id.to_string(), session.session_id()), inbound);
+
+    self.outbound_group_sessions.insert(
+      room_id.to_string(),
+      OutboundGroupSession {
+        session,
+        created_ts: now_ms(),
+        message_count: 0,
+        shared_with: HashMap::new(),
+      },
+    );
+  }
+
+  // Encrypt an event for the room, `share_room_key` has to be called first.
+  // Returns the content of the m.room.encrypted event to send instead.
+  pub fn encrypt_room_event(
+    &mut self,
+    room_id: &str,
+    event_type: &str,
+    content: &Value,
+  ) -> Result<Value> {
+    let sender_key = self.curve25519_key();
+    let outbound = self
+      .outbound_group_sessions
+      .get_mut(room_id)
+      .ok_or_else(|| CryptoError::MissingSession(room_id.to_string()))?;
+
+    let payload = json!({
+      "type": event_type,
+      "content": content,
+      "room_id": room_id,
+    });
+    let message = outbound.session.encrypt(payload.to_string());
+    outbound.message_count += 1;
+
+    Ok(json!({
+      "algorithm": MEGOLM_ALGORITHM,
+      "sender_key": sender_key,
+      "ciphertext": message.to_base64(),
+      "session_id": outbound.session.session_id(),
+      "device_id": self.device_id,
+    }))
+  }
+
+  // The event with its decrypted type and content
+  pub fn decrypt_room_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<RoomEvent> {
+    let algorithm = event.content["algorithm"].as_str().unwrap_or_default();
+    if algorithm != MEGOLM_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(algorithm.to_string()));
+    }
+    let session_id = event.content["session_id"].as_str().unwrap_or_default();
+    let ciphertext = event.content["ciphertext"].as_str().unwrap_or_default();
+    let id = (room_id.to_string(), session_id.to_string());
+
+    // The room key must have come from one of the sender's devices, or anyone we share an
+    // Olm session with could send events in someone else's name
+    let inbound = self
+      .inbound_group_sessions
+      .get(&id)
+      .ok_or_else(|| CryptoError::MissingSession(session_id.to_string()))?;
+    if let Some(sender_key) = event.content["sender_key"].as_str() {
+      if sender_key != inbound.sender_key {
+        return Err(CryptoError::Mismatch(String::from(
+          "Event's sender key doesn't match its session",
+        )));
+      }
+    }
+    if !self.is_device_of(
+      &event.sender,
+      &inbound.sender_key,
+      inbound.signing_key.as_deref(),
+    ) {
+      return Err(CryptoError::Mismatch(format!(
+        "Session wasn't created by a device of {}",
+        event.sender
+      )));
+    }
+
+    let inbound = self
+      .inbound_group_sessions
+      .get_mut(&id)
+      .ok_or_else(|| CryptoError::MissingSession(session_id.to_string()))?;
+    let message =
+      MegolmMessage::from_base64(ciphertext).map_err(|e| CryptoError::Megolm(e.to_string()))?;
+    let decrypted = inbound
+      .session
+      .decrypt(&message)
+      .map_err(|e| CryptoError::Megolm(e.to_string()))?;
+
+    let index = (session_id.to_string(), decrypted.message_index);
+    match self.decrypted_indexes.get(&index) {
+      Some(event_id) if *event_id != event.event_id => {
+        return Err(CryptoError::Mismatch(format!(
+          "Message index {} was already used by {}",
+          decrypted.message_index, event_id
+        )))
+      }
+      _ => {
+        self.decrypted_indexes.insert(index, event.event_id.clone());
+      }
+    }
+
+    let payload: Value = serde_json::from_slice(&decrypted.plaintext)?;
+    if payload["room_id"].as_str() != Some(room_id) {
+      return Err(CryptoError::Mismatch(String::from(
+        "Event was encrypted for another room",
+      )));
+    }
+
+    let mut decrypted_event = event.clone();
+    decrypted_event.r#type = payload["type"].as_str().unwrap_or_default().to_string();
+    decrypted_event.content = payload["content"].clone();
+    Ok(decrypted_event)
+  }
+
+  // Decrypt an m.room.encrypted to-device event sent with Olm
+  pub fn decrypt_to_device(&mut self, event: &BasicEvent) -> Result<DecryptedToDevice> {
+    let algorithm = event.content["algorithm"].as_str().unwrap_or_default();
+    if algorithm != OLM_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(algorithm.to_string()));
+    }
+    let sender = event.sender.clone().unwrap_or_default();
+    let sender_key = event.content["sender_key"]
+      .as_str()
+      .unwrap_or_default()
+      .to_string();
+    let ciphertext = &event.content["ciphertext"][self.curve25519_key()];
+    let message_type = ciphertext["type"]
+      .as_u64()
+      .ok_or_else(|| CryptoError::Olm(String::from("Not encrypted for this device")))?;
+    let body = base64_decode(ciphertext["body"].as_str().unwrap_or_default())
+      .map_err(|e| CryptoError::Olm(e.to_string()))?;
+    let message = OlmMessage::from_parts(message_type as usize, &body)
+      .map_err(|e| CryptoError::Olm(e.to_string()))?;
+
+    let plaintext = self.olm_decrypt(&sender_key, &message)?;
+    let payload: Value = serde_json::from_slice(&plaintext)?;
+
+    if payload["sender"].as_str() != Some(sender.as_str()) {
+      return Err(CryptoError::Mismatch(String::from("Sender doesn't match")));
+    }
+    if payload["recipient"].as_str() != Some(self.user_id.as_str())
+      || payload["recipient_keys"]["ed25519"].as_str() != Some(self.ed25519_key().as_str())
+    {
+      return Err(CryptoError::Mismatch(String::from(
+        "Event was encrypted for another device",
+      )));
+    }
+
+    Ok(DecryptedToDevice {
+      event: BasicEvent {
+        r#type: payload["type"].as_str().unwrap_or_default().to_string(),
+        content: payload["content"].clone(),
+        sender: Some(sender),
+      },
+      sender_key,
+      signing_key: payload["keys"]["ed25519"].as_str().map(String::from),
+    })
+  }
+
+  fn olm_decrypt(&mut self, sender_key: &str, message: &OlmMessage) -> Result<Vec<u8>> {
+    if let Some(sessions) = self.sessions.get_mut(sender_key) {
+      for i in 0..sessions.len() {
+        if let Ok(plaintext) = sessions[i].decrypt(message) {
+          let session = sessions.remove(i);
+          sessions.insert(0, session);
+          return Ok(plaintext);
+        }
+      }
+    }
+
+    // Only a pre-key message can start a new session
+    let pre_key_message = match message {
+      OlmMessage::PreKey(pre_key_message) => pre_key_message,
+      OlmMessage::Normal(_) => return Err(CryptoError::MissingSession(sender_key.to_string())),
+    };
+    let identity_key =
+      Curve25519PublicKey::from_base64(sender_key).map_err(|e| CryptoError::Olm(e.to_string()))?;
+    let result = self
+      .account
+      .create_inbound_session(identity_key, pre_key_message)
+      .map_err(|e| CryptoError::Olm(e.to_string()))?;
+
+    self
+      .sessions
+      .entry(sender_key.to_string())
+      .or_default()
+      .insert(0, result.session);
+    Ok(result.plaintext)
+  }
+
+  // Start decrypting a room's events with a room key sent to us over Olm. The key is kept
+  // with the sending device's keys, decrypt_room_event checks they belong to each event's
+  // sender.
+  pub fn receive_room_key(&mut self, decrypted: &DecryptedToDevice) -> Result<()> {
+    let content = match ToDeviceEvent::from(&decrypted.event).content {
+      ToDeviceContent::RoomKey(content) => content,
+      _ => return Err(CryptoError::Megolm(String::from("Not a room key"))),
+    };
+    // Devices which aren't known yet are checked when the key is used instead, as to-device
+    // events aren't sent again
+    let sender = decrypted.event.sender.as_deref().unwrap_or_default();
+    if self.devices.contains_key(sender)
+      && !self.is_device_of(
+        sender,
+        &decrypted.sender_key,
+        decrypted.signing_key.as_deref(),
+      )
+    {
+      return Err(CryptoError::Mismatch(format!(
+        "Room key wasn't sent by a device of {}",
+        sender
+      )));
+    }
+    if content.algorithm != MEGOLM_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(content.algorithm));
+    }
+    let RoomKeyContent {
+      room_id,
+      session_id,
+      session_key,
+      ..
+    } = content;
+    let session_key =
+      SessionKey::from_base64(&session_key).map_err(|e| CryptoError::Megolm(e.to_string()))?;
+
+    let session = InboundGroupSession::new(&session_key, MegolmConfig::version_1());
+    if session.session_id() != session_id {
+      return Err(CryptoError::Mismatch(String::from(
+        "Room key doesn't match its session id",
+      )));
+    }
+
+    // Keep the session we already have, it can't be replaced by someone else's key
+    self
+      .inbound_group_sessions
+      .entry((room_id.clone(), session_id))
+      .or_insert(InboundSession {
+        session,
+        room_id,
+        sender_key: decrypted.sender_key.clone(),
+        signing_key: decrypted.signing_key.clone(),
+      });
+    Ok(())
+  }
+
+  // Every room key we hold, at the first index we know of
+  pub fn export_room_keys(&self) -> Vec<ExportedRoomKey> {
+    self
+      .inbound_group_sessions
+      .iter()
+      .map(|((room_id, session_id), inbound)| self.export_room_key(room_id, session_id, inbound))
+      .collect()
+  }
+
+  // Take in exported room keys, keeping whichever copy of a session starts earlier. Returns
+  // how many were new or better than what we had.
+  pub fn import_room_keys(&mut self, keys: &[ExportedRoomKey]) -> usize {
+    let mut imported = 0;
+    for key in keys {
+      if key.algorithm != MEGOLM_ALGORITHM {
+        continue;
+      }
+      let session_key = match ExportedSessionKey::from_base64(&key.session_key) {
+        Ok(session_key) => session_key,
+        Err(_) => continue,
+      };
+      let session = InboundGroupSession::import(&session_key, MegolmConfig::version_1());
+      if session.session_id() != key.session_id {
+        continue;
+      }
+
+      let id = (key.room_id.clone(), key.session_id.clone());
+      let better = match self.inbound_group_sessions.get(&id) {
+        Some(existing) => session.first_known_index() < existing.session.first_known_index(),
+        None => true,
+      };
+      if better {
+        self.inbound_group_sessions.insert(
+          id.clone(),
+          InboundSession {
+            session,
+            room_id: key.room_id.clone(),
+            sender_key: key.sender_key.clone(),
+            signing_key: key.sender_claimed_keys.get("ed25519").cloned(),
+          },
+        );
+        self.backed_up_sessions.remove(&id);
+        imported += 1;
+      }
+    }
+    imported
+  }
+
+  // Upload room keys to the backup if its auth data was signed by this device, one of our
+  // verified devices or our trusted master key
+  pub fn enable_backup(&mut self, version: &BackupVersion) -> Result<()> {
+    if version.algorithm != BACKUP_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(version.algorithm.clone()));
+    }
+    let auth_data = serde_json::to_value(&version.auth_data)?;
+    let key_ids: Vec<String> = version
+      .auth_data
+      .signatures
+      .get(&self.user_id)
+      .map(|signatures| signatures.keys().cloned().collect())
+      .unwrap_or_default();
+
+    let trusted = key_ids.iter().any(|key_id| {
+      let id = key_id.trim_start_matches("ed25519:");
+      let public_key = if id == self.device_id {
+        Some(self.ed25519_key())
+      } else if self.master_key(&self.user_id).as_deref() == Some(id) {
+        Some(id.to_string()).filter(|_| self.is_user_verified(&self.user_id))
+      } else {
+        self
+          .devices
+          .get(&self.user_id)
+          .and_then(|devices| devices.get(id))
+          .filter(|_| self.is_device_verified(&self.user_id, id))
+          .and_then(|device| device.ed25519_key().map(str::to_string))
+      };
+      match public_key {
+        Some(public_key) => {
+          signatures::verify_json(&auth_data, &self.user_id, key_id, &public_key).is_ok()
+        }
+        None => false,
+      }
+    });
+    if !trusted {
+      return Err(CryptoError::Signature(String::from(
+        "The key backup isn't signed by a trusted device",
+      )));
+    }
+
+    // Keys uploaded to an older backup aren't in this one
+    self
+      .backed_up_sessions
+      .retain(|_, backed_up_to| *backed_up_to == version.version);
+    self.backup = Some((
+      version.version.clone(),
+      version.auth_data.public_key.clone(),
+    ));
+    Ok(())
+  }
+
+  pub fn disable_backup(&mut self) {
+    self.backup = None;
+    self.backed_up_sessions.clear();
+  }
+
+  // The enabled backup's version
+  pub fn backup_version(&self) -> Option<&str> {
+    self.backup.as_ref().map(|(version, _)| version.as_str())
+  }
+
+  // Upload room keys which aren't in the backup yet, a batch at a time. Returns how many were
+  // uploaded. If the backup was replaced by another one it's disabled.
+  pub fn backup_room_keys(&mut self, client: &MatrixClient) -> Result<usize> {
+    let (version, public_key) = match &self.backup {
+      Some(backup) => backup.clone(),
+      None => return Ok(0),
+    };
+
+    let mut keys = KeysBackup::default();
+    let mut uploaded = Vec::new();
+    for ((room_id, session_id), inbound) in &self.inbound_group_sessions {
+      if uploaded.len() >= BACKUP_BATCH_SIZE {
+        break;
+      }
+      let id = (room_id.clone(), session_id.clone());
+      if self.backed_up_sessions.get(&id) == Some(&version) {
+        continue;
+      }
+
+      let exported = self.export_room_key(room_id, session_id, inbound);
+      let session_data = backup::encrypt(&public_key, &serde_json::to_value(&exported)?)?;
+      let is_verified = self.is_sender_verified(&inbound.sender_key);
+      keys
+        .rooms
+        .entry(room_id.clone())
+        .or_default()
+        .sessions
+        .insert(
+          session_id.clone(),
+          KeyBackupData {
+            first_message_index: inbound.session.first_known_index(),
+            forwarded_count: 0,
+            is_verified,
+            session_data,
+          },
+        );
+      uploaded.push(id);
+    }
+    if uploaded.is_empty() {
+      return Ok(0);
+    }
+
+    match backup::upload_keys(client, &version, &keys) {
+      Ok(_) => {}
+      Err(e) if e.is_wrong_room_keys_version() => {
+        self.disable_backup();
+        return Err(CryptoError::Api(e));
+      }
+      Err(e) => return Err(CryptoError::Api(e)),
+    }
+    let count = uploaded.len();
+    self
+      .backed_up_sessions
+      .extend(uploaded.into_iter().map(|id| (id, version.clone())));
+    Ok(count)
+  }
+
+  // Create a new key backup for the key and start uploading to it. A key derived from a
+  // passphrase needs its salt and iteration count so it can be derived again.
+  pub fn create_backup(
+    &mut self,
+    client: &MatrixClient,
+    key: &BackupKey,
+    passphrase: Option<(String, u32)>,
+  ) -> Result<String> {
+    let (private_key_salt, private_key_iterations) = match passphrase {
+      Some((salt, iterations)) => (Some(salt), Some(iterations)),
+      None => (None, None),
+    };
+    let mut auth_data = serde_json::to_value(BackupAuthData {
+      public_key: key.public_key(),
+      private_key_salt,
+      private_key_iterations,
+      signatures: HashMap::new(),
+    })?;
+    self.sign_json(&mut auth_data);
+    if let Some(private_keys) = &self.cross_signing {
+      private_keys.sign_with_master(&mut auth_data);
+    }
+
+    let request = CreateVersionRequest {
+      algorithm: BACKUP_ALGORITHM.to_string(),
+      auth_data: serde_json::from_value(auth_data)?,
+    };
+    let version = backup::create_version(client, &request)?;
+    self.backed_up_sessions.clear();
+    self.backup = Some((version.clone(), key.public_key()));
+    Ok(version)
+  }
+
+  // Download and import the room keys in the current backup. Returns how many were imported.
+  pub fn restore_backup(&mut self, client: &MatrixClient, key: &BackupKey) -> Result<usize> {
+    let version = match backup::latest_version(client)? {
+      Some(version) => version,
+      None => return Err(CryptoError::Key(String::from("There is no key backup"))),
+    };
+    if version.auth_data.public_key != key.public_key() {
+      return Err(CryptoError::Key(String::from(
+        "The key doesn't match the key backup",
+      )));
+    }
+
+    let mut keys = Vec::new();
+    for (room_id, room) in backup::download_keys(client, &version.version)?.rooms {
+      for (session_id, data) in room.sessions {
+        // Sessions which can't be decrypted are skipped, they may be from another client
+        let mut session = match key.decrypt(&data.session_data) {
+          Ok(session) => session,
+          Err(_) => continue,
+        };
+        session["room_id"] = json!(room_id);
+        session["session_id"] = json!(session_id);
+        if let Ok(exported) = serde_json::from_value(session) {
+          keys.push(exported);
+        }
+      }
+    }
+
+    let imported = self.import_room_keys(&keys);
+    if self.backup_version() == Some(version.version.as_str()) {
+      self.backed_up_sessions.extend(
+        keys
+          .into_iter()
+          .map(|key| ((key.room_id, key.session_id), version.version.clone())),
+      );
+    }
+    Ok(imported)
+  }
+
+  // Whether the user verified the device, or its owner's self-signing key signed it and the
+  // owner is verified
+  pub fn is_device_verified(&self, user_id: &str, device_id: &str) -> bool {
+    let verified = self
+      .verified_devices
+      .get(user_id)
+      .map(|devices| devices.contains(device_id))
+      .unwrap_or(false);
+    if verified {
+      return true;
+    }
+
+    let device = match self.devices.get(user_id).and_then(|d| d.get(device_id)) {
+      Some(device) => device,
+      None => return false,
+    };
+    match self.cross_signing_keys.get(user_id) {
+      Some(user_keys) => self.is_user_verified(user_id) && user_keys.is_device_signed(device),
+      None => false,
+    }
+  }
+
+  // Whether the user's master key is trusted. It is if the user verified it, if it's ours and
+  // we hold its private key, or if our verified user-signing key signed it.
+  pub fn is_user_verified(&self, user_id: &str) -> bool {
+    let user_keys = match self.cross_signing_keys.get(user_id) {
+      Some(user_keys) => user_keys,
+      None => return false,
+    };
+    let master_key = match user_keys.master_key() {
+      Some(master_key) => master_key,
+      None => return false,
+    };
+    if self.verified_master_keys.get(user_id).map(String::as_str) == Some(master_key) {
+      return true;
+    }
+    if user_id == self.user_id {
+      return self
+        .cross_signing
+        .as_ref()
+        .map(|keys| keys.master_key() == master_key)
+        .unwrap_or(false);
+    }
+
+    if !self.is_user_verified(&self.user_id) {
+      return false;
+    }
+    let user_signing_key = match self
+      .cross_signing_keys
+      .get(&self.user_id)
+      .and_then(UserCrossSigningKeys::user_signing_key)
+    {
+      Some(user_signing_key) => user_signing_key,
+      None => return false,
+    };
+    match &user_keys.master {
+      Some(master) => master.is_signed_by(&self.user_id, user_signing_key),
+      None => false,
+    }
+  }
+
+  // The user's published cross-signing keys, once they've been queried
+  pub fn user_cross_signing_keys(&self, user_id: &str) -> Option<&UserCrossSigningKeys> {
+    self.cross_signing_keys.get(user_id)
+  }
+
+  pub fn has_cross_signing_keys(&self) -> bool {
+    self.cross_signing.is_some()
+  }
+
+  // Create new cross-signing keys and publish them. Publishing needs user-interactive auth,
+  // complete_stage is asked for each stage. The private keys only exist in this machine, so
+  // save it before signing this device with cross_sign_device.
+  pub fn bootstrap_cross_signing<A>(
+    &mut self,
+    client: &MatrixClient,
+    complete_stage: A,
+  ) -> Result<()>
+  where
+    A: FnMut(&UserInteractiveAuthResponse) -> Option<AuthData>,
+  {
+    let private_keys = PrivateCrossSigningKeys::new(&self.user_id);
+    let mut public_keys = private_keys.public_keys();
+    // This device signs the master key too, so other devices can see where it came from
+    if let Some(master) = public_keys.master.as_mut() {
+      let mut value = serde_json::to_value(&*master)?;
+      self.sign_json(&mut value);
+      *master = serde_json::from_value(value)?;
+    }
+
+    auth::with_interactive_auth(
+      |auth| {
+        let request = DeviceSigningUploadRequest {
+          master_key: public_keys.master.clone(),
+          self_signing_key: public_keys.self_signing.clone(),
+          user_signing_key: public_keys.user_signing.clone(),
+          auth,
+        };
+        cross_signing::upload_signing_keys(client, &request)
+      },
+      complete_stage,
+    )?;
+
+    self.cross_signing = Some(private_keys);
+    self
+      .cross_signing_keys
+      .insert(self.user_id.clone(), public_keys);
+    Ok(())
+  }
+
+  // Sign one of our own devices with our self-signing key
+  pub fn cross_sign_device(&mut self, client: &MatrixClient, device_id: &str) -> Result<()> {
+    let mut device = if device_id == self.device_id {
+      self.device_keys()
+    } else {
+      match self
+        .devices
+        .get(&self.user_id)
+        .and_then(|d| d.get(device_id))
+      {
+        Some(device) => device.clone(),
+        None => return Err(CryptoError::MissingSession(device_id.to_string())),
+      }
+    };
+    let private_keys = match &self.cross_signing {
+      Some(private_keys) => private_keys,
+      None => {
+        return Err(CryptoError::Signature(String::from(
+          "No cross-signing keys",
+        )))
+      }
+    };
+    // Only the new signature is uploaded, the server merges it with the others
+    device.signatures.clear();
+    private_keys.sign_device(&mut device);
+
+    let mut request = SignaturesUploadRequest::new();
+    request
+      .entry(self.user_id.clone())
+      .or_default()
+      .insert(device_id.to_string(), serde_json::to_value(&device)?);
+    self.upload_signatures(client, &request)
+  }
+
+  // Sign another user's master key with our user-signing key
+  pub fn cross_sign_user(&mut self, client: &MatrixClient, user_id: &str) -> Result<()> {
+    let mut master = match self
+      .cross_signing_keys
+      .get(user_id)
+      .and_then(|keys| keys.master.clone())
+    {
+      Some(master) => master,
+      None => return Err(CryptoError::MissingSession(user_id.to_string())),
+    };
+    let private_keys = match &self.cross_signing {
+      Some(private_keys) => private_keys,
+      None => {
+        return Err(CryptoError::Signature(String::from(
+          "No cross-signing keys",
+        )))
+      }
+    };
+    let master_key = master.public_key().unwrap_or_default().to_string();
+    master.signatures.clear();
+    private_keys.sign_user(&mut master);
+
+    let mut request = SignaturesUploadRequest::new();
+    request
+      .entry(user_id.to_string())
+      .or_default()
+      .insert(master_key, serde_json::to_value(&master)?);
+    self.upload_signatures(client, &request)
+  }
+
+  // Upload signatures, then fetch the signed keys again to see them
+  fn upload_signatures(
+    &mut self,
+    client: &MatrixClient,
+    request: &SignaturesUploadRequest,
+  ) -> Result<()> {
+    let response = cross_signing::upload_signatures(client, request)?;
+    if !response.failures.is_empty() {
+      return Err(CryptoError::Signature(format!(
+        "The server rejected signatures: {}",
+        serde_json::to_string(&response.failures)?
+      )));
+    }
+
+    for user_id in request.keys() {
+      if self.tracked_users.contains(user_id) {
+        self.outdated_users.insert(user_id.clone());
+      }
+    }
+    Ok(())
+  }
+
+  fn export_room_key(
+    &self,
+    room_id: &str,
+    session_id: &str,
+    inbound: &InboundSession,
+  ) -> ExportedRoomKey {
+    let mut sender_claimed_keys = HashMap::new();
+    if let Some(signing_key) = &inbound.signing_key {
+      sender_claimed_keys.insert(String::from("ed25519"), signing_key.clone());
+    }
+    ExportedRoomKey {
+      algorithm: MEGOLM_ALGORITHM.to_string(),
+      room_id: room_id.to_string(),
+      sender_key: inbound.sender_key.clone(),
+      session_id: session_id.to_string(),
+      session_key: inbound.session.export_at_first_known_index().to_base64(),
+      sender_claimed_keys,
+      forwarding_curve25519_key_chain: Vec::new(),
+    }
+  }
+
+  // Whether the curve25519 key, and the ed25519 key if given, belong to one of the user's
+  // known devices
+  fn is_device_of(&self, user_id: &str, sender_key: &str, signing_key: Option<&str>) -> bool {
+    let devices = match self.devices.get(user_id) {
+      Some(devices) => devices,
+      None => return false,
+    };
+    devices.values().any(|device| {
+      device.curve25519_key() == Some(sender_key)
+        && (signing_key.is_none() || device.ed25519_key() == signing_key)
+    })
+  }
+
+  // Whether the device with the curve25519 key is verified
+  fn is_sender_verified(&self, sender_key: &str) -> bool {
+    self.devices.iter().any(|(user_id, devices)| {
+      devices.iter().any(|(device_id, device)| {
+        device.curve25519_key() == Some(sender_key) && self.is_device_verified(user_id, device_id)
+      })
+    })
+  }
+
+  pub fn verification(&self, flow_id: &str) -> Option<&SasVerification> {
+    self.verifications.get(flow_id)
+  }
+
+  pub fn verifications(&self) -> impl Iterator<Item = &SasVerification> {
+    self.verifications.values()
+  }
+
+  // Ask one of the user's devices to verify with us over to-device events
+  pub fn request_verification(
+    &mut self,
+    client: &MatrixClient,
+    user_id: &str,
+    device_id: &str,
+  ) -> Result<String> {
+    self.update_tracked_users(vec![user_id.to_string()]);
+    self.query_keys(client)?;
+
+    let flow = VerificationFlow::ToDevice {
+      transaction_id: new_txn_id(),
+    };
+    let mut content = verification::request_content(&self.device_id, None, now_ms());
+    flow.add_to(&mut content);
+    let request = VerificationContent {
+      event_type: REQUEST,
+      content,
+    };
+    verification::send(
+      client,
+      &flow,
+      user_id,
+      Some(device_id),
+      &new_txn_id(),
+      &request,
+    )?;
+
+    let other = self.identity(user_id, Some(device_id));
+    let sas = SasVerification::new(flow, self.own_identity(), other, true);
+    let flow_id = sas.flow_id().to_string();
+    self.verifications.insert(flow_id.clone(), sas);
+    Ok(flow_id)
+  }
+
+  // Ask the user to verify in a room we share, whichever device they answer from
+  pub fn request_room_verification(
+    &mut self,
+    client: &MatrixClient,
+    room_id: &str,
+    user_id: &str,
+  ) -> Result<String> {
+    self.update_tracked_users(vec![user_id.to_string()]);
+    self.query_keys(client)?;
+
+    let content = verification::request_content(&self.device_id, Some(user_id), now_ms());
+    let response = send::send_event(client, room_id, "m.room.message", &new_txn_id(), &content)?;
+
+    let flow = VerificationFlow::Room {
+      room_id: room_id.to_string(),
+      event_id: response.event_id,
+    };
+    let other = self.identity(user_id, None);
+    let sas = SasVerification::new(flow, self.own_identity(), other, true);
+    let flow_id = sas.flow_id().to_string();
+    self.verifications.insert(flow_id.clone(), sas);
+    Ok(flow_id)
+  }
+
+  // Accept a request from the other side
+  pub fn accept_verification(&mut self, client: &MatrixClient, flow_id: &str) -> Result<()> {
+    let outgoing = self
+      .verification_mut(flow_id)?
+      .accept()
+      .into_iter()
+      .collect();
+    self.send_verification(client, flow_id, outgoing)
+  }
+
+  // The user says the SAS matches
+  pub fn confirm_verification(&mut self, client: &MatrixClient, flow_id: &str) -> Result<()> {
+    let outgoing = self.verification_mut(flow_id)?.confirm();
+    self.send_verification(client, flow_id, outgoing)
+  }
+
+  // The user gave up, or says the SAS doesn't match when code is MISMATCHED_SAS
+  pub fn cancel_verification(
+    &mut self,
+    client: &MatrixClient,
+    flow_id: &str,
+    code: &str,
+    reason: &str,
+  ) -> Result<()> {
+    let outgoing = self
+      .verification_mut(flow_id)?
+      .cancel(code, reason)
+      .into_iter()
+      .collect();
+    self.send_verification(client, flow_id, outgoing)
+  }
+
+  // Handle an m.key.verification.* event, or a request in a room.
+  // room is the room id, event id and origin_server_ts for events from a room timeline.
+  pub fn receive_verification_event(
+    &mut self,
+    client: &MatrixClient,
+    sender: &str,
+    event_type: &str,
+    content: &Value,
+    room: Option<(&str, &str, i64)>,
+  ) -> Result<()> {
+    let is_request = match room {
+      Some(_) => {
+        event_type == "m.room.message"
+          && content["msgtype"].as_str() == Some(REQUEST)
+          && content["to"].as_str() == Some(self.user_id.as_str())
+      }
+      None => event_type == REQUEST,
+    };
+    // In rooms we also see our own events come back
+    if room.is_some() && sender == self.user_id {
+      return Ok(());
+    }
+
+    if is_request {
+      // Old requests come back with the first sync after logging in, and the other side has
+      // given up on them by now
+      let timestamp = match room {
+        Some((_, _, origin_server_ts)) => origin_server_ts,
+        None => content["timestamp"].as_i64().unwrap_or_default(),
+      };
+      let age = now_ms() - timestamp;
+      if age > verification::TIMEOUT_MS || age < -verification::REQUEST_FUTURE_MS {
+        return Ok(());
+      }
+
+      let flow = match room {
+        Some((room_id, event_id, _)) => VerificationFlow::Room {
+          room_id: room_id.to_string(),
+          event_id: event_id.to_string(),
+        },
+        None => VerificationFlow::ToDevice {
+          transaction_id: content["transaction_id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        },
+      };
+      if self.verifications.contains_key(flow.flow_id()) {
+        return Ok(());
+      }
+      self.update_tracked_users(vec![sender.to_string()]);
+      self.query_keys(client)?;
+
+      let other = self.identity(sender, content["from_device"].as_str());
+      let sas = SasVerification::new(flow, self.own_identity(), other, false);
+      self.verifications.insert(sas.flow_id().to_string(), sas);
+      return Ok(());
+    }
+
+    let flow_id = match VerificationFlow::flow_id_of(content) {
+      Some(flow_id) => flow_id.to_string(),
+      None => return Ok(()),
+    };
+    let other_device_id = match self.verifications.get(&flow_id) {
+      Some(sas) if sas.other_user_id() == sender => sas.other_device_id().map(String::from),
+      _ => return Ok(()),
+    };
+
+    let outgoing = self
+      .verification_mut(&flow_id)?
+      .receive(event_type, content);
+    // The device answering a room request is only known now
+    if other_device_id.is_none() {
+      self.refresh_other_keys(&flow_id);
+    }
+    self.send_verification(client, &flow_id, outgoing)
+  }
+
+  // Cancel verifications the other side has stopped answering
+  pub fn cancel_expired_verifications(&mut self, client: &MatrixClient) -> Result<()> {
+    let expired: Vec<String> = self
+      .verifications
+      .values()
+      .filter(|sas| sas.is_expired())
+      .map(|sas| sas.flow_id().to_string())
+      .collect();
+
+    for flow_id in expired {
+      self.cancel_verification(
+        client,
+        &flow_id,
+        cancel_code::TIMEOUT,
+        "Verification timed out",
+      )?;
+    }
+    Ok(())
+  }
+
+  fn verification_mut(&mut self, flow_id: &str) -> Result<&mut SasVerification> {
+    self
+      .verifications
+      .get_mut(flow_id)
+      .ok_or_else(|| CryptoError::MissingSession(flow_id.to_string()))
+  }
+
+  // Keys we MAC for the other side
+  fn own_identity(&self) -> Identity {
+    let mut keys = HashMap::new();
+    keys.insert(format!("ed25519:{}", self.device_id), self.ed25519_key());
+    if let Some(master_key) = self.master_key(&self.user_id) {
+      keys.insert(format!("ed25519:{}", master_key), master_key);
+    }
+    Identity {
+      user_id: self.user_id.clone(),
+      device_id: Some(self.device_id.clone()),
+      keys,
+    }
+  }
+
+  // The other side with the keys we know for its device
+  fn identity(&self, user_id: &str, device_id: Option<&str>) -> Identity {
+    let mut keys = HashMap::new();
+    let device = device_id.and_then(|device_id| self.devices.get(user_id)?.get(device_id));
+    if let Some(ed25519_key) = device.and_then(DeviceKeys::ed25519_key) {
+      keys.insert(
+        format!("ed25519:{}", device_id.unwrap_or_default()),
+        ed25519_key.to_string(),
+      );
+    }
+    if let Some(master_key) = self.master_key(user_id) {
+      keys.insert(format!("ed25519:{}", master_key), master_key);
+    }
+    Identity {
+      user_id: user_id.to_string(),
+      device_id: device_id.map(String::from),
+      keys,
+    }
+  }
+
+  fn master_key(&self, user_id: &str) -> Option<String> {
+    let master_key = self.cross_signing_keys.get(user_id)?.master_key()?;
+    Some(master_key.to_string())
+  }
+
+  fn refresh_other_keys(&mut self, flow_id: &str) {
+    let (user_id, device_id) = match self.verifications.get(flow_id) {
+      Some(sas) => (
+        sas.other_user_id().to_string(),
+        sas.other_device_id().map(String::from),
+      ),
+      None => return,
+    };
+    let keys = self.identity(&user_id, device_id.as_deref()).keys;
+    if let Some(sas) = self.verifications.get_mut(flow_id) {
+      sas.set_other_keys(keys);
+    }
+  }
+
+  // Send what the verification produced and note the device and master key if they were
+  // verified. Newly verified keys are cross-signed when we have the keys to.
+  fn send_verification(
+    &mut self,
+    client: &MatrixClient,
+    flow_id: &str,
+    outgoing: Vec<VerificationContent>,
+  ) -> Result<()> {
+    let user_id = self.verification_mut(flow_id)?.other_user_id().to_string();
+    let master_key = self.master_key(&user_id);
+    let sas = self.verification_mut(flow_id)?;
+    let flow = sas.flow().clone();
+    let device_id = sas.other_device_id().map(String::from);
+    let is_verified = |key: &str| {
+      let key_id = format!("ed25519:{}", key);
+      sas.verified_keys().contains(&key_id)
+    };
+    let device_verified = device_id.as_deref().map(&is_verified).unwrap_or(false);
+    let master_verified = master_key.as_deref().map(&is_verified).unwrap_or(false);
+
+    for content in &outgoing {
+      verification::send(
+        client,
+        &flow,
+        &user_id,
+        device_id.as_deref(),
+        &new_txn_id(),
+        content,
+      )?;
+    }
+    let has_cross_signing_keys = self.cross_signing.is_some();
+    if let (Some(device_id), true) = (&device_id, device_verified) {
+      let newly_verified = self
+        .verified_devices
+        .entry(user_id.clone())
+        .or_default()
+        .insert(device_id.clone());
+      if newly_verified && user_id == self.user_id && has_cross_signing_keys {
+        self.cross_sign_device(client, device_id)?;
+      }
+    }
+    if let (Some(master_key), true) = (master_key, master_verified) {
+      let previous = self
+        .verified_master_keys
+        .insert(user_id.clone(), master_key.clone());
+      if previous != Some(master_key) && user_id != self.user_id && has_cross_signing_keys {
+        self.cross_sign_user(client, &user_id)?;
+      }
+    }
+    Ok(())
+  }
+
+  // Handle the encryption parts of a sync response: decrypt to-device and timeline events in
+  // place, take in room keys and verification events, note changed devices and top up our
+  // one-time keys, and back up new room keys. Events which can't be decrypted are left as
+  // they are, and nothing here fails the sync.
+  pub fn receive_sync(&mut self, client: &MatrixClient, response: &mut SyncResponse) -> Result<()> {
+    self.receive_device_lists(&response.device_lists);
+
+    for event in response.to_device.events.iter_mut() {
+      if event.r#type != "m.room.encrypted" {
+        continue;
+      }
+      if let Ok(decrypted) = self.decrypt_to_device(event) {
+        if decrypted.event.r#type == "m.room_key" {
+          self.receive_room_key(&decrypted).ok();
+        }
+        *event = decrypted.event;
+      }
+    }
+
+    // The senders' devices are needed to check the room keys belong to them
+    let senders: Vec<String> = response
+      .rooms
+      .join
+      .values()
+      .flat_map(|room| room.timeline.events.iter())
+      .filter(|event| event.r#type == "m.room.encrypted")
+      .map(|event| event.sender.clone())
+      .collect();
+    self.update_tracked_users(senders);
+    self.query_keys(client).ok();
+
+    for (room_id, room) in response.rooms.join.iter_mut() {
+      for event in room.timeline.events.iter_mut() {
+        if event.r#type != "m.room.encrypted" {
+          continue;
+        }
+        if let Ok(decrypted) = self.decrypt_room_event(room_id, event) {
+          *event = decrypted;
+        }
+      }
+    }
+
+    // A broken verification mustn't stop the rest of the sync from being handled
+    for event in &response.to_device.events {
+      if event.r#type.starts_with("m.key.verification.") {
+        let sender = event.sender.as_deref().unwrap_or_default();
+        self
+          .receive_verification_event(client, sender, &event.r#type, &event.content, None)
+          .ok();
+      }
+    }
+    for (room_id, room) in &response.rooms.join {
+      for event in &room.timeline.events {
+        if event.r#type.starts_with("m.key.verification.") || event.r#type == "m.room.message" {
+          let room = Some((
+            room_id.as_str(),
+            event.event_id.as_str(),
+            event.origin_server_ts,
+          ));
+          self
+            .receive_verification_event(client, &event.sender, &event.r#type, &event.content, room)
+            .ok();
+        }
+      }
+    }
+    self.cancel_expired_verifications(client).ok();
+    self.backup_room_keys(client).ok();
+
+    // One-time keys were used up decrypting the to-device events above, so failing here
+    // would lose the room keys they carried when the sync is retried. The counts come with
+    // every sync, the top up is tried again next time.
+    self
+      .upload_keys(client, &response.device_one_time_keys_count)
+      .ok();
+    Ok(())
+  }
+}
+
+fn new_txn_id() -> String {
+  let random: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(8)
+    .collect();
+  format!("{}.{}", now_ms(), random)
+}
+
+fn now_ms() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or(0)
+}
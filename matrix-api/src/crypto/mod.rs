@@ -0,0 +1,101 @@
+use serde_derive::Deserialize;
+use serde_json::json;
+use std::error;
+use std::fmt;
+
+use crate::api::ApiError;
+use crate::rooms::create::StateEvent;
+
+pub mod attachments;
+pub mod backup;
+pub mod cipher;
+pub mod cross_signing;
+pub mod key_export;
+pub mod keys;
+pub mod machine;
+pub mod secret_storage;
+pub mod signatures;
+pub mod store;
+pub mod verification;
+
+/*
+End-to-End Encryption
+Olm sessions between devices carry Megolm room keys, which encrypt the messages in rooms
+with an m.room.encryption state event. The crypto itself is done by vodozemac.
+
+docs: https://matrix.org/docs/spec/client_server/latest#end-to-end-encryption
+*/
+
+pub static OLM_ALGORITHM: &str = "m.olm.v1.curve25519-aes-sha2";
+pub static MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
+#[derive(Debug)]
+pub enum CryptoError {
+  Api(ApiError),
+  // An Olm message couldn't be decrypted or a session couldn't be created
+  Olm(String),
+  Megolm(String),
+  // No session to decrypt with, the keys may not have arrived yet
+  MissingSession(String),
+  Signature(String),
+  UnsupportedAlgorithm(String),
+  // The decrypted event doesn't match where it came from
+  Mismatch(String),
+  Serialization(String),
+  // A recovery key, passphrase or secret which doesn't match, or can't be read
+  Key(String),
+  // Something needed an OlmMachine but none was given
+  NotSetUp,
+}
+
+impl fmt::Display for CryptoError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CryptoError::Api(e) => write!(f, "{}", e),
+      CryptoError::Olm(message) => write!(f, "Olm error: {}", message),
+      CryptoError::Megolm(message) => write!(f, "Megolm error: {}", message),
+      CryptoError::MissingSession(session) => write!(f, "Missing session: {}", session),
+      CryptoError::Signature(message) => write!(f, "Invalid signature: {}", message),
+      CryptoError::UnsupportedAlgorithm(algorithm) => {
+        write!(f, "Unsupported algorithm: {}", algorithm)
+      }
+      CryptoError::Mismatch(message) => write!(f, "Mismatched event: {}", message),
+      CryptoError::Serialization(message) => write!(f, "Serialization error: {}", message),
+      CryptoError::Key(message) => write!(f, "Invalid key: {}", message),
+      CryptoError::NotSetUp => write!(f, "Encryption isn't set up"),
+    }
+  }
+}
+
+impl error::Error for CryptoError {}
+
+impl From<ApiError> for CryptoError {
+  fn from(error: ApiError) -> CryptoError {
+    CryptoError::Api(error)
+  }
+}
+
+impl From<serde_json::Error> for CryptoError {
+  fn from(error: serde_json::Error) -> CryptoError {
+    CryptoError::Serialization(error.to_string())
+  }
+}
+
+pub type Result<T> = ::std::result::Result<T, CryptoError>;
+
+// Content of a room's m.room.encryption state event
+#[derive(Deserialize, Debug, Clone)]
+pub struct EncryptionSettings {
+  pub algorithm: String,
+  pub rotation_period_ms: Option<i64>,
+  pub rotation_period_msgs: Option<u64>,
+}
+
+// Initial state which turns on encryption for a new room
+pub fn encryption_state_event() -> StateEvent {
+  StateEvent {
+    r#type: String::from("m.room.encryption"),
+    state_key: Some(String::new()),
+    content: json!({ "algorithm": MEGOLM_ALGORITHM }),
+  }
+}
@@ -0,0 +1,357 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use vodozemac::base64_encode;
+
+use crate::account_data;
+use crate::client::MatrixClient;
+use crate::crypto::cipher;
+use crate::crypto::cipher::Keys;
+use crate::crypto::{CryptoError, Result};
+
+/*
+Secret Storage
+Secrets such as the key backup's private key are kept encrypted in account data, so any of
+the user's devices can get them with the secret storage key. The key is random, shown to the
+user as a recovery key, or derived from a passphrase.
+
+docs: https://matrix.org/docs/spec/client_server/latest#storage
+*/
+
+pub static SECRET_STORAGE_ALGORITHM: &str = "m.secret_storage.v1.aes-hmac-sha2";
+pub static DEFAULT_KEY_EVENT: &str = "m.secret_storage.default_key";
+pub static PBKDF2: &str = "m.pbkdf2";
+pub static PASSPHRASE_ITERATIONS: u32 = 500_000;
+
+static PASSPHRASE_SALT_LENGTH: usize = 32;
+static KEY_ID_LENGTH: usize = 16;
+static RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+pub fn key_event_type(key_id: &str) -> String {
+  format!("m.secret_storage.key.{}", key_id)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PassphraseInfo {
+  pub algorithm: String,
+  pub salt: String,
+  pub iterations: u32,
+  // Length of the derived key, 256 when left out
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub bits: Option<u32>,
+}
+
+// Content of the m.secret_storage.key.<key id> account data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyDescription {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  pub algorithm: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub passphrase: Option<PassphraseInfo>,
+  // Encryption of 32 zero bytes, to check a key is the right one
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub iv: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mac: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedSecret {
+  pub iv: String,
+  pub ciphertext: String,
+  pub mac: String,
+}
+
+pub struct SecretStorageKey {
+  pub key_id: String,
+  pub description: KeyDescription,
+  key: [u8; 32],
+}
+
+impl SecretStorageKey {
+  // A new random key, or one derived from the passphrase so it can be used instead of the
+  // recovery key
+  pub fn new(passphrase: Option<&str>) -> SecretStorageKey {
+    let key_id = base64_encode(cipher::random_bytes(KEY_ID_LENGTH));
+    let (key, passphrase_info) = match passphrase {
+      Some(passphrase) => {
+        let salt = base64_encode(cipher::random_bytes(PASSPHRASE_SALT_LENGTH));
+        let info = PassphraseInfo {
+          algorithm: PBKDF2.to_string(),
+          salt,
+          iterations: PASSPHRASE_ITERATIONS,
+          bits: None,
+        };
+        (derive_from_passphrase(passphrase, &info), Some(info))
+      }
+      None => {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&cipher::random_bytes(32));
+        (key, None)
+      }
+    };
+
+    let iv = cipher::random_iv();
+    let (_, mac) = encrypt_with(&key, "", &[0u8; 32], &iv);
+    SecretStorageKey {
+      key_id,
+      description: KeyDescription {
+        name: None,
+        algorithm: SECRET_STORAGE_ALGORITHM.to_string(),
+        passphrase: passphrase_info,
+        iv: Some(base64_encode(iv)),
+        mac: Some(base64_encode(mac)),
+      },
+      key,
+    }
+  }
+
+  // The key described by the account data, checked against its MAC
+  pub fn from_recovery_key(
+    key_id: &str,
+    description: KeyDescription,
+    recovery_key: &str,
+  ) -> Result<SecretStorageKey> {
+    let key = decode_recovery_key(recovery_key)?;
+    SecretStorageKey::checked(key_id, description, key)
+  }
+
+  pub fn from_passphrase(
+    key_id: &str,
+    description: KeyDescription,
+    passphrase: &str,
+  ) -> Result<SecretStorageKey> {
+    let key = match &description.passphrase {
+      Some(info) if info.algorithm == PBKDF2 => derive_from_passphrase(passphrase, info),
+      _ => return Err(CryptoError::Key(String::from("The key has no passphrase"))),
+    };
+    SecretStorageKey::checked(key_id, description, key)
+  }
+
+  pub fn recovery_key(&self) -> String {
+    encode_recovery_key(&self.key)
+  }
+
+  // Encrypt a secret to store under the account data type name
+  pub fn encrypt(&self, name: &str, secret: &str) -> EncryptedSecret {
+    let iv = cipher::random_iv();
+    let (ciphertext, mac) = encrypt_with(&self.key, name, secret.as_bytes(), &iv);
+    EncryptedSecret {
+      iv: base64_encode(iv),
+      ciphertext: base64_encode(ciphertext),
+      mac: base64_encode(mac),
+    }
+  }
+
+  pub fn decrypt(&self, name: &str, encrypted: &EncryptedSecret) -> Result<String> {
+    let iv = decode_iv(&encrypted.iv)?;
+    let mut data = decode(&encrypted.ciphertext)?;
+    let mac = decode(&encrypted.mac)?;
+
+    let keys = derive_keys(&self.key, name);
+    if !cipher::verify_hmac_sha256(&keys.mac_key, &data, &mac) {
+      return Err(CryptoError::Key(format!(
+        "The MAC of {} doesn't match",
+        name
+      )));
+    }
+    cipher::aes_ctr(&keys.aes_key, &iv, &mut data);
+    String::from_utf8(data).map_err(|e| CryptoError::Key(e.to_string()))
+  }
+
+  fn checked(key_id: &str, description: KeyDescription, key: [u8; 32]) -> Result<SecretStorageKey> {
+    if description.algorithm != SECRET_STORAGE_ALGORITHM {
+      return Err(CryptoError::UnsupportedAlgorithm(description.algorithm));
+    }
+    // Keys made by older clients may not have a check
+    if let (Some(iv), Some(mac)) = (&description.iv, &description.mac) {
+      let (_, expected) = encrypt_with(&key, "", &[0u8; 32], &decode_iv(iv)?);
+      if expected != decode(mac)? {
+        return Err(CryptoError::Key(String::from(
+          "Wrong recovery key or passphrase",
+        )));
+      }
+    }
+
+    Ok(SecretStorageKey {
+      key_id: key_id.to_string(),
+      description,
+      key,
+    })
+  }
+}
+
+// The recovery key shown to users: base58 of a 2 byte prefix, the key and a parity byte,
+// in groups of 4 characters
+pub fn encode_recovery_key(key: &[u8; 32]) -> String {
+  let mut bytes = RECOVERY_KEY_PREFIX.to_vec();
+  bytes.extend_from_slice(key);
+  let parity = bytes.iter().fold(0u8, |parity, byte| parity ^ byte);
+  bytes.push(parity);
+
+  let encoded: Vec<char> = bs58::encode(bytes).into_string().chars().collect();
+  encoded
+    .chunks(4)
+    .map(|chunk| chunk.iter().collect::<String>())
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+pub fn decode_recovery_key(recovery_key: &str) -> Result<[u8; 32]> {
+  let recovery_key: String = recovery_key.split_whitespace().collect();
+  let bytes = bs58::decode(recovery_key)
+    .into_vec()
+    .map_err(|e| CryptoError::Key(e.to_string()))?;
+
+  if bytes.len() != RECOVERY_KEY_PREFIX.len() + 32 + 1 || bytes[..2] != RECOVERY_KEY_PREFIX {
+    return Err(CryptoError::Key(String::from("Not a recovery key")));
+  }
+  if bytes.iter().fold(0u8, |parity, byte| parity ^ byte) != 0 {
+    return Err(CryptoError::Key(String::from(
+      "The recovery key has a typo in it",
+    )));
+  }
+  let mut key = [0u8; 32];
+  key.copy_from_slice(&bytes[2..34]);
+  Ok(key)
+}
+
+// The default key's id and description, if secret storage has been set up
+pub fn default_key(
+  client: &MatrixClient,
+  user_id: &str,
+) -> Result<Option<(String, KeyDescription)>> {
+  let key_id = match account_data::get_account_data(client, user_id, DEFAULT_KEY_EVENT)? {
+    Some(content) => match content["key"].as_str() {
+      Some(key_id) => key_id.to_string(),
+      None => return Ok(None),
+    },
+    None => return Ok(None),
+  };
+  match account_data::get_account_data(client, user_id, &key_event_type(&key_id))? {
+    Some(description) => Ok(Some((key_id, serde_json::from_value(description)?))),
+    None => Ok(None),
+  }
+}
+
+// Publish the key's description and make it the default
+pub fn set_default_key(client: &MatrixClient, user_id: &str, key: &SecretStorageKey) -> Result<()> {
+  account_data::set_account_data(
+    client,
+    user_id,
+    &key_event_type(&key.key_id),
+    &serde_json::to_value(&key.description)?,
+  )?;
+  account_data::set_account_data(
+    client,
+    user_id,
+    DEFAULT_KEY_EVENT,
+    &json!({ "key": key.key_id }),
+  )?;
+  Ok(())
+}
+
+// Store the secret encrypted with the key, keeping copies encrypted with other keys
+pub fn store_secret(
+  client: &MatrixClient,
+  user_id: &str,
+  key: &SecretStorageKey,
+  name: &str,
+  secret: &str,
+) -> Result<()> {
+  let mut content = account_data::get_account_data(client, user_id, name)?
+    .filter(|content| content["encrypted"].is_object())
+    .unwrap_or_else(|| json!({ "encrypted": {} }));
+  content["encrypted"][&key.key_id] = serde_json::to_value(key.encrypt(name, secret))?;
+  account_data::set_account_data(client, user_id, name, &content)?;
+  Ok(())
+}
+
+// The secret stored under name, None if it isn't encrypted with this key
+pub fn get_secret(
+  client: &MatrixClient,
+  user_id: &str,
+  key: &SecretStorageKey,
+  name: &str,
+) -> Result<Option<String>> {
+  let content = match account_data::get_account_data(client, user_id, name)? {
+    Some(content) => content,
+    None => return Ok(None),
+  };
+  let encrypted: EncryptedSecret = match content["encrypted"].get(&key.key_id) {
+    Some(encrypted) => serde_json::from_value(encrypted.clone())?,
+    None => return Ok(None),
+  };
+  key.decrypt(name, &encrypted).map(Some)
+}
+
+fn derive_from_passphrase(passphrase: &str, info: &PassphraseInfo) -> [u8; 32] {
+  let mut key = [0u8; 32];
+  key.copy_from_slice(&cipher::pbkdf2_sha512(
+    passphrase,
+    info.salt.as_bytes(),
+    info.iterations,
+    32,
+  ));
+  key
+}
+
+// HKDF-SHA256 with a zero salt and the secret's name as the info
+fn derive_keys(key: &[u8; 32], name: &str) -> Keys {
+  Keys::from_slice(&cipher::hkdf_sha256(key, &[0u8; 32], name.as_bytes(), 64))
+}
+
+fn encrypt_with(key: &[u8; 32], name: &str, plaintext: &[u8], iv: &[u8; 16]) -> (Vec<u8>, Vec<u8>) {
+  let keys = derive_keys(key, name);
+  let mut ciphertext = plaintext.to_vec();
+  cipher::aes_ctr(&keys.aes_key, iv, &mut ciphertext);
+  let mac = cipher::hmac_sha256(&keys.mac_key, &ciphertext);
+  (ciphertext, mac)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+  cipher::decode_base64(value).ok_or_else(|| CryptoError::Key(format!("Invalid base64 {}", value)))
+}
+
+fn decode_iv(value: &str) -> Result<[u8; 16]> {
+  let bytes = decode(value)?;
+  if bytes.len() != 16 {
+    return Err(CryptoError::Key(String::from("The IV isn't 16 bytes")));
+  }
+  let mut iv = [0u8; 16];
+  iv.copy_from_slice(&bytes);
+  Ok(iv)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The recovery key for the RFC 7748 curve25519 test key, as other clients' backup tests use
+  static RECOVERY_KEY: &str = "EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d";
+  static KEY: [u8; 32] = [
+    0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66, 0x45,
+    0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9, 0x2c, 0x2a,
+  ];
+
+  #[test]
+  fn recovery_key_matches_other_clients() {
+    assert_eq!(encode_recovery_key(&KEY), RECOVERY_KEY);
+    assert_eq!(decode_recovery_key(RECOVERY_KEY).unwrap(), KEY);
+  }
+
+  #[test]
+  fn recovery_key_spacing_is_ignored() {
+    let unspaced: String = RECOVERY_KEY.split_whitespace().collect();
+    assert_eq!(decode_recovery_key(&unspaced).unwrap(), KEY);
+    let wrapped = RECOVERY_KEY.replace(' ', "\n");
+    assert_eq!(decode_recovery_key(&wrapped).unwrap(), KEY);
+  }
+
+  #[test]
+  fn recovery_key_typos_are_rejected() {
+    let typo = RECOVERY_KEY.replacen("EsTc", "EsTd", 1);
+    assert!(decode_recovery_key(&typo).is_err());
+    assert!(decode_recovery_key("not a recovery key").is_err());
+  }
+}
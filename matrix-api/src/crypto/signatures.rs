@@ -0,0 +1,66 @@
+use serde_json::{Map, Value};
+use vodozemac::{Ed25519PublicKey, Ed25519Signature};
+
+use crate::crypto::{CryptoError, Result};
+
+/*
+Signed JSON
+Keys and other objects are signed over their canonical JSON, with the "signatures" and
+"unsigned" fields left out
+
+docs: https://matrix.org/docs/spec/appendices#signing-json
+*/
+
+// Keys sorted, no whitespace
+pub fn canonical_json(value: &Value) -> String {
+  let mut value = sorted(value);
+  if let Value::Object(map) = &mut value {
+    map.remove("signatures");
+    map.remove("unsigned");
+  }
+  value.to_string()
+}
+
+// Rebuild objects with their keys inserted in order, whichever map serde_json was built with
+fn sorted(value: &Value) -> Value {
+  match value {
+    Value::Object(map) => {
+      let mut keys: Vec<&String> = map.keys().collect();
+      keys.sort();
+      let mut sorted_map = Map::new();
+      for key in keys {
+        sorted_map.insert(key.clone(), sorted(&map[key]));
+      }
+      Value::Object(sorted_map)
+    }
+    Value::Array(values) => Value::Array(values.iter().map(sorted).collect()),
+    value => value.clone(),
+  }
+}
+
+// Check the signature made by user_id with key_id ("ed25519:<device id>") using public_key
+pub fn verify_json(value: &Value, user_id: &str, key_id: &str, public_key: &str) -> Result<()> {
+  let signature = value["signatures"][user_id][key_id]
+    .as_str()
+    .ok_or_else(|| CryptoError::Signature(format!("No {} signature by {}", key_id, user_id)))?;
+
+  let public_key =
+    Ed25519PublicKey::from_base64(public_key).map_err(|e| CryptoError::Signature(e.to_string()))?;
+  let signature =
+    Ed25519Signature::from_base64(signature).map_err(|e| CryptoError::Signature(e.to_string()))?;
+
+  public_key
+    .verify(canonical_json(value).as_bytes(), &signature)
+    .map_err(|e| CryptoError::Signature(e.to_string()))
+}
+
+// Add a signature to the value's "signatures" field
+pub fn add_signature(value: &mut Value, user_id: &str, key_id: &str, signature: String) {
+  if !value["signatures"].is_object() {
+    value["signatures"] = Value::Object(Map::new());
+  }
+  if !value["signatures"][user_id].is_object() {
+    value["signatures"][user_id] = Value::Object(Map::new());
+  }
+  value["signatures"][user_id][key_id] = Value::String(signature);
+}
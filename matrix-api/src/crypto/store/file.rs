@@ -0,0 +1,243 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use vodozemac::olm::SessionPickle;
+use vodozemac::{base64_decode, base64_encode};
+
+use crate::crypto::cipher;
+use crate::crypto::cipher::Keys;
+use crate::crypto::cross_signing::{StoredCrossSigningKeys, UserCrossSigningKeys};
+use crate::crypto::keys::DeviceKeys;
+use crate::crypto::store::{
+  seal, unseal, CryptoChanges, CryptoStore, StoredAccount, StoredInboundGroupSession,
+  StoredOutboundGroupSession, TrackedUser, PASSPHRASE_ROUNDS, SALT_LENGTH,
+};
+use crate::store::{Result, StoreError};
+
+/*
+File Crypto Store
+Keeps everything in a single file, encrypted as a whole with the passphrase. The file is
+rewritten on every save, so this suits a client with a handful of rooms.
+*/
+
+static FILE_VERSION: u32 = 1;
+
+// What's written to disk
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+  version: u32,
+  salt: String,
+  rounds: u32,
+  ciphertext: String,
+}
+
+// Pickles are kept as JSON so they can be handed out more than once
+#[derive(Serialize, Deserialize, Default)]
+struct Contents {
+  account: Option<Value>,
+  sessions: HashMap<String, Vec<Value>>,
+  // Keyed by room id then session id
+  inbound_group_sessions: HashMap<String, HashMap<String, Value>>,
+  // Keyed by room id
+  outbound_group_sessions: HashMap<String, Value>,
+  devices: HashMap<String, HashMap<String, DeviceKeys>>,
+  tracked_users: Vec<TrackedUser>,
+  #[serde(default)]
+  verified_devices: HashMap<String, HashSet<String>>,
+  #[serde(default)]
+  cross_signing: Option<Value>,
+  #[serde(default)]
+  cross_signing_keys: HashMap<String, UserCrossSigningKeys>,
+  #[serde(default)]
+  verified_master_keys: HashMap<String, String>,
+}
+
+pub struct FileCryptoStore {
+  path: PathBuf,
+  salt: Vec<u8>,
+  rounds: u32,
+  keys: Keys,
+  contents: Contents,
+}
+
+// A file name for the login's keys, so logging in as someone else doesn't replace the keys
+// of another device. Characters which don't belong in a file name are replaced with _.
+pub fn file_name(user_id: &str, device_id: &str) -> String {
+  format!("crypto_store_{}_{}.json", user_id, device_id)
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+      _ => '_',
+    })
+    .collect()
+}
+
+impl FileCryptoStore {
+  // Open the store at path, it's created on the first save.
+  // Fails with StoreError::Decryption if the passphrase is wrong.
+  pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<FileCryptoStore> {
+    let path = path.as_ref().to_path_buf();
+
+    if !path.exists() {
+      let salt = cipher::random_bytes(SALT_LENGTH);
+      let keys = cipher::derive_keys(passphrase, &salt, PASSPHRASE_ROUNDS);
+      return Ok(FileCryptoStore {
+        path,
+        salt,
+        rounds: PASSPHRASE_ROUNDS,
+        keys,
+        contents: Contents::default(),
+      });
+    }
+
+    let file: EncryptedFile = serde_json::from_slice(&fs::read(&path)?)?;
+    if file.version != FILE_VERSION {
+      return Err(StoreError::Serialization(format!(
+        "Unknown crypto store version {}",
+        file.version
+      )));
+    }
+    let salt = decode(&file.salt)?;
+    let keys = cipher::derive_keys(passphrase, &salt, file.rounds);
+    let contents = unseal(&keys, &decode(&file.ciphertext)?)?;
+
+    Ok(FileCryptoStore {
+      path,
+      salt,
+      rounds: file.rounds,
+      keys,
+      contents,
+    })
+  }
+
+  // Write to a temporary file first so a crash can't leave half a store behind
+  fn write(&self) -> Result<()> {
+    let file = EncryptedFile {
+      version: FILE_VERSION,
+      salt: base64_encode(&self.salt),
+      rounds: self.rounds,
+      ciphertext: base64_encode(seal(&self.keys, &self.contents)?),
+    };
+
+    let temporary_path = self.path.with_extension("tmp");
+    fs::write(&temporary_path, serde_json::to_vec(&file)?)?;
+    fs::rename(&temporary_path, &self.path)?;
+    Ok(())
+  }
+}
+
+impl CryptoStore for FileCryptoStore {
+  fn save_changes(&mut self, changes: CryptoChanges) -> Result<()> {
+    let contents = &mut self.contents;
+
+    if let Some(account) = changes.account {
+      contents.account = Some(serde_json::to_value(account)?);
+    }
+    for (sender_key, sessions) in changes.sessions {
+      let sessions = sessions
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<serde_json::Result<_>>()?;
+      contents.sessions.insert(sender_key, sessions);
+    }
+    for session in changes.inbound_group_sessions {
+      contents
+        .inbound_group_sessions
+        .entry(session.room_id.clone())
+        .or_default()
+        .insert(session.session_id.clone(), serde_json::to_value(session)?);
+    }
+    for session in changes.outbound_group_sessions {
+      contents
+        .outbound_group_sessions
+        .insert(session.room_id.clone(), serde_json::to_value(session)?);
+    }
+    contents.devices.extend(changes.devices);
+    if let Some(tracked_users) = changes.tracked_users {
+      contents.tracked_users = tracked_users;
+    }
+    contents.verified_devices.extend(changes.verified_devices);
+    if let Some(cross_signing) = changes.cross_signing {
+      contents.cross_signing = Some(serde_json::to_value(cross_signing)?);
+    }
+    contents
+      .cross_signing_keys
+      .extend(changes.cross_signing_keys);
+    contents
+      .verified_master_keys
+      .extend(changes.verified_master_keys);
+
+    self.write()
+  }
+
+  fn account(&self) -> Result<Option<StoredAccount>> {
+    match &self.contents.account {
+      Some(account) => Ok(Some(serde_json::from_value(account.clone())?)),
+      None => Ok(None),
+    }
+  }
+
+  fn sessions(&self) -> Result<HashMap<String, Vec<SessionPickle>>> {
+    let mut sessions = HashMap::new();
+    for (sender_key, pickles) in &self.contents.sessions {
+      let pickles = pickles
+        .iter()
+        .map(|pickle| serde_json::from_value(pickle.clone()))
+        .collect::<serde_json::Result<_>>()?;
+      sessions.insert(sender_key.clone(), pickles);
+    }
+    Ok(sessions)
+  }
+
+  fn inbound_group_sessions(&self) -> Result<Vec<StoredInboundGroupSession>> {
+    let mut sessions = Vec::new();
+    for room_sessions in self.contents.inbound_group_sessions.values() {
+      for session in room_sessions.values() {
+        sessions.push(serde_json::from_value(session.clone())?);
+      }
+    }
+    Ok(sessions)
+  }
+
+  fn outbound_group_sessions(&self) -> Result<Vec<StoredOutboundGroupSession>> {
+    let mut sessions = Vec::new();
+    for session in self.contents.outbound_group_sessions.values() {
+      let session: StoredOutboundGroupSession = serde_json::from_value(session.clone())?;
+      sessions.push(session);
+    }
+    Ok(sessions)
+  }
+
+  fn devices(&self) -> Result<HashMap<String, HashMap<String, DeviceKeys>>> {
+    Ok(self.contents.devices.clone())
+  }
+
+  fn tracked_users(&self) -> Result<Vec<TrackedUser>> {
+    Ok(self.contents.tracked_users.clone())
+  }
+
+  fn verified_devices(&self) -> Result<HashMap<String, HashSet<String>>> {
+    Ok(self.contents.verified_devices.clone())
+  }
+
+  fn cross_signing(&self) -> Result<Option<StoredCrossSigningKeys>> {
+    match &self.contents.cross_signing {
+      Some(cross_signing) => Ok(Some(serde_json::from_value(cross_signing.clone())?)),
+      None => Ok(None),
+    }
+  }
+
+  fn cross_signing_keys(&self) -> Result<HashMap<String, UserCrossSigningKeys>> {
+    Ok(self.contents.cross_signing_keys.clone())
+  }
+
+  fn verified_master_keys(&self) -> Result<HashMap<String, String>> {
+    Ok(self.contents.verified_master_keys.clone())
+  }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+  base64_decode(value).map_err(|e| StoreError::Serialization(e.to_string()))
+}
@@ -0,0 +1,118 @@
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use vodozemac::megolm::{GroupSessionPickle, InboundGroupSessionPickle};
+use vodozemac::olm::{AccountPickle, SessionPickle};
+
+use crate::crypto::cipher;
+use crate::crypto::cipher::Keys;
+use crate::crypto::cross_signing::{StoredCrossSigningKeys, UserCrossSigningKeys};
+use crate::crypto::keys::DeviceKeys;
+use crate::store::{Result, StoreError};
+
+pub mod file;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/*
+Crypto Store
+Keeps the Olm account and sessions between restarts. Losing them means losing the keys to
+every message received so far, so stores encrypt them with a key derived from a passphrase.
+
+Save an OlmMachine with `OlmMachine::save` after it has handled a sync or sent a room key,
+and load it again with `OlmMachine::load`.
+*/
+
+pub static PASSPHRASE_ROUNDS: u32 = 100_000;
+pub static SALT_LENGTH: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredAccount {
+  pub user_id: String,
+  pub device_id: String,
+  pub pickle: AccountPickle,
+  pub device_keys_uploaded: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredInboundGroupSession {
+  pub room_id: String,
+  pub session_id: String,
+  pub sender_key: String,
+  pub signing_key: Option<String>,
+  pub pickle: InboundGroupSessionPickle,
+  // The key backup version the session was uploaded to
+  #[serde(default)]
+  pub backed_up_to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredOutboundGroupSession {
+  pub room_id: String,
+  pub pickle: GroupSessionPickle,
+  pub created_ts: i64,
+  pub message_count: u64,
+  pub shared_with: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrackedUser {
+  pub user_id: String,
+  // The user's devices need to be queried again
+  pub outdated: bool,
+}
+
+// Everything to write in one go
+#[derive(Default)]
+pub struct CryptoChanges {
+  pub account: Option<StoredAccount>,
+  // Replaces the Olm sessions stored for each curve25519 key, most recently used first
+  pub sessions: HashMap<String, Vec<SessionPickle>>,
+  // Added, or replacing the one with the same room and session id
+  pub inbound_group_sessions: Vec<StoredInboundGroupSession>,
+  // Replacing the room's previous session
+  pub outbound_group_sessions: Vec<StoredOutboundGroupSession>,
+  // Replaces the devices stored for each user
+  pub devices: HashMap<String, HashMap<String, DeviceKeys>>,
+  // Replaces every tracked user, devices of users no longer tracked are ignored
+  pub tracked_users: Option<Vec<TrackedUser>>,
+  // Replaces the devices marked as verified for each user
+  pub verified_devices: HashMap<String, HashSet<String>>,
+  // Replaces our private cross-signing keys
+  pub cross_signing: Option<StoredCrossSigningKeys>,
+  // Replaces the public cross-signing keys stored for each user
+  pub cross_signing_keys: HashMap<String, UserCrossSigningKeys>,
+  // Replaces the master key the user has verified for each user
+  pub verified_master_keys: HashMap<String, String>,
+}
+
+pub trait CryptoStore {
+  fn save_changes(&mut self, changes: CryptoChanges) -> Result<()>;
+
+  fn account(&self) -> Result<Option<StoredAccount>>;
+  // Keyed by the other device's curve25519 key, most recently used first
+  fn sessions(&self) -> Result<HashMap<String, Vec<SessionPickle>>>;
+  fn inbound_group_sessions(&self) -> Result<Vec<StoredInboundGroupSession>>;
+  fn outbound_group_sessions(&self) -> Result<Vec<StoredOutboundGroupSession>>;
+  // Keyed by user id then device id
+  fn devices(&self) -> Result<HashMap<String, HashMap<String, DeviceKeys>>>;
+  fn tracked_users(&self) -> Result<Vec<TrackedUser>>;
+  // Device ids the user has verified, keyed by user id
+  fn verified_devices(&self) -> Result<HashMap<String, HashSet<String>>>;
+  fn cross_signing(&self) -> Result<Option<StoredCrossSigningKeys>>;
+  // Keyed by user id
+  fn cross_signing_keys(&self) -> Result<HashMap<String, UserCrossSigningKeys>>;
+  // Master keys the user has verified, keyed by user id
+  fn verified_master_keys(&self) -> Result<HashMap<String, String>>;
+}
+
+// Serialize then encrypt a value for storage
+fn seal<T: serde::Serialize>(keys: &Keys, value: &T) -> Result<Vec<u8>> {
+  let plaintext = serde_json::to_vec(value)?;
+  Ok(cipher::encrypt(keys, &plaintext))
+}
+
+fn unseal<T: DeserializeOwned>(keys: &Keys, data: &[u8]) -> Result<T> {
+  let plaintext = cipher::decrypt(keys, data).ok_or(StoreError::Decryption)?;
+  Ok(serde_json::from_slice(&plaintext)?)
+}
@@ -0,0 +1,371 @@
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use vodozemac::olm::SessionPickle;
+
+use crate::crypto::cipher;
+use crate::crypto::cipher::Keys;
+use crate::crypto::cross_signing::{StoredCrossSigningKeys, UserCrossSigningKeys};
+use crate::crypto::keys::DeviceKeys;
+use crate::crypto::store::{
+  seal, unseal, CryptoChanges, CryptoStore, StoredAccount, StoredInboundGroupSession,
+  StoredOutboundGroupSession, TrackedUser, PASSPHRASE_ROUNDS, SALT_LENGTH,
+};
+use crate::store::{Result, StoreError};
+
+/*
+SQLite Crypto Store
+Keeps the crypto state in a SQLite database, which can be the same file as the SqliteStore's.
+Ids are stored as they are so rows can be looked up, everything else is encrypted with the
+passphrase.
+
+Only built with the "sqlite" feature.
+*/
+
+static SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS crypto_meta (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    salt BLOB NOT NULL,
+    rounds INTEGER NOT NULL,
+    passphrase_check BLOB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS crypto_account (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    data BLOB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS olm_sessions (
+    sender_key TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (sender_key, position)
+  );
+  CREATE TABLE IF NOT EXISTS inbound_group_sessions (
+    room_id TEXT NOT NULL,
+    session_id TEXT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (room_id, session_id)
+  );
+  CREATE TABLE IF NOT EXISTS outbound_group_sessions (
+    room_id TEXT PRIMARY KEY,
+    data BLOB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS devices (
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (user_id, device_id)
+  );
+  CREATE TABLE IF NOT EXISTS tracked_users (
+    user_id TEXT PRIMARY KEY,
+    outdated INTEGER NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS verified_devices (
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    PRIMARY KEY (user_id, device_id)
+  );
+  CREATE TABLE IF NOT EXISTS cross_signing (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    data BLOB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS cross_signing_keys (
+    user_id TEXT PRIMARY KEY,
+    data BLOB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS verified_master_keys (
+    user_id TEXT PRIMARY KEY,
+    master_key TEXT NOT NULL
+  );
+";
+
+// Encrypted when the store is created, a wrong passphrase fails to decrypt it
+static PASSPHRASE_CHECK: &str = "matrix-api crypto store";
+
+pub struct SqliteCryptoStore {
+  connection: Connection,
+  keys: Keys,
+}
+
+impl SqliteCryptoStore {
+  // Open or create the database at path.
+  // Fails with StoreError::Decryption if the passphrase is wrong.
+  pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<SqliteCryptoStore> {
+    SqliteCryptoStore::from_connection(Connection::open(path)?, passphrase)
+  }
+
+  // Database which is thrown away when the store is dropped
+  pub fn open_in_memory(passphrase: &str) -> Result<SqliteCryptoStore> {
+    SqliteCryptoStore::from_connection(Connection::open_in_memory()?, passphrase)
+  }
+
+  fn from_connection(connection: Connection, passphrase: &str) -> Result<SqliteCryptoStore> {
+    connection.execute_batch(SCHEMA)?;
+
+    let meta: Option<(Vec<u8>, u32, Vec<u8>)> = connection
+      .query_row(
+        "SELECT salt, rounds, passphrase_check FROM crypto_meta WHERE id = 0",
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+      )
+      .optional()?;
+
+    let keys = match meta {
+      Some((salt, rounds, passphrase_check)) => {
+        let keys = cipher::derive_keys(passphrase, &salt, rounds);
+        let check: String = unseal(&keys, &passphrase_check)?;
+        if check != PASSPHRASE_CHECK {
+          return Err(StoreError::Decryption);
+        }
+        keys
+      }
+      None => {
+        let salt = cipher::random_bytes(SALT_LENGTH);
+        let keys = cipher::derive_keys(passphrase, &salt, PASSPHRASE_ROUNDS);
+        connection.execute(
+          "INSERT INTO crypto_meta (id, salt, rounds, passphrase_check) VALUES (0, ?1, ?2, ?3)",
+          params![salt, PASSPHRASE_ROUNDS, seal(&keys, &PASSPHRASE_CHECK)?],
+        )?;
+        keys
+      }
+    };
+
+    Ok(SqliteCryptoStore { connection, keys })
+  }
+
+  fn load_all<T: serde::de::DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>> {
+    let mut statement = self.connection.prepare(sql)?;
+    let rows = statement.query_map(NO_PARAMS, |row| row.get::<_, Vec<u8>>(0))?;
+
+    let mut values = Vec::new();
+    for data in rows {
+      values.push(unseal(&self.keys, &data?)?);
+    }
+    Ok(values)
+  }
+}
+
+impl CryptoStore for SqliteCryptoStore {
+  fn save_changes(&mut self, changes: CryptoChanges) -> Result<()> {
+    let transaction = self.connection.transaction()?;
+    let keys = &self.keys;
+
+    if let Some(account) = changes.account {
+      transaction.execute(
+        "INSERT OR REPLACE INTO crypto_account (id, data) VALUES (0, ?1)",
+        params![seal(keys, &account)?],
+      )?;
+    }
+    for (sender_key, sessions) in changes.sessions {
+      transaction.execute(
+        "DELETE FROM olm_sessions WHERE sender_key = ?1",
+        params![sender_key],
+      )?;
+      for (position, session) in sessions.iter().enumerate() {
+        transaction.execute(
+          "INSERT INTO olm_sessions (sender_key, position, data) VALUES (?1, ?2, ?3)",
+          params![sender_key, position as i64, seal(keys, session)?],
+        )?;
+      }
+    }
+    for session in changes.inbound_group_sessions {
+      transaction.execute(
+        "INSERT OR REPLACE INTO inbound_group_sessions (room_id, session_id, data)
+         VALUES (?1, ?2, ?3)",
+        params![session.room_id, session.session_id, seal(keys, &session)?],
+      )?;
+    }
+    for session in changes.outbound_group_sessions {
+      transaction.execute(
+        "INSERT OR REPLACE INTO outbound_group_sessions (room_id, data) VALUES (?1, ?2)",
+        params![session.room_id, seal(keys, &session)?],
+      )?;
+    }
+    for (user_id, devices) in changes.devices {
+      transaction.execute("DELETE FROM devices WHERE user_id = ?1", params![user_id])?;
+      for (device_id, device) in devices {
+        transaction.execute(
+          "INSERT INTO devices (user_id, device_id, data) VALUES (?1, ?2, ?3)",
+          params![user_id, device_id, seal(keys, &device)?],
+        )?;
+      }
+    }
+    if let Some(tracked_users) = changes.tracked_users {
+      transaction.execute("DELETE FROM tracked_users", NO_PARAMS)?;
+      for user in tracked_users {
+        transaction.execute(
+          "INSERT INTO tracked_users (user_id, outdated) VALUES (?1, ?2)",
+          params![user.user_id, user.outdated],
+        )?;
+      }
+    }
+    for (user_id, device_ids) in changes.verified_devices {
+      transaction.execute(
+        "DELETE FROM verified_devices WHERE user_id = ?1",
+        params![user_id],
+      )?;
+      for device_id in device_ids {
+        transaction.execute(
+          "INSERT INTO verified_devices (user_id, device_id) VALUES (?1, ?2)",
+          params![user_id, device_id],
+        )?;
+      }
+    }
+    if let Some(cross_signing) = changes.cross_signing {
+      transaction.execute(
+        "INSERT OR REPLACE INTO cross_signing (id, data) VALUES (0, ?1)",
+        params![seal(keys, &cross_signing)?],
+      )?;
+    }
+    for (user_id, user_keys) in changes.cross_signing_keys {
+      transaction.execute(
+        "INSERT OR REPLACE INTO cross_signing_keys (user_id, data) VALUES (?1, ?2)",
+        params![user_id, seal(keys, &user_keys)?],
+      )?;
+    }
+    for (user_id, master_key) in changes.verified_master_keys {
+      transaction.execute(
+        "INSERT OR REPLACE INTO verified_master_keys (user_id, master_key) VALUES (?1, ?2)",
+        params![user_id, master_key],
+      )?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+  }
+
+  fn account(&self) -> Result<Option<StoredAccount>> {
+    let data: Option<Vec<u8>> = self
+      .connection
+      .query_row(
+        "SELECT data FROM crypto_account WHERE id = 0",
+        NO_PARAMS,
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    match data {
+      Some(data) => Ok(Some(unseal(&self.keys, &data)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn sessions(&self) -> Result<HashMap<String, Vec<SessionPickle>>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT sender_key, data FROM olm_sessions ORDER BY sender_key, position")?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+
+    let mut sessions: HashMap<String, Vec<SessionPickle>> = HashMap::new();
+    for row in rows {
+      let (sender_key, data) = row?;
+      sessions
+        .entry(sender_key)
+        .or_default()
+        .push(unseal(&self.keys, &data)?);
+    }
+    Ok(sessions)
+  }
+
+  fn inbound_group_sessions(&self) -> Result<Vec<StoredInboundGroupSession>> {
+    self.load_all("SELECT data FROM inbound_group_sessions")
+  }
+
+  fn outbound_group_sessions(&self) -> Result<Vec<StoredOutboundGroupSession>> {
+    self.load_all("SELECT data FROM outbound_group_sessions")
+  }
+
+  fn devices(&self) -> Result<HashMap<String, HashMap<String, DeviceKeys>>> {
+    let mut devices: HashMap<String, HashMap<String, DeviceKeys>> = HashMap::new();
+    for device in self.load_all::<DeviceKeys>("SELECT data FROM devices")? {
+      devices
+        .entry(device.user_id.clone())
+        .or_default()
+        .insert(device.device_id.clone(), device);
+    }
+    Ok(devices)
+  }
+
+  fn tracked_users(&self) -> Result<Vec<TrackedUser>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT user_id, outdated FROM tracked_users")?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+      Ok(TrackedUser {
+        user_id: row.get(0)?,
+        outdated: row.get(1)?,
+      })
+    })?;
+
+    let mut users = Vec::new();
+    for user in rows {
+      users.push(user?);
+    }
+    Ok(users)
+  }
+
+  fn verified_devices(&self) -> Result<HashMap<String, HashSet<String>>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT user_id, device_id FROM verified_devices")?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut devices: HashMap<String, HashSet<String>> = HashMap::new();
+    for row in rows {
+      let (user_id, device_id) = row?;
+      devices.entry(user_id).or_default().insert(device_id);
+    }
+    Ok(devices)
+  }
+
+  fn cross_signing(&self) -> Result<Option<StoredCrossSigningKeys>> {
+    let data: Option<Vec<u8>> = self
+      .connection
+      .query_row(
+        "SELECT data FROM cross_signing WHERE id = 0",
+        NO_PARAMS,
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    match data {
+      Some(data) => Ok(Some(unseal(&self.keys, &data)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn cross_signing_keys(&self) -> Result<HashMap<String, UserCrossSigningKeys>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT user_id, data FROM cross_signing_keys")?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+
+    let mut keys = HashMap::new();
+    for row in rows {
+      let (user_id, data) = row?;
+      keys.insert(user_id, unseal(&self.keys, &data)?);
+    }
+    Ok(keys)
+  }
+
+  fn verified_master_keys(&self) -> Result<HashMap<String, String>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT user_id, master_key FROM verified_master_keys")?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut keys = HashMap::new();
+    for row in rows {
+      let (user_id, master_key) = row?;
+      keys.insert(user_id, master_key);
+    }
+    Ok(keys)
+  }
+}
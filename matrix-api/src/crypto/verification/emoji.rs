@@ -0,0 +1,272 @@
+/*
+SAS Emoji
+The 64 emoji a short authentication string is shown as, with the names from the spec's
+translations table
+
+docs: https://matrix.org/docs/spec/client_server/latest#sas-method-emoji
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Emoji {
+  pub symbol: &'static str,
+  pub name: &'static str,
+}
+
+pub static EMOJI: [Emoji; 64] = [
+  Emoji {
+    symbol: "🐶",
+    name: "Dog",
+  },
+  Emoji {
+    symbol: "🐱",
+    name: "Cat",
+  },
+  Emoji {
+    symbol: "🦁",
+    name: "Lion",
+  },
+  Emoji {
+    symbol: "🐎",
+    name: "Horse",
+  },
+  Emoji {
+    symbol: "🦄",
+    name: "Unicorn",
+  },
+  Emoji {
+    symbol: "🐷",
+    name: "Pig",
+  },
+  Emoji {
+    symbol: "🐘",
+    name: "Elephant",
+  },
+  Emoji {
+    symbol: "🐰",
+    name: "Rabbit",
+  },
+  Emoji {
+    symbol: "🐼",
+    name: "Panda",
+  },
+  Emoji {
+    symbol: "🐓",
+    name: "Rooster",
+  },
+  Emoji {
+    symbol: "🐧",
+    name: "Penguin",
+  },
+  Emoji {
+    symbol: "🐢",
+    name: "Turtle",
+  },
+  Emoji {
+    symbol: "🐟",
+    name: "Fish",
+  },
+  Emoji {
+    symbol: "🐙",
+    name: "Octopus",
+  },
+  Emoji {
+    symbol: "🦋",
+    name: "Butterfly",
+  },
+  Emoji {
+    symbol: "🌷",
+    name: "Flower",
+  },
+  Emoji {
+    symbol: "🌳",
+    name: "Tree",
+  },
+  Emoji {
+    symbol: "🌵",
+    name: "Cactus",
+  },
+  Emoji {
+    symbol: "🍄",
+    name: "Mushroom",
+  },
+  Emoji {
+    symbol: "🌏",
+    name: "Globe",
+  },
+  Emoji {
+    symbol: "🌙",
+    name: "Moon",
+  },
+  Emoji {
+    symbol: "☁️",
+    name: "Cloud",
+  },
+  Emoji {
+    symbol: "🔥",
+    name: "Fire",
+  },
+  Emoji {
+    symbol: "🍌",
+    name: "Banana",
+  },
+  Emoji {
+    symbol: "🍎",
+    name: "Apple",
+  },
+  Emoji {
+    symbol: "🍓",
+    name: "Strawberry",
+  },
+  Emoji {
+    symbol: "🌽",
+    name: "Corn",
+  },
+  Emoji {
+    symbol: "🍕",
+    name: "Pizza",
+  },
+  Emoji {
+    symbol: "🎂",
+    name: "Cake",
+  },
+  Emoji {
+    symbol: "❤️",
+    name: "Heart",
+  },
+  Emoji {
+    symbol: "😀",
+    name: "Smiley",
+  },
+  Emoji {
+    symbol: "🤖",
+    name: "Robot",
+  },
+  Emoji {
+    symbol: "🎩",
+    name: "Hat",
+  },
+  Emoji {
+    symbol: "👓",
+    name: "Glasses",
+  },
+  Emoji {
+    symbol: "🔧",
+    name: "Spanner",
+  },
+  Emoji {
+    symbol: "🎅",
+    name: "Santa",
+  },
+  Emoji {
+    symbol: "👍",
+    name: "Thumbs Up",
+  },
+  Emoji {
+    symbol: "☂️",
+    name: "Umbrella",
+  },
+  Emoji {
+    symbol: "⌛",
+    name: "Hourglass",
+  },
+  Emoji {
+    symbol: "⏰",
+    name: "Clock",
+  },
+  Emoji {
+    symbol: "🎁",
+    name: "Gift",
+  },
+  Emoji {
+    symbol: "💡",
+    name: "Light Bulb",
+  },
+  Emoji {
+    symbol: "📕",
+    name: "Book",
+  },
+  Emoji {
+    symbol: "✏️",
+    name: "Pencil",
+  },
+  Emoji {
+    symbol: "📎",
+    name: "Paperclip",
+  },
+  Emoji {
+    symbol: "✂️",
+    name: "Scissors",
+  },
+  Emoji {
+    symbol: "🔒",
+    name: "Lock",
+  },
+  Emoji {
+    symbol: "🔑",
+    name: "Key",
+  },
+  Emoji {
+    symbol: "🔨",
+    name: "Hammer",
+  },
+  Emoji {
+    symbol: "☎️",
+    name: "Telephone",
+  },
+  Emoji {
+    symbol: "🏁",
+    name: "Flag",
+  },
+  Emoji {
+    symbol: "🚂",
+    name: "Train",
+  },
+  Emoji {
+    symbol: "🚲",
+    name: "Bicycle",
+  },
+  Emoji {
+    symbol: "✈️",
+    name: "Aeroplane",
+  },
+  Emoji {
+    symbol: "🚀",
+    name: "Rocket",
+  },
+  Emoji {
+    symbol: "🏆",
+    name: "Trophy",
+  },
+  Emoji {
+    symbol: "⚽",
+    name: "Ball",
+  },
+  Emoji {
+    symbol: "🎸",
+    name: "Guitar",
+  },
+  Emoji {
+    symbol: "🎺",
+    name: "Trumpet",
+  },
+  Emoji {
+    symbol: "🔔",
+    name: "Bell",
+  },
+  Emoji {
+    symbol: "⚓",
+    name: "Anchor",
+  },
+  Emoji {
+    symbol: "🎧",
+    name: "Headphones",
+  },
+  Emoji {
+    symbol: "📁",
+    name: "Folder",
+  },
+  Emoji {
+    symbol: "📌",
+    name: "Pin",
+  },
+];
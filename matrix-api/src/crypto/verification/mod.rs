@@ -0,0 +1,148 @@
+use serde_json::{json, Value};
+
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::rooms::send;
+use crate::to_device;
+use crate::to_device::DeviceMessages;
+
+pub mod emoji;
+pub mod sas;
+
+/*
+Key Verification
+Users compare a short authentication string on both devices to check there's no one in the
+middle, each side then sends a MAC of its keys so the other can mark them as verified.
+
+Verification runs over to-device events, or in a room the users share where the request is
+an m.room.message and the rest of the events reference it.
+
+docs: https://matrix.org/docs/spec/client_server/latest#key-verification-framework
+*/
+
+pub static SAS_METHOD: &str = "m.sas.v1";
+
+pub static REQUEST: &str = "m.key.verification.request";
+pub static READY: &str = "m.key.verification.ready";
+pub static START: &str = "m.key.verification.start";
+pub static ACCEPT: &str = "m.key.verification.accept";
+pub static KEY: &str = "m.key.verification.key";
+pub static MAC: &str = "m.key.verification.mac";
+pub static DONE: &str = "m.key.verification.done";
+pub static CANCEL: &str = "m.key.verification.cancel";
+
+// Verifications which haven't finished after this long are cancelled
+pub static TIMEOUT_MS: i64 = 600_000;
+// Requests older than TIMEOUT_MS or further ahead than this are ignored, the spec allows for
+// clocks being a little off
+pub static REQUEST_FUTURE_MS: i64 = 300_000;
+
+// Codes sent in m.key.verification.cancel
+pub mod cancel_code {
+  pub static USER: &str = "m.user";
+  pub static TIMEOUT: &str = "m.timeout";
+  pub static UNKNOWN_METHOD: &str = "m.unknown_method";
+  pub static UNEXPECTED_MESSAGE: &str = "m.unexpected_message";
+  pub static KEY_MISMATCH: &str = "m.key_mismatch";
+  pub static INVALID_MESSAGE: &str = "m.invalid_message";
+  pub static MISMATCHED_COMMITMENT: &str = "m.mismatched_commitment";
+  pub static MISMATCHED_SAS: &str = "m.mismatched_sas";
+}
+
+// How the verification's events get to the other side
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerificationFlow {
+  ToDevice { transaction_id: String },
+  // Referencing the request event in the room
+  Room { room_id: String, event_id: String },
+}
+
+impl VerificationFlow {
+  pub fn flow_id(&self) -> &str {
+    match self {
+      VerificationFlow::ToDevice { transaction_id } => transaction_id,
+      VerificationFlow::Room { event_id, .. } => event_id,
+    }
+  }
+
+  // Add the field tying the content to the verification
+  pub fn add_to(&self, content: &mut Value) {
+    match self {
+      VerificationFlow::ToDevice { transaction_id } => {
+        content["transaction_id"] = json!(transaction_id);
+      }
+      VerificationFlow::Room { event_id, .. } => {
+        content["m.relates_to"] = json!({ "rel_type": "m.reference", "event_id": event_id });
+      }
+    }
+  }
+
+  // The verification a received event belongs to
+  pub fn flow_id_of(content: &Value) -> Option<&str> {
+    content["transaction_id"]
+      .as_str()
+      .or_else(|| content["m.relates_to"]["event_id"].as_str())
+  }
+}
+
+// An event to send to the other side
+#[derive(Debug, Clone)]
+pub struct VerificationContent {
+  pub event_type: &'static str,
+  pub content: Value,
+}
+
+// Content of a request, to_user_id is only needed in rooms
+pub fn request_content(from_device: &str, to_user_id: Option<&str>, timestamp: i64) -> Value {
+  match to_user_id {
+    Some(to_user_id) => json!({
+      "msgtype": REQUEST,
+      "body": "Asking to verify your keys, your client doesn't support key verification",
+      "to": to_user_id,
+      "from_device": from_device,
+      "methods": [SAS_METHOD],
+    }),
+    None => json!({
+      "from_device": from_device,
+      "methods": [SAS_METHOD],
+      "timestamp": timestamp,
+    }),
+  }
+}
+
+// Send to the other device, or to every device of the user when it isn't known yet.
+// Room events use txn_id as their transaction id.
+pub fn send(
+  client: &MatrixClient,
+  flow: &VerificationFlow,
+  other_user_id: &str,
+  other_device_id: Option<&str>,
+  txn_id: &str,
+  outgoing: &VerificationContent,
+) -> Result<()> {
+  match flow {
+    VerificationFlow::ToDevice { .. } => {
+      let mut messages = DeviceMessages::new();
+      match other_device_id {
+        Some(device_id) => to_device::add_message(
+          &mut messages,
+          other_user_id,
+          device_id,
+          outgoing.content.clone(),
+        ),
+        None => to_device::add_all_devices(&mut messages, other_user_id, outgoing.content.clone()),
+      }
+      to_device::send_to_device(client, outgoing.event_type, txn_id, &messages)
+    }
+    VerificationFlow::Room { room_id, .. } => {
+      send::send_event(
+        client,
+        room_id,
+        outgoing.event_type,
+        txn_id,
+        &outgoing.content,
+      )?;
+      Ok(())
+    }
+  }
+}
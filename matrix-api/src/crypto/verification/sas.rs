@@ -0,0 +1,736 @@
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vodozemac::base64_encode;
+use vodozemac::sas::{EstablishedSas, Sas};
+
+use crate::crypto::cipher;
+use crate::crypto::signatures;
+use crate::crypto::verification::emoji::{Emoji, EMOJI};
+use crate::crypto::verification::{
+  cancel_code, VerificationContent, VerificationFlow, ACCEPT, CANCEL, DONE, KEY, MAC, READY,
+  SAS_METHOD, START, TIMEOUT_MS,
+};
+
+/*
+SAS Verification
+Both devices make an ephemeral curve25519 key and swap them, the starting side commits to its
+key first so neither can pick one to get a wanted result. The shared secret is shown as emoji
+or numbers, and once the user says they match it's used to MAC each side's keys.
+
+docs: https://matrix.org/docs/spec/client_server/latest#short-authentication-string-sas-verification
+*/
+
+static KEY_AGREEMENT_PROTOCOL: &str = "curve25519-hkdf-sha256";
+static HASH: &str = "sha256";
+// Preferred first, the second is the old libolm encoding
+static MAC_METHODS: &[&str] = &["hkdf-hmac-sha256.v2", "hkdf-hmac-sha256"];
+static SAS_TYPES: &[&str] = &["emoji", "decimal"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SasState {
+  // We asked, waiting for the other side to be ready
+  Requested,
+  // They asked, waiting for the user to accept
+  RequestReceived,
+  Ready,
+  // Waiting for the other side to accept our start
+  Started,
+  // Waiting for the other side's key
+  Accepted,
+  // The SAS can be shown, waiting for the user to compare it
+  KeysExchanged,
+  // The user said the SAS matches, waiting for the other side's MAC
+  Confirmed,
+  Done,
+  Cancelled { code: String, reason: String },
+}
+
+// One side of the verification
+#[derive(Debug, Clone)]
+pub struct Identity {
+  pub user_id: String,
+  pub device_id: Option<String>,
+  // Keys to MAC, or to check the MACs of, keyed by key id e.g. "ed25519:<device id>"
+  pub keys: HashMap<String, String>,
+}
+
+// Cancel code and reason when the other side breaks the protocol
+type Step = Result<Vec<VerificationContent>, (&'static str, String)>;
+
+pub struct SasVerification {
+  flow: VerificationFlow,
+  own: Identity,
+  other: Identity,
+  created_ts: i64,
+  state: SasState,
+  we_started: bool,
+  sas: Option<Sas>,
+  established: Option<EstablishedSas>,
+  // As sent, the commitment is over it
+  start_content: Option<Value>,
+  commitment: Option<String>,
+  mac_method: &'static str,
+  sas_types: Vec<String>,
+  their_mac: Option<Value>,
+  // Their key ids whose MACs matched
+  verified_keys: Vec<String>,
+  done_sent: bool,
+  done_received: bool,
+}
+
+impl SasVerification {
+  // we_requested is false for an incoming request, which waits for `accept`
+  pub fn new(
+    flow: VerificationFlow,
+    own: Identity,
+    other: Identity,
+    we_requested: bool,
+  ) -> SasVerification {
+    SasVerification {
+      flow,
+      own,
+      other,
+      created_ts: now_ms(),
+      state: if we_requested {
+        SasState::Requested
+      } else {
+        SasState::RequestReceived
+      },
+      we_started: false,
+      sas: None,
+      established: None,
+      start_content: None,
+      commitment: None,
+      mac_method: MAC_METHODS[0],
+      sas_types: Vec::new(),
+      their_mac: None,
+      verified_keys: Vec::new(),
+      done_sent: false,
+      done_received: false,
+    }
+  }
+
+  pub fn flow(&self) -> &VerificationFlow {
+    &self.flow
+  }
+
+  pub fn flow_id(&self) -> &str {
+    self.flow.flow_id()
+  }
+
+  pub fn state(&self) -> &SasState {
+    &self.state
+  }
+
+  pub fn other_user_id(&self) -> &str {
+    &self.other.user_id
+  }
+
+  pub fn other_device_id(&self) -> Option<&str> {
+    self.other.device_id.as_deref()
+  }
+
+  // Set once the other device is known, its keys are needed to check their MAC
+  pub fn set_other_keys(&mut self, keys: HashMap<String, String>) {
+    self.other.keys = keys;
+  }
+
+  // Key ids of the other side which were verified
+  pub fn verified_keys(&self) -> &[String] {
+    &self.verified_keys
+  }
+
+  pub fn is_finished(&self) -> bool {
+    matches!(self.state, SasState::Done | SasState::Cancelled { .. })
+  }
+
+  pub fn is_expired(&self) -> bool {
+    !self.is_finished() && now_ms() - self.created_ts > TIMEOUT_MS
+  }
+
+  // Accept an incoming request
+  pub fn accept(&mut self) -> Option<VerificationContent> {
+    if self.state != SasState::RequestReceived {
+      return None;
+    }
+    self.state = SasState::Ready;
+    Some(self.outgoing(
+      READY,
+      json!({ "from_device": self.own_device_id(), "methods": [SAS_METHOD] }),
+    ))
+  }
+
+  // Start SAS once the request is ready
+  pub fn start(&mut self) -> Option<VerificationContent> {
+    if self.state != SasState::Ready {
+      return None;
+    }
+    let outgoing = self.outgoing(
+      START,
+      json!({
+        "from_device": self.own_device_id(),
+        "method": SAS_METHOD,
+        "key_agreement_protocols": [KEY_AGREEMENT_PROTOCOL],
+        "hashes": [HASH],
+        "message_authentication_codes": MAC_METHODS,
+        "short_authentication_string": SAS_TYPES,
+      }),
+    );
+    self.start_content = Some(outgoing.content.clone());
+    self.sas = Some(Sas::new());
+    self.we_started = true;
+    self.state = SasState::Started;
+    Some(outgoing)
+  }
+
+  // The user says the SAS matches on both devices
+  pub fn confirm(&mut self) -> Vec<VerificationContent> {
+    if self.state != SasState::KeysExchanged {
+      return Vec::new();
+    }
+
+    let info = self.mac_info(&self.own, &self.other);
+    let mut key_ids: Vec<&String> = self.own.keys.keys().collect();
+    key_ids.sort();
+    let mut mac = serde_json::Map::new();
+    for key_id in &key_ids {
+      let key = &self.own.keys[*key_id];
+      mac.insert(
+        key_id.to_string(),
+        json!(self.calculate_mac(key, &format!("{}{}", info, key_id))),
+      );
+    }
+    let key_ids = key_ids
+      .iter()
+      .map(|key_id| key_id.as_str())
+      .collect::<Vec<_>>()
+      .join(",");
+    let keys = self.calculate_mac(&key_ids, &format!("{}KEY_IDS", info));
+
+    self.state = SasState::Confirmed;
+    let mut outgoing = vec![self.outgoing(MAC, json!({ "mac": mac, "keys": keys }))];
+    if self.their_mac.is_some() {
+      outgoing.extend(self.step(|sas| sas.check_their_mac()));
+    }
+    outgoing
+  }
+
+  // Nothing to send if it had already finished
+  pub fn cancel(&mut self, code: &str, reason: &str) -> Option<VerificationContent> {
+    if self.is_finished() {
+      return None;
+    }
+    self.state = SasState::Cancelled {
+      code: code.to_string(),
+      reason: reason.to_string(),
+    };
+    Some(self.outgoing(CANCEL, json!({ "code": code, "reason": reason })))
+  }
+
+  // Handle an event from the other side, returning what to send back.
+  // Events which break the protocol cancel the verification.
+  pub fn receive(&mut self, event_type: &str, content: &Value) -> Vec<VerificationContent> {
+    if self.is_finished() {
+      return Vec::new();
+    }
+
+    match event_type {
+      t if t == READY => self.step(|sas| sas.receive_ready(content)),
+      t if t == START => self.step(|sas| sas.receive_start(content)),
+      t if t == ACCEPT => self.step(|sas| sas.receive_accept(content)),
+      t if t == KEY => self.step(|sas| sas.receive_key(content)),
+      t if t == MAC => self.step(|sas| sas.receive_mac(content)),
+      t if t == DONE => self.step(|sas| sas.receive_done()),
+      t if t == CANCEL => {
+        self.state = SasState::Cancelled {
+          code: content["code"].as_str().unwrap_or_default().to_string(),
+          reason: content["reason"].as_str().unwrap_or_default().to_string(),
+        };
+        Vec::new()
+      }
+      _ => Vec::new(),
+    }
+  }
+
+  // The SAS as emoji, once the keys have been exchanged
+  pub fn emoji(&self) -> Option<Vec<Emoji>> {
+    if !self.sas_types.iter().any(|t| t == "emoji") {
+      return None;
+    }
+    let bytes = self.established.as_ref()?.bytes(&self.sas_info());
+    Some(
+      bytes
+        .emoji_indices()
+        .iter()
+        .map(|i| EMOJI[*i as usize])
+        .collect(),
+    )
+  }
+
+  // The SAS as three numbers from 1000 to 9191, once the keys have been exchanged
+  pub fn decimals(&self) -> Option<(u16, u16, u16)> {
+    if !self.sas_types.iter().any(|t| t == "decimal") {
+      return None;
+    }
+    let bytes = self.established.as_ref()?.bytes(&self.sas_info());
+    Some(bytes.decimals())
+  }
+
+  fn step<F: FnOnce(&mut SasVerification) -> Step>(&mut self, f: F) -> Vec<VerificationContent> {
+    match f(self) {
+      Ok(outgoing) => outgoing,
+      Err((code, reason)) => self.cancel(code, &reason).into_iter().collect(),
+    }
+  }
+
+  fn receive_ready(&mut self, content: &Value) -> Step {
+    if self.state != SasState::Requested {
+      return Err(unexpected(READY));
+    }
+    if !has_value(&content["methods"], SAS_METHOD) {
+      return Err((
+        cancel_code::UNKNOWN_METHOD,
+        String::from("SAS isn't supported"),
+      ));
+    }
+    if self.other.device_id.is_none() {
+      self.other.device_id = content["from_device"].as_str().map(String::from);
+    }
+    self.state = SasState::Ready;
+
+    // We asked, so we start
+    Ok(self.start().into_iter().collect())
+  }
+
+  fn receive_start(&mut self, content: &Value) -> Step {
+    match self.state {
+      SasState::Ready => (),
+      // Both sides started, the one from the lower user then device id is kept
+      SasState::Started => {
+        let ours_first = match self.own.user_id.cmp(&self.other.user_id) {
+          Ordering::Equal => {
+            let their_device = content["from_device"].as_str().unwrap_or_default();
+            self.own_device_id() < their_device
+          }
+          ordering => ordering == Ordering::Less,
+        };
+        if ours_first {
+          return Ok(Vec::new());
+        }
+        self.we_started = false;
+      }
+      _ => return Err(unexpected(START)),
+    }
+
+    if content["method"].as_str() != Some(SAS_METHOD)
+      || !has_value(&content["key_agreement_protocols"], KEY_AGREEMENT_PROTOCOL)
+      || !has_value(&content["hashes"], HASH)
+    {
+      return Err((
+        cancel_code::UNKNOWN_METHOD,
+        String::from("Unsupported SAS method"),
+      ));
+    }
+    let mac_method = MAC_METHODS
+      .iter()
+      .find(|method| has_value(&content["message_authentication_codes"], method))
+      .ok_or_else(|| {
+        (
+          cancel_code::UNKNOWN_METHOD,
+          String::from("No shared MAC method"),
+        )
+      })?;
+    let sas_types: Vec<String> = SAS_TYPES
+      .iter()
+      .filter(|t| has_value(&content["short_authentication_string"], t))
+      .map(|t| t.to_string())
+      .collect();
+    if sas_types.is_empty() {
+      return Err((
+        cancel_code::UNKNOWN_METHOD,
+        String::from("No shared SAS type"),
+      ));
+    }
+    if self.other.device_id.is_none() {
+      self.other.device_id = content["from_device"].as_str().map(String::from);
+    }
+
+    let sas = Sas::new();
+    let commitment = commitment(&sas.public_key().to_base64(), content);
+    self.sas = Some(sas);
+    self.start_content = Some(content.clone());
+    self.mac_method = mac_method;
+    self.sas_types = sas_types;
+    self.state = SasState::Accepted;
+
+    Ok(vec![self.outgoing(
+      ACCEPT,
+      json!({
+        "method": SAS_METHOD,
+        "key_agreement_protocol": KEY_AGREEMENT_PROTOCOL,
+        "hash": HASH,
+        "message_authentication_code": mac_method,
+        "short_authentication_string": self.sas_types,
+        "commitment": commitment,
+      }),
+    )])
+  }
+
+  fn receive_accept(&mut self, content: &Value) -> Step {
+    if self.state != SasState::Started || !self.we_started {
+      return Err(unexpected(ACCEPT));
+    }
+    if content["key_agreement_protocol"].as_str() != Some(KEY_AGREEMENT_PROTOCOL)
+      || content["hash"].as_str() != Some(HASH)
+    {
+      return Err((
+        cancel_code::UNKNOWN_METHOD,
+        String::from("Unsupported SAS method"),
+      ));
+    }
+    let mac_method = content["message_authentication_code"].as_str();
+    self.mac_method = MAC_METHODS
+      .iter()
+      .find(|method| Some(**method) == mac_method)
+      .ok_or_else(|| {
+        (
+          cancel_code::UNKNOWN_METHOD,
+          String::from("Unsupported MAC method"),
+        )
+      })?;
+    self.sas_types = SAS_TYPES
+      .iter()
+      .filter(|t| has_value(&content["short_authentication_string"], t))
+      .map(|t| t.to_string())
+      .collect();
+    if self.sas_types.is_empty() {
+      return Err((
+        cancel_code::UNKNOWN_METHOD,
+        String::from("No shared SAS type"),
+      ));
+    }
+    self.commitment = content["commitment"].as_str().map(String::from);
+    if self.commitment.is_none() {
+      return Err((
+        cancel_code::INVALID_MESSAGE,
+        String::from("Missing commitment"),
+      ));
+    }
+
+    self.state = SasState::Accepted;
+    Ok(vec![self.key_content()])
+  }
+
+  fn receive_key(&mut self, content: &Value) -> Step {
+    if self.state != SasState::Accepted {
+      return Err(unexpected(KEY));
+    }
+    let their_key = content["key"]
+      .as_str()
+      .ok_or_else(|| (cancel_code::INVALID_MESSAGE, String::from("Missing key")))?;
+
+    let mut outgoing = Vec::new();
+    if self.we_started {
+      let start_content = self.start_content.as_ref().expect("start was sent");
+      if self.commitment.as_deref() != Some(&commitment(their_key, start_content)) {
+        return Err((
+          cancel_code::MISMATCHED_COMMITMENT,
+          String::from("Key doesn't match the commitment"),
+        ));
+      }
+    } else {
+      outgoing.push(self.key_content());
+    }
+
+    let sas = self.sas.take().expect("SAS was made on start");
+    let established = sas
+      .diffie_hellman_with_raw(their_key)
+      .map_err(|e| (cancel_code::KEY_MISMATCH, e.to_string()))?;
+    self.established = Some(established);
+    self.state = SasState::KeysExchanged;
+    Ok(outgoing)
+  }
+
+  fn receive_mac(&mut self, content: &Value) -> Step {
+    match self.state {
+      SasState::KeysExchanged => {
+        self.their_mac = Some(content.clone());
+        Ok(Vec::new())
+      }
+      SasState::Confirmed => {
+        self.their_mac = Some(content.clone());
+        self.check_their_mac()
+      }
+      _ => Err(unexpected(MAC)),
+    }
+  }
+
+  fn receive_done(&mut self) -> Step {
+    self.done_received = true;
+    if self.done_sent {
+      self.state = SasState::Done;
+    }
+    Ok(Vec::new())
+  }
+
+  // Check the MACs of the other side's keys, we can only say we're done if they match
+  fn check_their_mac(&mut self) -> Step {
+    let their_mac = self.their_mac.clone().unwrap_or_default();
+    let info = self.mac_info(&self.other, &self.own);
+
+    let macs = their_mac["mac"]
+      .as_object()
+      .ok_or_else(|| (cancel_code::INVALID_MESSAGE, String::from("Missing MAC")))?;
+    let mut key_ids: Vec<&String> = macs.keys().collect();
+    key_ids.sort();
+    let key_ids_string = key_ids
+      .iter()
+      .map(|key_id| key_id.as_str())
+      .collect::<Vec<_>>()
+      .join(",");
+    if their_mac["keys"].as_str()
+      != Some(&self.calculate_mac(&key_ids_string, &format!("{}KEY_IDS", info)))
+    {
+      return Err((
+        cancel_code::KEY_MISMATCH,
+        String::from("Key ids don't match"),
+      ));
+    }
+
+    // Keys we don't know about are skipped
+    let mut verified_keys = Vec::new();
+    for key_id in key_ids {
+      let key = match self.other.keys.get(key_id) {
+        Some(key) => key,
+        None => continue,
+      };
+      if macs[key_id].as_str() != Some(&self.calculate_mac(key, &format!("{}{}", info, key_id))) {
+        return Err((
+          cancel_code::KEY_MISMATCH,
+          format!("{} doesn't match", key_id),
+        ));
+      }
+      verified_keys.push(key_id.clone());
+    }
+    if verified_keys.is_empty() {
+      return Err((
+        cancel_code::KEY_MISMATCH,
+        String::from("No known keys were sent"),
+      ));
+    }
+
+    self.verified_keys = verified_keys;
+    self.done_sent = true;
+    if self.done_received {
+      self.state = SasState::Done;
+    }
+    Ok(vec![self.outgoing(DONE, json!({}))])
+  }
+
+  fn key_content(&self) -> VerificationContent {
+    let key = self
+      .sas
+      .as_ref()
+      .expect("SAS was made on start")
+      .public_key()
+      .to_base64();
+    self.outgoing(KEY, json!({ "key": key }))
+  }
+
+  fn outgoing(&self, event_type: &'static str, mut content: Value) -> VerificationContent {
+    self.flow.add_to(&mut content);
+    VerificationContent {
+      event_type,
+      content,
+    }
+  }
+
+  fn own_device_id(&self) -> &str {
+    self.own.device_id.as_deref().unwrap_or_default()
+  }
+
+  fn sas_info(&self) -> String {
+    let established = self.established.as_ref().expect("keys were exchanged");
+    let own_key = established.our_public_key().to_base64();
+    let their_key = established.their_public_key().to_base64();
+    let (starter, starter_key, accepter, accepter_key) = if self.we_started {
+      (&self.own, own_key, &self.other, their_key)
+    } else {
+      (&self.other, their_key, &self.own, own_key)
+    };
+
+    format!(
+      "MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}|{}|{}|{}|{}",
+      starter.user_id,
+      starter.device_id.as_deref().unwrap_or_default(),
+      starter_key,
+      accepter.user_id,
+      accepter.device_id.as_deref().unwrap_or_default(),
+      accepter_key,
+      self.flow_id()
+    )
+  }
+
+  fn mac_info(&self, sender: &Identity, receiver: &Identity) -> String {
+    format!(
+      "MATRIX_KEY_VERIFICATION_MAC{}{}{}{}{}",
+      sender.user_id,
+      sender.device_id.as_deref().unwrap_or_default(),
+      receiver.user_id,
+      receiver.device_id.as_deref().unwrap_or_default(),
+      self.flow_id()
+    )
+  }
+
+  fn calculate_mac(&self, input: &str, info: &str) -> String {
+    let established = self.established.as_ref().expect("keys were exchanged");
+    if self.mac_method == MAC_METHODS[0] {
+      established.calculate_mac(input, info).to_base64()
+    } else {
+      established.calculate_mac_invalid_base64(input, info)
+    }
+  }
+}
+
+// Hash of the accepting side's key and the start content
+fn commitment(key: &str, start_content: &Value) -> String {
+  let input = format!("{}{}", key, signatures::canonical_json(start_content));
+  base64_encode(cipher::sha256(input.as_bytes()))
+}
+
+fn has_value(values: &Value, value: &str) -> bool {
+  values
+    .as_array()
+    .map(|values| values.iter().any(|v| v.as_str() == Some(value)))
+    .unwrap_or(false)
+}
+
+fn unexpected(event_type: &str) -> (&'static str, String) {
+  (
+    cancel_code::UNEXPECTED_MESSAGE,
+    format!("Unexpected {}", event_type),
+  )
+}
+
+fn now_ms() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity(user_id: &str, device_id: &str) -> Identity {
+    let mut keys = HashMap::new();
+    keys.insert(
+      format!("ed25519:{}", device_id),
+      format!("{} signing key", device_id),
+    );
+    Identity {
+      user_id: user_id.to_string(),
+      device_id: Some(device_id.to_string()),
+      keys,
+    }
+  }
+
+  // Alice asked Bob to verify
+  fn verifications() -> (SasVerification, SasVerification) {
+    let flow = VerificationFlow::ToDevice {
+      transaction_id: String::from("transaction"),
+    };
+    let alice = identity("@alice:example.org", "ALICE");
+    let bob = identity("@bob:example.org", "BOB");
+    (
+      SasVerification::new(flow.clone(), alice.clone(), bob.clone(), true),
+      SasVerification::new(flow, bob, alice, false),
+    )
+  }
+
+  fn deliver(
+    sas: &mut SasVerification,
+    outgoing: Vec<VerificationContent>,
+  ) -> Vec<VerificationContent> {
+    outgoing
+      .iter()
+      .flat_map(|outgoing| sas.receive(outgoing.event_type, &outgoing.content))
+      .collect()
+  }
+
+  fn exchange_keys(alice: &mut SasVerification, bob: &mut SasVerification) {
+    let ready = bob.accept().into_iter().collect();
+    let start = deliver(alice, ready);
+    let accept = deliver(bob, start);
+    let alice_key = deliver(alice, accept);
+    let bob_key = deliver(bob, alice_key);
+    assert!(deliver(alice, bob_key).is_empty());
+  }
+
+  fn cancelled_with(sas: &SasVerification) -> &str {
+    match sas.state() {
+      SasState::Cancelled { code, .. } => code,
+      state => panic!("expected a cancelled verification, got {:?}", state),
+    }
+  }
+
+  #[test]
+  fn both_sides_verify_each_other() {
+    let (mut alice, mut bob) = verifications();
+    exchange_keys(&mut alice, &mut bob);
+
+    assert_eq!(*alice.state(), SasState::KeysExchanged);
+    assert_eq!(*bob.state(), SasState::KeysExchanged);
+    assert!(alice.emoji().is_some());
+    assert_eq!(alice.emoji(), bob.emoji());
+    assert_eq!(alice.decimals(), bob.decimals());
+
+    let alice_mac = alice.confirm();
+    let bob_mac = bob.confirm();
+    let bob_done = deliver(&mut bob, alice_mac);
+    let alice_done = deliver(&mut alice, bob_mac);
+    deliver(&mut alice, bob_done);
+    deliver(&mut bob, alice_done);
+
+    assert_eq!(*alice.state(), SasState::Done);
+    assert_eq!(*bob.state(), SasState::Done);
+    assert_eq!(alice.verified_keys(), ["ed25519:BOB"]);
+    assert_eq!(bob.verified_keys(), ["ed25519:ALICE"]);
+  }
+
+  #[test]
+  fn changed_mac_is_cancelled() {
+    let (mut alice, mut bob) = verifications();
+    exchange_keys(&mut alice, &mut bob);
+
+    let mut alice_mac = alice.confirm();
+    alice_mac[0].content["mac"]["ed25519:ALICE"] = json!("not the mac");
+    bob.confirm();
+    let outgoing = deliver(&mut bob, alice_mac);
+
+    assert_eq!(cancelled_with(&bob), cancel_code::KEY_MISMATCH);
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].event_type, CANCEL);
+    assert!(bob.verified_keys().is_empty());
+  }
+
+  #[test]
+  fn key_not_matching_the_commitment_is_cancelled() {
+    let (mut alice, mut bob) = verifications();
+    let ready = bob.accept().into_iter().collect();
+    let start = deliver(&mut alice, ready);
+    let mut accept = deliver(&mut bob, start);
+    accept[0].content["commitment"] = json!(commitment("another key", &json!({})));
+    let alice_key = deliver(&mut alice, accept);
+    let bob_key = deliver(&mut bob, alice_key);
+    let outgoing = deliver(&mut alice, bob_key);
+
+    assert_eq!(cancelled_with(&alice), cancel_code::MISMATCHED_COMMITMENT);
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].event_type, CANCEL);
+    assert!(alice.emoji().is_none());
+  }
+}
@@ -0,0 +1,74 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+/*
+Events
+Common event shapes returned by the client-server API
+
+docs: https://matrix.org/docs/spec/client_server/latest#room-events
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsignedData {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub age: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub redacted_because: Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub transaction_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub prev_content: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomEvent {
+  pub content: Value,
+  pub r#type: String,
+  pub event_id: String,
+  pub sender: String,
+  pub origin_server_ts: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub unsigned: Option<UnsignedData>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  // Only present on state events
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub state_key: Option<String>,
+}
+
+impl RoomEvent {
+  pub fn is_state(&self) -> bool {
+    self.state_key.is_some()
+  }
+
+  // Parse the content of an m.room.member event
+  pub fn member_content(&self) -> Option<MemberContent> {
+    match self.r#type.as_ref() {
+      "m.room.member" => serde_json::from_value(self.content.clone()).ok(),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Membership {
+  #[serde(rename = "invite")]
+  Invite,
+  #[serde(rename = "join")]
+  Join,
+  #[serde(rename = "knock")]
+  Knock,
+  #[serde(rename = "leave")]
+  Leave,
+  #[serde(rename = "ban")]
+  Ban,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#m-room-member
+#[derive(Deserialize, Debug, Clone)]
+pub struct MemberContent {
+  pub membership: Membership,
+  pub displayname: Option<String>,
+  pub avatar_url: Option<String>,
+  pub is_direct: Option<bool>,
+}
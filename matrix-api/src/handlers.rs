@@ -0,0 +1,159 @@
+use crate::client::MatrixClient;
+use crate::events::{MemberContent, RoomEvent};
+use crate::sync::{BasicEvent, StrippedStateEvent, SyncResponse};
+
+/*
+Event Handlers
+Callbacks registered on the client with `MatrixClient::add_event_handler`, which are called
+for each event in a sync response so consumers don't need to walk the response themselves.
+
+Handlers get the client, so they can respond to events, e.g. reply to a message.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomMembership {
+  Joined,
+  // Only the invite's stripped state is known, its events have no id or timestamp
+  Invited,
+  Left,
+}
+
+// The room an event was received in
+#[derive(Debug, Clone, Copy)]
+pub struct RoomContext<'a> {
+  pub room_id: &'a str,
+  pub membership: RoomMembership,
+}
+
+pub type RoomEventHandler = Box<dyn Fn(&MatrixClient, &RoomContext, &RoomEvent)>;
+pub type MemberEventHandler = Box<dyn Fn(&MatrixClient, &RoomContext, &RoomEvent, &MemberContent)>;
+pub type EphemeralEventHandler = Box<dyn Fn(&MatrixClient, &RoomContext, &BasicEvent)>;
+pub type ToDeviceEventHandler = Box<dyn Fn(&MatrixClient, &BasicEvent)>;
+// Global account data is passed without a room
+pub type AccountDataEventHandler = Box<dyn Fn(&MatrixClient, Option<&RoomContext>, &BasicEvent)>;
+
+pub enum EventHandler {
+  // Timeline events which aren't state, e.g. m.room.message and m.reaction
+  Message(RoomEventHandler),
+  // m.room.member state events, these also go to the State handlers
+  Member(MemberEventHandler),
+  State(RoomEventHandler),
+  Ephemeral(EphemeralEventHandler),
+  ToDevice(ToDeviceEventHandler),
+  AccountData(AccountDataEventHandler),
+}
+
+// Returned when adding a handler, to remove it again later
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventHandlerId(pub(crate) usize);
+
+// Call the client's handlers for every event in the response, in the order they happened
+pub fn dispatch(client: &MatrixClient, response: &SyncResponse) {
+  let handlers = client.event_handlers();
+  if handlers.is_empty() {
+    return;
+  }
+
+  let state = |room: &RoomContext, event: &RoomEvent| {
+    for (_, handler) in handlers {
+      match handler {
+        EventHandler::State(handler) => handler(client, room, event),
+        EventHandler::Member(handler) => {
+          if let Some(content) = event.member_content() {
+            handler(client, room, event, &content)
+          }
+        }
+        _ => (),
+      }
+    }
+  };
+
+  let timeline = |room: &RoomContext, events: &[RoomEvent]| {
+    for event in events {
+      if event.is_state() {
+        state(room, event);
+        continue;
+      }
+      for (_, handler) in handlers {
+        if let EventHandler::Message(handler) = handler {
+          handler(client, room, event);
+        }
+      }
+    }
+  };
+
+  let account_data = |room: Option<&RoomContext>, events: &[BasicEvent]| {
+    for event in events {
+      for (_, handler) in handlers {
+        if let EventHandler::AccountData(handler) = handler {
+          handler(client, room, event);
+        }
+      }
+    }
+  };
+
+  for (room_id, joined) in &response.rooms.join {
+    let room = RoomContext {
+      room_id,
+      membership: RoomMembership::Joined,
+    };
+    for event in &joined.state.events {
+      state(&room, event);
+    }
+    timeline(&room, &joined.timeline.events);
+    for event in &joined.ephemeral.events {
+      for (_, handler) in handlers {
+        if let EventHandler::Ephemeral(handler) = handler {
+          handler(client, &room, event);
+        }
+      }
+    }
+    account_data(Some(&room), &joined.account_data.events);
+  }
+
+  for (room_id, invited) in &response.rooms.invite {
+    let room = RoomContext {
+      room_id,
+      membership: RoomMembership::Invited,
+    };
+    for event in &invited.invite_state.events {
+      state(&room, &stripped_event(room_id, event));
+    }
+  }
+
+  for (room_id, left) in &response.rooms.leave {
+    let room = RoomContext {
+      room_id,
+      membership: RoomMembership::Left,
+    };
+    for event in &left.state.events {
+      state(&room, event);
+    }
+    timeline(&room, &left.timeline.events);
+    account_data(Some(&room), &left.account_data.events);
+  }
+
+  account_data(None, &response.account_data.events);
+
+  for event in &response.to_device.events {
+    for (_, handler) in handlers {
+      if let EventHandler::ToDevice(handler) = handler {
+        handler(client, event);
+      }
+    }
+  }
+}
+
+// Stripped state as a room event, so the same handlers can take it
+fn stripped_event(room_id: &str, event: &StrippedStateEvent) -> RoomEvent {
+  RoomEvent {
+    content: event.content.clone(),
+    r#type: event.r#type.clone(),
+    event_id: String::new(),
+    sender: event.sender.clone(),
+    origin_server_ts: 0,
+    unsigned: None,
+    room_id: Some(room_id.to_string()),
+    state_key: Some(event.state_key.clone()),
+  }
+}
@@ -1,9 +1,25 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub mod account;
+pub mod account_data;
 pub mod api;
 pub mod auth;
 pub mod client;
+pub mod crypto;
+pub mod events;
+pub mod handlers;
 pub mod login;
+pub mod media;
+pub mod push;
+pub mod refresh;
 pub mod registration;
 pub mod rooms;
+pub mod search;
+pub mod send_queue;
+pub mod sso;
+pub mod store;
+pub mod sync;
+pub mod threepid;
+pub mod to_device;
+pub mod user_directory;
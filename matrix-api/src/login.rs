@@ -14,9 +14,19 @@ pub struct LoginFlows {
   pub flows: Vec<LoginFlow>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct IdentityProvider {
+  pub id: String,
+  pub name: String,
+  pub icon: Option<String>,
+  pub brand: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct LoginFlow {
   pub r#type: AuthenticationTypes,
+  // Only given for m.login.sso, the providers which can be picked from
+  pub identity_providers: Option<Vec<IdentityProvider>>,
 }
 
 #[derive(Serialize, Debug)]
@@ -28,7 +38,7 @@ pub enum LoginType {
   Token { token: String },
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ThirdPartyMedium {
   #[serde(rename = "email")]
   Email,
@@ -54,9 +64,14 @@ pub enum UserIdentifier {
 pub struct LoginModel {
   #[serde(flatten, rename = "type")]
   pub r#type: LoginType,
-  pub identifier: UserIdentifier,
+  // Not needed when logging in with a token
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub identifier: Option<UserIdentifier>,
   pub device_id: String,
   pub initial_device_display_name: String,
+  // Ask for a refresh token alongside an expiring access token
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,6 +94,9 @@ pub struct LoginResponse {
   pub home_server: String,
   pub device_id: String,
   pub well_known: Option<DiscoveryInformation>,
+  pub refresh_token: Option<String>,
+  // Lifetime of the access token, it does not expire when missing
+  pub expires_in_ms: Option<i64>,
 }
 
 pub fn get_login_flows(client: &MatrixClient) -> Result<LoginFlows> {
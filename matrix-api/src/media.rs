@@ -0,0 +1,113 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Media
+Upload files to the content repository to get an mxc:// URI to send in events, and download
+them again. Files for encrypted rooms are encrypted first, see crypto::attachments.
+
+docs: https://matrix.org/docs/spec/client_server/latest#content-repository
+*/
+
+pub static UPLOAD_ENDPOINT: &str = "/_matrix/media/r0/upload";
+
+pub fn download_endpoint(server_name: &str, media_id: &str) -> String {
+  format!(
+    "/_matrix/media/r0/download/{}/{}",
+    api::encode(server_name),
+    api::encode(media_id)
+  )
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct UploadQuery {
+  pub filename: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UploadResponse {
+  pub content_uri: String,
+}
+
+// The server name and media id of an mxc://<server name>/<media id> URI
+pub fn parse_mxc(uri: &str) -> Option<(&str, &str)> {
+  let mut parts = uri.strip_prefix("mxc://")?.splitn(2, '/');
+  match (parts.next(), parts.next()) {
+    (Some(server_name), Some(media_id)) if !server_name.is_empty() && !media_id.is_empty() => {
+      Some((server_name, media_id))
+    }
+    _ => None,
+  }
+}
+
+// Upload the file, returning its mxc:// URI
+pub fn upload(
+  client: &MatrixClient,
+  content_type: &str,
+  filename: Option<&str>,
+  data: Vec<u8>,
+) -> Result<String> {
+  upload_body(client, content_type, filename, reqwest::Body::from(data))
+}
+
+// Upload a file as it's read, length is the number of bytes the reader will give
+pub fn upload_reader<R: Read + Send + 'static>(
+  client: &MatrixClient,
+  content_type: &str,
+  filename: Option<&str>,
+  reader: R,
+  length: u64,
+) -> Result<String> {
+  upload_body(
+    client,
+    content_type,
+    filename,
+    reqwest::Body::sized(reader, length),
+  )
+}
+
+fn upload_body(
+  client: &MatrixClient,
+  content_type: &str,
+  filename: Option<&str>,
+  body: reqwest::Body,
+) -> Result<String> {
+  let query = UploadQuery {
+    filename: filename.map(String::from),
+  };
+  let mut response = api::post_body_query(client, UPLOAD_ENDPOINT, content_type, body, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success: UploadResponse = response.json()?;
+      Ok(success.content_uri)
+    }
+    StatusCode::FORBIDDEN | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// The file at the mxc:// URI, read it from the response as it downloads
+pub fn download(client: &MatrixClient, uri: &str) -> Result<reqwest::Response> {
+  let (server_name, media_id) = match parse_mxc(uri) {
+    Some(parts) => parts,
+    None => return Err(ApiError::Http(400, Some("Not an mxc:// URI"))),
+  };
+  let response = api::get(client, &download_endpoint(server_name, media_id))?;
+
+  match response.status() {
+    StatusCode::OK => Ok(response),
+    StatusCode::NOT_FOUND | StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
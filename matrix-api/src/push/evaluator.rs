@@ -0,0 +1,273 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::events::RoomEvent;
+use crate::push::rules::{Action, PushCondition, PushRule, RuleKind, Ruleset, SimpleAction, Tweak};
+
+/*
+Push Rule Evaluation
+Decides locally whether an incoming event should notify, following the same rules as the server
+
+docs: https://matrix.org/docs/spec/client_server/latest#push-rules
+*/
+
+// What is known about the user and room an event is being evaluated for
+#[derive(Debug)]
+pub struct PushContext<'a> {
+  pub user_id: &'a str,
+  // The user's display name in the room
+  pub display_name: Option<&'a str>,
+  pub member_count: u64,
+  pub sender_power_level: i64,
+  // The `notifications` section of the room's power levels, e.g. "room" => 50
+  pub notification_power_levels: HashMap<String, i64>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PushDecision {
+  pub notify: bool,
+  pub highlight: bool,
+  pub sound: Option<String>,
+  // The rule which produced the decision, None if nothing matched
+  pub rule_id: Option<String>,
+}
+
+impl PushDecision {
+  fn from_rule(rule: &PushRule) -> PushDecision {
+    let mut decision = PushDecision {
+      rule_id: Some(rule.rule_id.clone()),
+      ..PushDecision::default()
+    };
+
+    for action in &rule.actions {
+      match action {
+        Action::Simple(SimpleAction::Notify) | Action::Simple(SimpleAction::Coalesce) => {
+          decision.notify = true
+        }
+        Action::SetTweak(Tweak::Highlight { value }) => decision.highlight = *value,
+        Action::SetTweak(Tweak::Sound { value }) => decision.sound = Some(value.clone()),
+        _ => (),
+      }
+    }
+
+    decision
+  }
+}
+
+impl Ruleset {
+  // Find the first enabled rule matching the event and turn its actions into a decision
+  pub fn evaluate(&self, event: &RoomEvent, context: &PushContext) -> PushDecision {
+    let event_json = match serde_json::to_value(event) {
+      Ok(value) => value,
+      Err(_) => return PushDecision::default(),
+    };
+
+    for (kind, rules) in self.kinds() {
+      let matched = rules
+        .iter()
+        .filter(|rule| rule.enabled)
+        .find(|rule| rule_matches(kind, rule, event, &event_json, context));
+
+      if let Some(rule) = matched {
+        return PushDecision::from_rule(rule);
+      }
+    }
+
+    PushDecision::default()
+  }
+}
+
+fn rule_matches(
+  kind: RuleKind,
+  rule: &PushRule,
+  event: &RoomEvent,
+  event_json: &Value,
+  context: &PushContext,
+) -> bool {
+  match kind {
+    RuleKind::Override | RuleKind::Underride => match &rule.conditions {
+      Some(conditions) => conditions
+        .iter()
+        .all(|condition| condition_matches(condition, event_json, context)),
+      None => true,
+    },
+    RuleKind::Content => match (&rule.pattern, event_json["content"]["body"].as_str()) {
+      (Some(pattern), Some(body)) => word_glob_match(pattern, body),
+      _ => false,
+    },
+    RuleKind::Room => event.room_id.as_ref() == Some(&rule.rule_id),
+    RuleKind::Sender => event.sender == rule.rule_id,
+  }
+}
+
+fn condition_matches(condition: &PushCondition, event_json: &Value, context: &PushContext) -> bool {
+  match condition {
+    PushCondition::EventMatch { key, pattern } => match property(event_json, key) {
+      Some(Value::String(value)) if key == "content.body" => word_glob_match(pattern, value),
+      Some(Value::String(value)) => glob_match(pattern, value),
+      _ => false,
+    },
+    PushCondition::ContainsDisplayName => {
+      match (context.display_name, event_json["content"]["body"].as_str()) {
+        (Some(name), Some(body)) if !name.is_empty() => contains_word(body, name),
+        _ => false,
+      }
+    }
+    PushCondition::RoomMemberCount { is } => member_count_matches(is, context.member_count),
+    PushCondition::SenderNotificationPermission { key } => {
+      let required = context
+        .notification_power_levels
+        .get(key)
+        .cloned()
+        .unwrap_or(50);
+      context.sender_power_level >= required
+    }
+    PushCondition::EventPropertyIs { key, value } => match value {
+      Value::Array(_) | Value::Object(_) => false,
+      _ => property(event_json, key) == Some(value),
+    },
+    PushCondition::Unknown => false,
+  }
+}
+
+// Look up a dotted key such as `content.body`, where `\.` is a literal dot in a field name
+fn property<'a>(event_json: &'a Value, key: &str) -> Option<&'a Value> {
+  let mut fields = vec![String::new()];
+  let mut chars = key.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => {
+        if let Some(escaped) = chars.next() {
+          fields.last_mut()?.push(escaped);
+        }
+      }
+      '.' => fields.push(String::new()),
+      c => fields.last_mut()?.push(c),
+    }
+  }
+
+  fields
+    .iter()
+    .try_fold(event_json, |value, field| value.get(field.as_str()))
+}
+
+// Compare the member count against `is`, e.g. `2`, `==2`, `<10` or `>=3`
+fn member_count_matches(is: &str, member_count: u64) -> bool {
+  let number_start = is.find(|c: char| c.is_ascii_digit()).unwrap_or(is.len());
+  let (operator, number) = is.split_at(number_start);
+  let number = match number.parse::<u64>() {
+    Ok(number) => number,
+    Err(_) => return false,
+  };
+
+  match operator {
+    "" | "==" => member_count == number,
+    "<" => member_count < number,
+    ">" => member_count > number,
+    "<=" => member_count <= number,
+    ">=" => member_count >= number,
+    _ => false,
+  }
+}
+
+// Case insensitive glob match against the whole value, `*` matches any run and `?` one character
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+  let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+  let value: Vec<char> = value.to_lowercase().chars().collect();
+  glob_match_chars(&pattern, &value)
+}
+
+fn glob_match_chars(pattern: &[char], value: &[char]) -> bool {
+  let (mut p, mut v) = (0, 0);
+  // Where to resume from after the last `*`, if matching further along fails
+  let mut backtrack: Option<(usize, usize)> = None;
+
+  while v < value.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+      p += 1;
+      v += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      backtrack = Some((p, v));
+      p += 1;
+    } else if let Some((star, matched)) = backtrack {
+      p = star + 1;
+      v = matched + 1;
+      backtrack = Some((star, matched + 1));
+    } else {
+      return false;
+    }
+  }
+
+  pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn is_word_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+// Glob match against any part of the value which isn't next to a word character on either
+// side, so `@room` matches "hey @room!" but not "a@room" or "@roomy"
+pub fn word_glob_match(pattern: &str, value: &str) -> bool {
+  let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+  let value: Vec<char> = value.to_lowercase().chars().collect();
+  let starts: Vec<usize> = (0..=value.len())
+    .filter(|&i| i == 0 || !is_word_char(value[i - 1]))
+    .collect();
+  let ends: Vec<usize> = (0..=value.len())
+    .filter(|&i| i == value.len() || !is_word_char(value[i]))
+    .collect();
+
+  starts.iter().any(|&start| {
+    ends
+      .iter()
+      .filter(|&&end| end >= start)
+      .any(|&end| glob_match_chars(&pattern, &value[start..end]))
+  })
+}
+
+// Whether the words appear in the value, not as part of a longer word
+fn contains_word(value: &str, words: &str) -> bool {
+  let words: Vec<char> = words.to_lowercase().chars().collect();
+  let value: Vec<char> = value.to_lowercase().chars().collect();
+
+  (0..value.len())
+    .filter(|&start| value[start..].starts_with(&words))
+    .any(|start| {
+      let end = start + words.len();
+      let starts_word = start == 0 || !is_word_char(value[start - 1]);
+      let ends_word = end == value.len() || !is_word_char(value[end]);
+      starts_word && ends_word
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn word_glob_matches_room_mentions() {
+    assert!(word_glob_match("@room", "@room"));
+    assert!(word_glob_match("@room", "Hey @room, lunch?"));
+    assert!(word_glob_match("@room", "@ROOM!"));
+    assert!(!word_glob_match("@room", "@roomy"));
+    assert!(!word_glob_match("@room", "me@room"));
+  }
+
+  #[test]
+  fn word_glob_matches_globs() {
+    assert!(word_glob_match("cake*", "I like cakes"));
+    assert!(word_glob_match("c?ke", "a cake here"));
+    assert!(word_glob_match("*lie*", "the cake is a lie"));
+    assert!(!word_glob_match("cake?", "I like cake"));
+    assert!(!word_glob_match("c?ke", "cooke"));
+  }
+
+  #[test]
+  fn word_glob_matches_at_word_edges() {
+    assert!(word_glob_match("cake", "cake"));
+    assert!(word_glob_match("cake", "(cake)"));
+    assert!(!word_glob_match("cake", "cupcake"));
+    assert!(!word_glob_match("cake", "cakes"));
+    assert!(!word_glob_match("cake", "cake_day"));
+  }
+}
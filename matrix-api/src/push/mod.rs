@@ -0,0 +1,4 @@
+pub mod evaluator;
+pub mod notifications;
+pub mod pushers;
+pub mod rules;
@@ -0,0 +1,60 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::RoomEvent;
+use crate::push::rules::Action;
+
+/*
+Notifications
+The events the user has been notified about, for showing a notifications inbox
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-notifications
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/notifications";
+
+#[derive(Serialize, Debug, Default)]
+pub struct NotificationsQuery {
+  // Pagination token from a previous response's next_token
+  pub from: Option<String>,
+  pub limit: Option<i64>,
+  // Set to "highlight" to only return notifications which were highlighted
+  pub only: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Notification {
+  // The actions of the push rule which matched the event
+  pub actions: Vec<Action>,
+  pub event: RoomEvent,
+  pub profile_tag: Option<String>,
+  pub read: bool,
+  pub room_id: String,
+  pub ts: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NotificationsResponse {
+  pub next_token: Option<String>,
+  pub notifications: Vec<Notification>,
+}
+
+pub fn get_notifications(
+  client: &MatrixClient,
+  query: NotificationsQuery,
+) -> Result<NotificationsResponse> {
+  let mut response = api::get_query(client, ENDPOINT, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,116 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Pushers
+The push gateways (or email addresses) the homeserver sends notifications to
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-pushers
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/pushers";
+pub static SET_ENDPOINT: &str = "/_matrix/client/r0/pushers/set";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PusherKind {
+  #[serde(rename = "http")]
+  Http,
+  #[serde(rename = "email")]
+  Email,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PushFormat {
+  // Only send the event and room id's, the app fetches the event itself
+  #[serde(rename = "event_id_only")]
+  EventIdOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PusherData {
+  // The push gateway's notify url, required for http pushers
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub format: Option<PushFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pusher {
+  // Device token for http pushers, the address for email pushers
+  pub pushkey: String,
+  pub kind: PusherKind,
+  // Reverse-DNS style identifier of the application, e.g. com.example.app.desktop
+  pub app_id: String,
+  pub app_display_name: String,
+  pub device_display_name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub profile_tag: Option<String>,
+  pub lang: String,
+  pub data: PusherData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushersResponse {
+  pub pushers: Vec<Pusher>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SetPusherRequest {
+  #[serde(flatten)]
+  pub pusher: Pusher,
+  // Keep other pushers with the same pushkey for other users, instead of replacing them
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub append: Option<bool>,
+}
+
+// A pusher is removed by setting it with a null kind
+#[derive(Serialize, Debug)]
+struct DeletePusherRequest<'a> {
+  pushkey: &'a str,
+  app_id: &'a str,
+  kind: Option<PusherKind>,
+}
+
+pub fn get_pushers(client: &MatrixClient) -> Result<PushersResponse> {
+  let mut response = api::get(client, ENDPOINT)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn set_pusher(client: &MatrixClient, request: SetPusherRequest) -> Result<()> {
+  let response = api::post(client, SET_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn delete_pusher(client: &MatrixClient, pushkey: &str, app_id: &str) -> Result<()> {
+  let request = DeletePusherRequest {
+    pushkey,
+    app_id,
+    kind: None,
+  };
+  let response = api::post(client, SET_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,301 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Push Rules
+The rules deciding which events notify the user, stored on the homeserver
+
+docs: https://matrix.org/docs/spec/client_server/latest#push-rules
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/pushrules/";
+
+// Push rules are only ever stored in the global scope
+pub static SCOPE: &str = "global";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RuleKind {
+  #[serde(rename = "override")]
+  Override,
+  #[serde(rename = "content")]
+  Content,
+  #[serde(rename = "room")]
+  Room,
+  #[serde(rename = "sender")]
+  Sender,
+  #[serde(rename = "underride")]
+  Underride,
+}
+
+impl RuleKind {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      RuleKind::Override => "override",
+      RuleKind::Content => "content",
+      RuleKind::Room => "room",
+      RuleKind::Sender => "sender",
+      RuleKind::Underride => "underride",
+    }
+  }
+}
+
+pub fn rule_endpoint(kind: RuleKind, rule_id: &str) -> String {
+  format!(
+    "{}{}/{}/{}",
+    ENDPOINT,
+    SCOPE,
+    kind.as_str(),
+    api::encode(rule_id)
+  )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SimpleAction {
+  #[serde(rename = "notify")]
+  Notify,
+  #[serde(rename = "dont_notify")]
+  DontNotify,
+  #[serde(rename = "coalesce")]
+  Coalesce,
+}
+
+fn default_highlight() -> bool {
+  true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "set_tweak")]
+pub enum Tweak {
+  #[serde(rename = "sound")]
+  Sound { value: String },
+  #[serde(rename = "highlight")]
+  Highlight {
+    #[serde(default = "default_highlight")]
+    value: bool,
+  },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Action {
+  Simple(SimpleAction),
+  SetTweak(Tweak),
+  // Actions and tweaks this client doesn't know about, kept so rules can be written back
+  Unknown(Value),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum PushCondition {
+  #[serde(rename = "event_match")]
+  EventMatch { key: String, pattern: String },
+  #[serde(rename = "contains_display_name")]
+  ContainsDisplayName,
+  #[serde(rename = "room_member_count")]
+  RoomMemberCount { is: String },
+  #[serde(rename = "sender_notification_permission")]
+  SenderNotificationPermission { key: String },
+  #[serde(rename = "event_property_is")]
+  EventPropertyIs { key: String, value: Value },
+  // Conditions this client doesn't know about never match
+  #[serde(other)]
+  Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PushRule {
+  pub rule_id: String,
+  pub default: bool,
+  pub enabled: bool,
+  pub actions: Vec<Action>,
+  // Only for override and underride rules
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub conditions: Option<Vec<PushCondition>>,
+  // Only for content rules, a glob matched against the message body
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pattern: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Ruleset {
+  #[serde(rename = "override", default)]
+  pub override_rules: Vec<PushRule>,
+  #[serde(default)]
+  pub content: Vec<PushRule>,
+  #[serde(default)]
+  pub room: Vec<PushRule>,
+  #[serde(default)]
+  pub sender: Vec<PushRule>,
+  #[serde(default)]
+  pub underride: Vec<PushRule>,
+}
+
+impl Ruleset {
+  // Rules of each kind, in the order they are evaluated
+  pub fn kinds(&self) -> Vec<(RuleKind, &Vec<PushRule>)> {
+    vec![
+      (RuleKind::Override, &self.override_rules),
+      (RuleKind::Content, &self.content),
+      (RuleKind::Room, &self.room),
+      (RuleKind::Sender, &self.sender),
+      (RuleKind::Underride, &self.underride),
+    ]
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushRulesResponse {
+  pub global: Ruleset,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewPushRule {
+  pub actions: Vec<Action>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub conditions: Option<Vec<PushCondition>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pattern: Option<String>,
+}
+
+// Position of a new rule relative to another user defined rule of the same kind
+#[derive(Serialize, Debug, Default)]
+pub struct NewPushRuleQuery {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub before: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RuleEnabled {
+  pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RuleActions {
+  pub actions: Vec<Action>,
+}
+
+pub fn get_push_rules(client: &MatrixClient) -> Result<PushRulesResponse> {
+  let mut response = api::get(client, ENDPOINT)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn get_push_rule(client: &MatrixClient, kind: RuleKind, rule_id: &str) -> Result<PushRule> {
+  let mut response = api::get(client, &rule_endpoint(kind, rule_id))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn delete_push_rule(client: &MatrixClient, kind: RuleKind, rule_id: &str) -> Result<()> {
+  let response = api::delete(client, &rule_endpoint(kind, rule_id))?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn set_push_rule(
+  client: &MatrixClient,
+  kind: RuleKind,
+  rule_id: &str,
+  rule: NewPushRule,
+  query: NewPushRuleQuery,
+) -> Result<()> {
+  let response = api::put_query(client, &rule_endpoint(kind, rule_id), &rule, &query)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::TOO_MANY_REQUESTS => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn get_push_rule_enabled(client: &MatrixClient, kind: RuleKind, rule_id: &str) -> Result<bool> {
+  let endpoint = format!("{}/enabled", rule_endpoint(kind, rule_id));
+  let mut response = api::get(client, &endpoint)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success: RuleEnabled = response.json()?;
+      Ok(success.enabled)
+    }
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn set_push_rule_enabled(
+  client: &MatrixClient,
+  kind: RuleKind,
+  rule_id: &str,
+  enabled: bool,
+) -> Result<()> {
+  let endpoint = format!("{}/enabled", rule_endpoint(kind, rule_id));
+  let response = api::put(client, &endpoint, &RuleEnabled { enabled })?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn get_push_rule_actions(
+  client: &MatrixClient,
+  kind: RuleKind,
+  rule_id: &str,
+) -> Result<Vec<Action>> {
+  let endpoint = format!("{}/actions", rule_endpoint(kind, rule_id));
+  let mut response = api::get(client, &endpoint)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success: RuleActions = response.json()?;
+      Ok(success.actions)
+    }
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn set_push_rule_actions(
+  client: &MatrixClient,
+  kind: RuleKind,
+  rule_id: &str,
+  actions: Vec<Action>,
+) -> Result<()> {
+  let endpoint = format!("{}/actions", rule_endpoint(kind, rule_id));
+  let response = api::put(client, &endpoint, &RuleActions { actions })?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,63 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Refresh
+Exchange a refresh token for a new access token
+
+docs: https://spec.matrix.org/v1.3/client-server-api/#post_matrixclientv3refresh
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/v3/refresh";
+
+#[derive(Serialize, Debug)]
+pub struct RefreshRequest {
+  pub refresh_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshResponse {
+  pub access_token: String,
+  // When not given the previous refresh token can be used again
+  pub refresh_token: Option<String>,
+  pub expires_in_ms: Option<i64>,
+}
+
+pub fn refresh(client: &MatrixClient, request: RefreshRequest) -> Result<RefreshResponse> {
+  // Not sent with api::post, a 401 here must not start another refresh
+  let url = format!("{}{}", client.get_base_url(), ENDPOINT);
+  let mut response = reqwest::Client::new().post(&url).json(&request).send()?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Renew the client's access token using its refresh token, returns false if it has none.
+// The refresh token is kept when renewing fails, so it can be tried again.
+pub fn refresh_client(client: &MatrixClient) -> Result<bool> {
+  let refresh_token = match client.refresh_token() {
+    Some(refresh_token) => refresh_token,
+    None => return Ok(false),
+  };
+
+  let request = RefreshRequest {
+    refresh_token: refresh_token.clone(),
+  };
+  let response = refresh(client, request)?;
+  client.update_tokens(
+    response.access_token,
+    response.refresh_token.or(Some(refresh_token)),
+  );
+
+  Ok(true)
+}
@@ -4,10 +4,14 @@ use std::collections::HashMap;
 
 use crate::api;
 use crate::api::ApiError;
+use crate::api::MatrixErrorCode;
 use crate::api::Result;
 use crate::client::MatrixClient;
 
 pub static ENDPOINT: &str = "/_matrix/client/r0/register";
+pub static AVAILABLE_ENDPOINT: &str = "/_matrix/client/r0/register/available";
+pub static TOKEN_VALIDITY_ENDPOINT: &str =
+   "/_matrix/client/v1/register/m.login.registration_token/validity";
 
 #[derive(Deserialize, Debug)]
 pub struct UserInteractiveAuthenticationModel {
@@ -28,29 +32,60 @@ pub struct Empty;
 pub struct AuthModel {
    session: String,
    r#type: String,
+   // For the m.login.registration_token stage
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub token: Option<String>,
+}
+
+impl AuthModel {
+   pub fn stage(&self) -> &str {
+      &self.r#type
+   }
 }
 
 #[derive(Serialize, Debug)]
 pub enum RegistrationKind {
+   #[serde(rename = "guest")]
    Guest,
+   #[serde(rename = "user")]
    User,
 }
 
+// The kind of account is a query parameter rather than part of the body
+#[derive(Serialize, Debug)]
+pub struct RegistrationQuery<'a> {
+   pub kind: &'a RegistrationKind,
+}
+
 #[derive(Serialize, Debug)]
 pub struct RegistrationModel {
    pub auth: AuthModel,
+   #[serde(skip)]
    pub kind: RegistrationKind,
    pub username: String,
    pub password: String,
    pub initial_device_display_name: String,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub refresh_token: Option<bool>,
+   // Set when upgrading a guest account to a full account
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub guest_access_token: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GuestRegistrationModel {
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub initial_device_display_name: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RegistrationResponse {
-   user_id: String,
-   home_server: String,
-   access_token: String,
-   device_id: String,
+   pub user_id: String,
+   pub home_server: String,
+   pub access_token: String,
+   pub device_id: String,
+   pub refresh_token: Option<String>,
+   pub expires_in_ms: Option<i64>,
 }
 
 pub fn auth_request(client: &MatrixClient) -> Result<UserInteractiveAuthenticationModel> {
@@ -68,6 +103,7 @@ pub fn auth_select_flow(model: UserInteractiveAuthenticationModel) -> AuthModel
    let auth: AuthModel = AuthModel {
       session: model.session,
       r#type: stage,
+      token: None,
    };
 
    println!(
@@ -83,7 +119,8 @@ pub fn auth_select_flow(model: UserInteractiveAuthenticationModel) -> AuthModel
 }
 
 pub fn register(client: &MatrixClient, model: RegistrationModel) -> Result<RegistrationResponse> {
-   let mut response = api::post(client, ENDPOINT, &model)?;
+   let query = RegistrationQuery { kind: &model.kind };
+   let mut response = api::post_query(client, ENDPOINT, &model, &query)?;
 
    match response.status() {
       StatusCode::OK => {
@@ -97,3 +134,108 @@ pub fn register(client: &MatrixClient, model: RegistrationModel) -> Result<Regis
       s => Err(ApiError::from(s)),
    }
 }
+
+// Register a guest account, which needs no authentication but can only use a subset of the API
+pub fn register_guest(
+   client: &MatrixClient,
+   model: GuestRegistrationModel,
+) -> Result<RegistrationResponse> {
+   let query = RegistrationQuery {
+      kind: &RegistrationKind::Guest,
+   };
+   let mut response = api::post_query(client, ENDPOINT, &model, &query)?;
+
+   match response.status() {
+      StatusCode::OK => {
+         let success = response.json()?;
+         Ok(success)
+      }
+      StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+      s => Err(ApiError::from(s)),
+   }
+}
+
+// Turn the guest account the client is logged in with into a full account, keeping its user id
+pub fn upgrade_guest(
+   client: &MatrixClient,
+   mut model: RegistrationModel,
+) -> Result<RegistrationResponse> {
+   model.kind = RegistrationKind::User;
+   model.guest_access_token = client.get_raw_access_token();
+
+   register(client, model)
+}
+
+#[derive(Serialize, Debug)]
+pub struct AvailabilityQuery<'a> {
+   pub username: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AvailabilityResponse {
+   pub available: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UsernameAvailability {
+   Available,
+   // M_USER_IN_USE
+   InUse,
+   // M_INVALID_USERNAME
+   Invalid,
+   // M_EXCLUSIVE, reserved by an application service
+   Exclusive,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TokenValidityQuery<'a> {
+   pub token: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenValidityResponse {
+   pub valid: bool,
+}
+
+// Check whether a username can be registered, before asking for the rest of the details
+pub fn check_username(client: &MatrixClient, username: &str) -> Result<UsernameAvailability> {
+   let query = AvailabilityQuery { username };
+   let mut response = api::get_query(client, AVAILABLE_ENDPOINT, &query)?;
+
+   match response.status() {
+      StatusCode::OK => {
+         let success: AvailabilityResponse = response.json()?;
+         if success.available {
+            Ok(UsernameAvailability::Available)
+         } else {
+            Ok(UsernameAvailability::InUse)
+         }
+      }
+      StatusCode::BAD_REQUEST => match ApiError::from(response) {
+         ApiError::Response(_, error) => match error.code {
+            MatrixErrorCode::UserInUse => Ok(UsernameAvailability::InUse),
+            MatrixErrorCode::InvalidUsername => Ok(UsernameAvailability::Invalid),
+            MatrixErrorCode::Exclusive => Ok(UsernameAvailability::Exclusive),
+            _ => Err(ApiError::Response(400, error)),
+         },
+         e => Err(e),
+      },
+      StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+      s => Err(ApiError::from(s)),
+   }
+}
+
+// Check a registration token for the m.login.registration_token stage without using it up
+pub fn check_registration_token(client: &MatrixClient, token: &str) -> Result<bool> {
+   let query = TokenValidityQuery { token };
+   let mut response = api::get_query(client, TOKEN_VALIDITY_ENDPOINT, &query)?;
+
+   match response.status() {
+      StatusCode::OK => {
+         let success: TokenValidityResponse = response.json()?;
+         Ok(success.valid)
+      }
+      StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+      s => Err(ApiError::from(s)),
+   }
+}
@@ -0,0 +1,58 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::RoomEvent;
+
+/*
+Event Context
+Returns a number of events before and after the given event, along with the room state at that point
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-rooms-roomid-context-eventid
+*/
+
+pub fn endpoint(room_id: &str, event_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/context/{}",
+    api::encode(room_id),
+    api::encode(event_id)
+  )
+}
+
+#[derive(Serialize, Debug)]
+pub struct ContextQuery {
+  pub limit: Option<i64>,
+  // JSON encoded RoomEventFilter
+  pub filter: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContextResponse {
+  pub start: Option<String>,
+  pub end: Option<String>,
+  pub events_before: Vec<RoomEvent>,
+  pub event: RoomEvent,
+  pub events_after: Vec<RoomEvent>,
+  pub state: Vec<RoomEvent>,
+}
+
+pub fn get_context(
+  client: &MatrixClient,
+  room_id: &str,
+  event_id: &str,
+  query: ContextQuery,
+) -> Result<ContextResponse> {
+  let mut response = api::get_query(client, &endpoint(room_id, event_id), &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -1,5 +1,6 @@
 use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 
 use crate::api;
@@ -45,6 +46,10 @@ pub struct CreationContent {
   pub federate: Option<bool>,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub predecessor: Option<PreviousRoom>,
+  // e.g. m.space, left out for ordinary rooms
+  #[serde(rename = "type")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_type: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -52,8 +57,7 @@ pub struct StateEvent {
   pub r#type: String,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub state_key: Option<String>,
-  // TODO: Incorrect type definition, need to confirm what 'object' is
-  pub content: String,
+  pub content: Value,
 }
 
 #[derive(Serialize, Debug)]
@@ -129,7 +133,7 @@ pub struct CreateRoomRequest {
 
 #[derive(Deserialize, Debug)]
 pub struct CreateRoomResponse {
-  room_id: String,
+  pub room_id: String,
 }
 
 pub fn create_room(
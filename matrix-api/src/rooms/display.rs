@@ -0,0 +1,296 @@
+use crate::events::{MemberContent, Membership, RoomEvent};
+use crate::sync::RoomSummary;
+
+/*
+Room Display
+Names and avatars to show for rooms and their members, worked out from the room's state
+
+docs: https://matrix.org/docs/spec/client_server/latest#calculating-the-display-name-for-a-room
+*/
+
+// Number of members used to name a room which has no summary
+static MAX_HEROES: usize = 5;
+
+// The current state of a room, as needed to display it
+#[derive(Debug, Clone)]
+pub struct RoomState {
+  pub room_id: String,
+  pub events: Vec<RoomEvent>,
+  pub summary: RoomSummary,
+}
+
+impl RoomState {
+  pub fn new(room_id: &str, events: Vec<RoomEvent>, summary: RoomSummary) -> RoomState {
+    RoomState {
+      room_id: room_id.to_string(),
+      events,
+      summary,
+    }
+  }
+
+  pub fn state_event(&self, event_type: &str, state_key: &str) -> Option<&RoomEvent> {
+    self
+      .events
+      .iter()
+      .find(|e| e.r#type == event_type && e.state_key.as_deref() == Some(state_key))
+  }
+
+  pub fn member(&self, user_id: &str) -> Option<MemberContent> {
+    self
+      .state_event("m.room.member", user_id)
+      .and_then(RoomEvent::member_content)
+  }
+
+  fn members(&self) -> impl Iterator<Item = (&str, MemberContent)> {
+    self
+      .events
+      .iter()
+      .filter_map(|e| match (&e.state_key, e.member_content()) {
+        (Some(user_id), Some(content)) => Some((user_id.as_str(), content)),
+        _ => None,
+      })
+  }
+
+  fn member_count(&self, membership: Membership) -> u64 {
+    self
+      .members()
+      .filter(|(_, content)| content.membership == membership)
+      .count() as u64
+  }
+
+  // Other members to name the room after, from the summary or picked from the state
+  fn heroes(&self, own_user_id: &str) -> Vec<String> {
+    if let Some(heroes) = &self.summary.heroes {
+      return heroes.clone();
+    }
+
+    let pick = |memberships: &[Membership]| {
+      let mut heroes: Vec<String> = self
+        .members()
+        .filter(|(user_id, content)| {
+          *user_id != own_user_id && memberships.contains(&content.membership)
+        })
+        .map(|(user_id, _)| user_id.to_string())
+        .collect();
+      heroes.sort();
+      heroes.truncate(MAX_HEROES);
+      heroes
+    };
+
+    // An empty room is named after the members who used to be in it
+    let heroes = pick(&[Membership::Join, Membership::Invite]);
+    if heroes.is_empty() {
+      pick(&[Membership::Leave, Membership::Ban])
+    } else {
+      heroes
+    }
+  }
+
+  // The member's display name, followed by their user id if another member shares it
+  pub fn member_display_name(&self, user_id: &str) -> String {
+    let name = match self.member(user_id).and_then(|m| m.displayname) {
+      Some(name) if !name.is_empty() => name,
+      _ => return user_id.to_string(),
+    };
+
+    let ambiguous = self.members().any(|(other_id, content)| {
+      other_id != user_id
+        && (content.membership == Membership::Join || content.membership == Membership::Invite)
+        && content.displayname.as_deref() == Some(name.as_str())
+    });
+
+    if ambiguous {
+      format!("{} ({})", name, user_id)
+    } else {
+      name
+    }
+  }
+
+  pub fn display_name(&self, own_user_id: &str) -> String {
+    if let Some(name) = self
+      .state_event("m.room.name", "")
+      .and_then(|e| e.content["name"].as_str())
+      .filter(|name| !name.is_empty())
+    {
+      return name.to_string();
+    }
+
+    if let Some(alias) = self
+      .state_event("m.room.canonical_alias", "")
+      .and_then(|e| e.content["alias"].as_str())
+      .filter(|alias| !alias.is_empty())
+    {
+      return alias.to_string();
+    }
+
+    let joined = self
+      .summary
+      .joined_member_count
+      .unwrap_or_else(|| self.member_count(Membership::Join));
+    let invited = self
+      .summary
+      .invited_member_count
+      .unwrap_or_else(|| self.member_count(Membership::Invite));
+    let total = joined + invited;
+
+    let names: Vec<String> = self
+      .heroes(own_user_id)
+      .iter()
+      .map(|hero| self.member_display_name(hero))
+      .collect();
+
+    if names.is_empty() {
+      return String::from("Empty Room");
+    }
+
+    if total <= 1 {
+      format!("Empty Room (was {})", join_names(&names, 0))
+    } else {
+      let others = (total - 1).saturating_sub(names.len() as u64);
+      join_names(&names, others)
+    }
+  }
+
+  // The room's avatar, or the other member's avatar for a room between two people
+  pub fn avatar_url(&self, own_user_id: &str) -> Option<String> {
+    if let Some(url) = self
+      .state_event("m.room.avatar", "")
+      .and_then(|e| e.content["url"].as_str())
+    {
+      return Some(url.to_string());
+    }
+
+    let heroes = self.heroes(own_user_id);
+    if heroes.len() == 1 {
+      self.member(&heroes[0]).and_then(|m| m.avatar_url)
+    } else {
+      None
+    }
+  }
+}
+
+// "Alice", "Alice and Bob", "Alice, Bob and Charlie" or "Alice, Bob and 3 others"
+fn join_names(names: &[String], others: u64) -> String {
+  let mut parts = names.to_vec();
+  match others {
+    0 => (),
+    1 => parts.push(String::from("1 other")),
+    n => parts.push(format!("{} others", n)),
+  }
+
+  match parts.split_last() {
+    Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+    Some((last, _)) => last.clone(),
+    None => String::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+
+  static ALICE: &str = "@alice:example.org";
+  static BOB: &str = "@bob:example.org";
+  static CHARLIE: &str = "@charlie:example.org";
+
+  fn state(event_type: &str, state_key: &str, content: Value) -> RoomEvent {
+    RoomEvent {
+      content,
+      r#type: event_type.to_string(),
+      event_id: format!("${}{}", event_type, state_key),
+      sender: ALICE.to_string(),
+      origin_server_ts: 0,
+      unsigned: None,
+      room_id: None,
+      state_key: Some(state_key.to_string()),
+    }
+  }
+
+  fn member(user_id: &str, membership: &str, displayname: &str) -> RoomEvent {
+    state(
+      "m.room.member",
+      user_id,
+      json!({ "membership": membership, "displayname": displayname }),
+    )
+  }
+
+  fn room(events: Vec<RoomEvent>) -> RoomState {
+    RoomState::new("!room:example.org", events, RoomSummary::default())
+  }
+
+  #[test]
+  fn name_comes_before_alias() {
+    let alias = state(
+      "m.room.canonical_alias",
+      "",
+      json!({ "alias": "#room:example.org" }),
+    );
+    let name = state("m.room.name", "", json!({ "name": "The Room" }));
+
+    assert_eq!(
+      room(vec![alias.clone(), name]).display_name(ALICE),
+      "The Room"
+    );
+    assert_eq!(room(vec![alias]).display_name(ALICE), "#room:example.org");
+  }
+
+  #[test]
+  fn unnamed_room_is_named_after_members() {
+    let members = vec![
+      member(ALICE, "join", "Alice"),
+      member(BOB, "join", "Bob"),
+      member(CHARLIE, "invite", "Charlie"),
+    ];
+
+    assert_eq!(room(members).display_name(ALICE), "Bob and Charlie");
+  }
+
+  #[test]
+  fn summary_heroes_are_named_with_the_other_members() {
+    let mut room = room(vec![
+      member(BOB, "join", "Bob"),
+      member(CHARLIE, "join", "Charlie"),
+    ]);
+    room.summary = RoomSummary {
+      heroes: Some(vec![BOB.to_string(), CHARLIE.to_string()]),
+      joined_member_count: Some(8),
+      invited_member_count: Some(2),
+    };
+
+    assert_eq!(room.display_name(ALICE), "Bob, Charlie and 7 others");
+  }
+
+  #[test]
+  fn empty_room_is_named_after_former_members() {
+    let alone = vec![member(ALICE, "join", "Alice")];
+    let mut left = alone.clone();
+    left.push(member(BOB, "leave", "Bob"));
+
+    assert_eq!(room(alone).display_name(ALICE), "Empty Room");
+    assert_eq!(room(left).display_name(ALICE), "Empty Room (was Bob)");
+  }
+
+  #[test]
+  fn shared_display_names_are_told_apart() {
+    let members = vec![
+      member(ALICE, "join", "Alice"),
+      member(BOB, "join", "Bob"),
+      member(CHARLIE, "join", "Bob"),
+    ];
+    let mut charlie_left = members.clone();
+    charlie_left[2] = member(CHARLIE, "leave", "Bob");
+
+    let room_state = room(members);
+    assert_eq!(
+      room_state.member_display_name(BOB),
+      "Bob (@bob:example.org)"
+    );
+    assert_eq!(
+      room_state.display_name(ALICE),
+      "Bob (@bob:example.org) and Bob (@charlie:example.org)"
+    );
+    assert_eq!(room(charlie_left).member_display_name(BOB), "Bob");
+    assert_eq!(room_state.member_display_name(ALICE), "Alice");
+  }
+}
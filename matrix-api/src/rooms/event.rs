@@ -0,0 +1,35 @@
+use reqwest::StatusCode;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::RoomEvent;
+
+/*
+Get Event
+Fetch a single event from a room by its id
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-rooms-roomid-event-eventid
+*/
+
+pub fn endpoint(room_id: &str, event_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/event/{}",
+    api::encode(room_id),
+    api::encode(event_id)
+  )
+}
+
+pub fn get_event(client: &MatrixClient, room_id: &str, event_id: &str) -> Result<RoomEvent> {
+  let mut response = api::get(client, &endpoint(room_id, event_id))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
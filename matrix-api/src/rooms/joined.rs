@@ -0,0 +1,34 @@
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Joined Rooms
+List the rooms the current user has joined
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-joined-rooms
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/joined_rooms";
+
+#[derive(Deserialize, Debug)]
+pub struct JoinedRoomsResponse {
+  pub joined_rooms: Vec<String>,
+}
+
+pub fn get_joined_rooms(client: &MatrixClient) -> Result<JoinedRoomsResponse> {
+  let mut response = api::get(client, ENDPOINT)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
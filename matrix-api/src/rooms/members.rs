@@ -0,0 +1,82 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::{Membership, RoomEvent};
+
+/*
+Room Members
+List the membership events or currently joined members of a room
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-rooms-roomid-members
+*/
+
+pub fn members_endpoint(room_id: &str) -> String {
+  format!("/_matrix/client/r0/rooms/{}/members", api::encode(room_id))
+}
+
+pub fn joined_members_endpoint(room_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/joined_members",
+    api::encode(room_id)
+  )
+}
+
+#[derive(Serialize, Debug)]
+pub struct MembersQuery {
+  // Sync token to return the members as they were at that point
+  pub at: Option<String>,
+  pub membership: Option<Membership>,
+  pub not_membership: Option<Membership>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MembersResponse {
+  pub chunk: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoomMember {
+  pub display_name: Option<String>,
+  pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JoinedMembersResponse {
+  // Mapping from user id's to their profile in the room
+  pub joined: HashMap<String, RoomMember>,
+}
+
+pub fn get_members(
+  client: &MatrixClient,
+  room_id: &str,
+  query: MembersQuery,
+) -> Result<MembersResponse> {
+  let mut response = api::get_query(client, &members_endpoint(room_id), &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn get_joined_members(client: &MatrixClient, room_id: &str) -> Result<JoinedMembersResponse> {
+  let mut response = api::get(client, &joined_members_endpoint(room_id))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -1,2 +1,11 @@
+pub mod context;
 pub mod create;
+pub mod display;
+pub mod event;
+pub mod joined;
+pub mod members;
+pub mod peek;
 pub mod public;
+pub mod send;
+pub mod spaces;
+pub mod state;
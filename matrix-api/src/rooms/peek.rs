@@ -0,0 +1,76 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::{Membership, RoomEvent};
+
+/*
+Peeking
+Read a world readable room without joining it, which is how guests browse rooms
+
+docs: https://matrix.org/docs/spec/client_server/latest#peeking
+*/
+
+pub static EVENTS_ENDPOINT: &str = "/_matrix/client/r0/events";
+
+pub fn initial_sync_endpoint(room_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/initialSync",
+    api::encode(room_id)
+  )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PaginationChunk {
+  pub start: String,
+  pub end: String,
+  pub chunk: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoomInitialSync {
+  pub room_id: String,
+  // The user's membership, if they have one
+  pub membership: Option<Membership>,
+  // The most recent messages, `end` is the token to peek for new events from
+  pub messages: Option<PaginationChunk>,
+  pub state: Option<Vec<RoomEvent>>,
+  pub visibility: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PeekEventsQuery {
+  pub room_id: String,
+  pub from: Option<String>,
+  // Milliseconds to wait for new events before returning
+  pub timeout: Option<i64>,
+}
+
+pub fn initial_sync(client: &MatrixClient, room_id: &str) -> Result<RoomInitialSync> {
+  let mut response = api::get(client, &initial_sync_endpoint(room_id))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn peek_events(client: &MatrixClient, query: PeekEventsQuery) -> Result<PaginationChunk> {
+  let mut response = api::get_query(client, EVENTS_ENDPOINT, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -28,17 +28,32 @@ pub struct PublicRoomsResponse {
 
 #[derive(Deserialize, Debug)]
 pub struct PublicRoomsChunk {
+  #[serde(default)]
   pub aliases: Vec<String>,
-  pub canonical_alias: String,
-  pub name: String,
+  pub canonical_alias: Option<String>,
+  pub name: Option<String>,
   pub num_joined_members: i64,
   pub room_id: String,
-  pub topic: String,
+  pub topic: Option<String>,
   pub world_readable: bool,
   pub guest_can_join: bool,
   pub avatar_url: Option<String>,
 }
 
+impl PublicRoomsChunk {
+  // The directory has no member state, so fall back to the aliases then the room id
+  pub fn display_name(&self) -> String {
+    self
+      .name
+      .iter()
+      .chain(self.canonical_alias.iter())
+      .chain(self.aliases.iter())
+      .find(|name| !name.is_empty())
+      .cloned()
+      .unwrap_or_else(|| self.room_id.clone())
+  }
+}
+
 pub fn list_public_rooms(
   client: &MatrixClient,
   query: PublicRoomsQuery,
@@ -0,0 +1,58 @@
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+Send Event
+Send a message event to a room. Retrying with the same transaction id is safe,
+the server returns the original event instead of sending it twice.
+
+docs: https://matrix.org/docs/spec/client_server/latest#put-matrix-client-r0-rooms-roomid-send-eventtype-txnid
+*/
+
+pub fn endpoint(room_id: &str, event_type: &str, txn_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/send/{}/{}",
+    api::encode(room_id),
+    api::encode(event_type),
+    api::encode(txn_id)
+  )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SendResponse {
+  pub event_id: String,
+}
+
+// Content of a plain text m.room.message
+pub fn text_content(body: &str) -> Value {
+  json!({ "msgtype": "m.text", "body": body })
+}
+
+pub fn send_event(
+  client: &MatrixClient,
+  room_id: &str,
+  event_type: &str,
+  txn_id: &str,
+  content: &Value,
+) -> Result<SendResponse> {
+  let mut response = api::put(client, &endpoint(room_id, event_type, txn_id), content)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST
+    | StatusCode::UNAUTHORIZED
+    | StatusCode::FORBIDDEN
+    | StatusCode::NOT_FOUND
+    | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
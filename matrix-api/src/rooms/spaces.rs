@@ -0,0 +1,188 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::rooms::send::SendResponse;
+use crate::rooms::state;
+use crate::sync::StrippedStateEvent;
+
+/*
+Spaces
+A space is a room created with the m.space type, whose m.space.child state events list the
+rooms in it. Rooms can point back with m.space.parent. The hierarchy endpoint walks the
+children, including rooms the user hasn't joined.
+
+docs: https://spec.matrix.org/v1.2/client-server-api/#spaces
+*/
+
+pub static SPACE_TYPE: &str = "m.space";
+pub static CHILD_EVENT: &str = "m.space.child";
+pub static PARENT_EVENT: &str = "m.space.parent";
+
+// There is no r0 version of the hierarchy endpoint
+pub fn hierarchy_endpoint(room_id: &str) -> String {
+  format!(
+    "/_matrix/client/v1/rooms/{}/hierarchy",
+    api::encode(room_id)
+  )
+}
+
+// Content of an m.space.child event, the state key is the child's room id. A child without
+// via has been removed from the space.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SpaceChildContent {
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub via: Vec<String>,
+  // Children are sorted by this, then by room id
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub order: Option<String>,
+  #[serde(default)]
+  pub suggested: bool,
+}
+
+// Content of an m.space.parent event, the state key is the parent's room id
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SpaceParentContent {
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub via: Vec<String>,
+  #[serde(default)]
+  pub canonical: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct HierarchyQuery {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub from: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<u32>,
+  // How many levels of children to go down, the server picks when left out
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_depth: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub suggested_only: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpaceHierarchyRoom {
+  pub room_id: String,
+  pub name: Option<String>,
+  pub topic: Option<String>,
+  pub canonical_alias: Option<String>,
+  pub avatar_url: Option<String>,
+  pub num_joined_members: i64,
+  pub world_readable: bool,
+  pub guest_can_join: bool,
+  pub join_rule: Option<String>,
+  pub room_type: Option<String>,
+  // The room's m.space.child events
+  #[serde(default)]
+  pub children_state: Vec<StrippedStateEvent>,
+}
+
+impl SpaceHierarchyRoom {
+  pub fn is_space(&self) -> bool {
+    self.room_type.as_deref() == Some(SPACE_TYPE)
+  }
+
+  // Room ids of the children which haven't been removed
+  pub fn children(&self) -> Vec<String> {
+    self
+      .children_state
+      .iter()
+      .filter(|event| event.r#type == CHILD_EVENT && event.content["via"].is_array())
+      .map(|event| event.state_key.clone())
+      .collect()
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HierarchyResponse {
+  // The requested room first, then its children depth first
+  pub rooms: Vec<SpaceHierarchyRoom>,
+  pub next_batch: Option<String>,
+}
+
+// The server part of a room id, to use as via when the room is on our own server
+pub fn server_name(room_id: &str) -> Option<&str> {
+  room_id.split_once(':').map(|(_, server_name)| server_name)
+}
+
+pub fn get_hierarchy(
+  client: &MatrixClient,
+  room_id: &str,
+  query: &HierarchyQuery,
+) -> Result<HierarchyResponse> {
+  let mut response = api::get_query(client, &hierarchy_endpoint(room_id), query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST
+    | StatusCode::FORBIDDEN
+    | StatusCode::NOT_FOUND
+    | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Every room in the space down to max_depth, following next_batch until the end
+pub fn full_hierarchy(
+  client: &MatrixClient,
+  room_id: &str,
+  max_depth: Option<u32>,
+) -> Result<Vec<SpaceHierarchyRoom>> {
+  let mut query = HierarchyQuery {
+    max_depth,
+    ..Default::default()
+  };
+  let mut rooms = Vec::new();
+
+  loop {
+    let response = get_hierarchy(client, room_id, &query)?;
+    rooms.extend(response.rooms);
+    match response.next_batch {
+      Some(next_batch) => query.from = Some(next_batch),
+      None => return Ok(rooms),
+    }
+  }
+}
+
+// Add the room to the space, or update how it's listed
+pub fn add_child(
+  client: &MatrixClient,
+  space_id: &str,
+  child_id: &str,
+  content: &SpaceChildContent,
+) -> Result<SendResponse> {
+  state::send_state_event(client, space_id, CHILD_EVENT, child_id, &json!(content))
+}
+
+// State events can't be deleted, so the child is replaced with empty content
+pub fn remove_child(client: &MatrixClient, space_id: &str, child_id: &str) -> Result<SendResponse> {
+  state::send_state_event(client, space_id, CHILD_EVENT, child_id, &json!({}))
+}
+
+pub fn set_parent(
+  client: &MatrixClient,
+  room_id: &str,
+  parent_id: &str,
+  content: &SpaceParentContent,
+) -> Result<SendResponse> {
+  state::send_state_event(client, room_id, PARENT_EVENT, parent_id, &json!(content))
+}
+
+pub fn remove_parent(
+  client: &MatrixClient,
+  room_id: &str,
+  parent_id: &str,
+) -> Result<SendResponse> {
+  state::send_state_event(client, room_id, PARENT_EVENT, parent_id, &json!({}))
+}
@@ -0,0 +1,67 @@
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::rooms::send::SendResponse;
+
+/*
+Room State
+Get and set a room's state events, keyed by event type and state key. Setting the same type
+and key again replaces the event.
+
+docs: https://matrix.org/docs/spec/client_server/latest#put-matrix-client-r0-rooms-roomid-state-eventtype-statekey
+*/
+
+pub fn endpoint(room_id: &str, event_type: &str, state_key: &str) -> String {
+  format!(
+    "/_matrix/client/r0/rooms/{}/state/{}/{}",
+    api::encode(room_id),
+    api::encode(event_type),
+    api::encode(state_key)
+  )
+}
+
+pub fn send_state_event(
+  client: &MatrixClient,
+  room_id: &str,
+  event_type: &str,
+  state_key: &str,
+  content: &Value,
+) -> Result<SendResponse> {
+  let mut response = api::put(client, &endpoint(room_id, event_type, state_key), content)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST
+    | StatusCode::UNAUTHORIZED
+    | StatusCode::FORBIDDEN
+    | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Content of the state event, None if the room has no such state
+pub fn get_state_event(
+  client: &MatrixClient,
+  room_id: &str,
+  event_type: &str,
+  state_key: &str,
+) -> Result<Option<Value>> {
+  let mut response = api::get(client, &endpoint(room_id, event_type, state_key))?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(Some(success))
+    }
+    StatusCode::NOT_FOUND => Ok(None),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
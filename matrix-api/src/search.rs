@@ -0,0 +1,193 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::RoomEvent;
+
+/*
+Search
+Server side search of room events
+
+docs: https://matrix.org/docs/spec/client_server/latest#post-matrix-client-r0-search
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/search";
+
+#[derive(Serialize, Debug)]
+pub struct SearchQuery {
+  // Point to continue from, taken from a previous response's next_batch
+  pub next_batch: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub enum SearchKey {
+  #[serde(rename = "content.body")]
+  ContentBody,
+  #[serde(rename = "content.name")]
+  ContentName,
+  #[serde(rename = "content.topic")]
+  ContentTopic,
+}
+
+#[derive(Serialize, Debug)]
+pub enum OrderBy {
+  #[serde(rename = "rank")]
+  Rank,
+  #[serde(rename = "recent")]
+  Recent,
+}
+
+#[derive(Serialize, Debug)]
+pub enum GroupKey {
+  #[serde(rename = "room_id")]
+  RoomId,
+  #[serde(rename = "sender")]
+  Sender,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Group {
+  pub key: GroupKey,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Groupings {
+  pub group_by: Vec<Group>,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#filtering
+#[derive(Serialize, Debug, Default)]
+pub struct RoomEventFilter {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub not_senders: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub not_types: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub senders: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub types: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rooms: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub not_rooms: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub contains_url: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EventContext {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub before_limit: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub after_limit: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub include_profile: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomEventsCriteria {
+  pub search_term: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub keys: Option<Vec<SearchKey>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub filter: Option<RoomEventFilter>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub order_by: Option<OrderBy>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_context: Option<EventContext>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub include_state: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub groupings: Option<Groupings>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchCategories {
+  pub room_events: RoomEventsCriteria,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchRequest {
+  pub search_categories: SearchCategories,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserProfile {
+  pub displayname: Option<String>,
+  pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventContextResult {
+  pub start: Option<String>,
+  pub end: Option<String>,
+  // Mapping from user id's to their profile at the time of the event
+  pub profile_info: Option<HashMap<String, UserProfile>>,
+  #[serde(default)]
+  pub events_before: Vec<RoomEvent>,
+  #[serde(default)]
+  pub events_after: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResult {
+  pub rank: Option<f64>,
+  pub result: RoomEvent,
+  pub context: Option<EventContextResult>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GroupValue {
+  pub next_batch: Option<String>,
+  pub order: Option<i64>,
+  #[serde(default)]
+  pub results: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoomEventsResults {
+  pub count: Option<i64>,
+  // Words which should be highlighted in the results
+  #[serde(default)]
+  pub highlights: Vec<String>,
+  #[serde(default)]
+  pub results: Vec<SearchResult>,
+  // Mapping from room id's to their current state, if include_state was set
+  pub state: Option<HashMap<String, Vec<RoomEvent>>>,
+  // Mapping from group key (room_id/sender) to group value to results
+  pub groups: Option<HashMap<String, HashMap<String, GroupValue>>>,
+  pub next_batch: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResultCategories {
+  pub room_events: Option<RoomEventsResults>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResponse {
+  pub search_categories: ResultCategories,
+}
+
+pub fn search(
+  client: &MatrixClient,
+  query: SearchQuery,
+  request: SearchRequest,
+) -> Result<SearchResponse> {
+  let mut response = api::post_query(client, ENDPOINT, &request, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
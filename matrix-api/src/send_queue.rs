@@ -0,0 +1,360 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::api::ApiError;
+use crate::client::MatrixClient;
+use crate::crypto;
+use crate::crypto::machine::OlmMachine;
+use crate::crypto::{CryptoError, EncryptionSettings};
+use crate::events::{Membership, RoomEvent, UnsignedData};
+use crate::rooms::send;
+use crate::rooms::send::SendResponse;
+use crate::store;
+use crate::store::StateStore;
+use crate::sync::SyncResponse;
+
+/*
+Send Queue
+Outgoing messages are saved in the state store before they are sent, so they survive going
+offline, rate limits and restarts. Each keeps its transaction id across retries, which lets the
+server drop duplicates and lets us match it to its remote echo in sync.
+
+Events are sent in order per room, a room's later events wait while an earlier one is retrying.
+Events for rooms with encryption turned on are encrypted just before each attempt, so they are
+stored in plain text.
+*/
+
+// An event which hits a server or Olm error after this many attempts is marked as failed.
+// Network errors and rate limits are retried until they clear up.
+pub static MAX_ATTEMPTS: u32 = 5;
+
+static INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+static MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingEvent {
+  pub txn_id: String,
+  pub room_id: String,
+  pub event_type: String,
+  pub content: Value,
+  pub sender: String,
+  pub created_ts: i64,
+  pub attempts: u32,
+  // Why the event could not be sent, it won't be retried until `SendQueue::retry`
+  pub failure: Option<String>,
+}
+
+impl PendingEvent {
+  // Event to show in the room's timeline until the real one comes down sync
+  pub fn local_echo(&self) -> RoomEvent {
+    RoomEvent {
+      content: self.content.clone(),
+      r#type: self.event_type.clone(),
+      event_id: local_event_id(&self.txn_id),
+      sender: self.sender.clone(),
+      origin_server_ts: self.created_ts,
+      unsigned: Some(UnsignedData {
+        age: None,
+        redacted_because: None,
+        transaction_id: Some(self.txn_id.clone()),
+        prev_content: None,
+      }),
+      room_id: Some(self.room_id.clone()),
+      state_key: None,
+    }
+  }
+}
+
+// Local echoes get a placeholder id, the real one is only known once the server has the event
+pub fn local_event_id(txn_id: &str) -> String {
+  format!("~{}", txn_id)
+}
+
+#[derive(Debug)]
+pub enum SendOutcome {
+  Sent {
+    txn_id: String,
+    event_id: String,
+  },
+  // Will be tried again after the delay
+  Retrying {
+    txn_id: String,
+    error: String,
+    delay: Duration,
+  },
+  Failed {
+    txn_id: String,
+    error: String,
+  },
+}
+
+#[derive(Default)]
+pub struct SendQueue {
+  // When each retrying event may next be sent, not persisted so a restart retries straight away
+  next_attempt: HashMap<String, Instant>,
+}
+
+impl SendQueue {
+  pub fn new() -> SendQueue {
+    SendQueue {
+      next_attempt: HashMap::new(),
+    }
+  }
+
+  // Save an event to be sent and return its local echo, sender is the logged in user
+  pub fn enqueue(
+    &mut self,
+    store: &mut dyn StateStore,
+    sender: &str,
+    room_id: &str,
+    event_type: &str,
+    content: Value,
+  ) -> store::Result<RoomEvent> {
+    let pending = PendingEvent {
+      txn_id: new_txn_id(),
+      room_id: room_id.to_string(),
+      event_type: event_type.to_string(),
+      content,
+      sender: sender.to_string(),
+      created_ts: now_ms(),
+      attempts: 0,
+      failure: None,
+    };
+    store.save_pending_event(&pending)?;
+
+    Ok(pending.local_echo())
+  }
+
+  // Try to send every event which is due, oldest first. Events for encrypted rooms are marked
+  // as failed without an OlmMachine, `retry` them once encryption is set up.
+  pub fn process(
+    &mut self,
+    client: &MatrixClient,
+    store: &mut dyn StateStore,
+    mut crypto: Option<&mut OlmMachine>,
+  ) -> store::Result<Vec<SendOutcome>> {
+    let mut outcomes = Vec::new();
+    let mut blocked_rooms = HashSet::new();
+    let now = Instant::now();
+
+    for mut pending in store.pending_events()? {
+      if pending.failure.is_some() || blocked_rooms.contains(&pending.room_id) {
+        blocked_rooms.insert(pending.room_id.clone());
+        continue;
+      }
+      if let Some(next_attempt) = self.next_attempt.get(&pending.txn_id) {
+        if *next_attempt > now {
+          blocked_rooms.insert(pending.room_id.clone());
+          continue;
+        }
+      }
+
+      let encryption = store.state_event(&pending.room_id, "m.room.encryption", "")?;
+      let result = match encryption {
+        Some(encryption) => {
+          let mut members = Vec::new();
+          for membership in [Membership::Join, Membership::Invite].iter().cloned() {
+            let events = store.members(&pending.room_id, membership)?;
+            members.extend(events.into_iter().filter_map(|event| event.state_key));
+          }
+          send_encrypted(
+            client,
+            crypto.as_deref_mut(),
+            &pending,
+            encryption.content,
+            &members,
+          )
+        }
+        None => send::send_event(
+          client,
+          &pending.room_id,
+          &pending.event_type,
+          &pending.txn_id,
+          &pending.content,
+        )
+        .map_err(CryptoError::from),
+      };
+      let error = match result {
+        Ok(response) => {
+          store.remove_pending_event(&pending.txn_id)?;
+          self.next_attempt.remove(&pending.txn_id);
+          outcomes.push(SendOutcome::Sent {
+            txn_id: pending.txn_id,
+            event_id: response.event_id,
+          });
+          continue;
+        }
+        Err(error) => error,
+      };
+
+      blocked_rooms.insert(pending.room_id.clone());
+      let message = error.to_string();
+      let retry = match retry_kind(&error) {
+        Retry::Transient(delay) => Some(delay),
+        Retry::Counted if pending.attempts + 1 < MAX_ATTEMPTS => Some(None),
+        Retry::Counted | Retry::Never => None,
+      };
+      pending.attempts += 1;
+
+      match retry {
+        Some(delay) => {
+          let delay = delay.unwrap_or_else(|| backoff(pending.attempts));
+          self
+            .next_attempt
+            .insert(pending.txn_id.clone(), now + delay);
+          store.save_pending_event(&pending)?;
+          outcomes.push(SendOutcome::Retrying {
+            txn_id: pending.txn_id,
+            error: message,
+            delay,
+          });
+        }
+        None => {
+          self.next_attempt.remove(&pending.txn_id);
+          pending.failure = Some(message.clone());
+          store.save_pending_event(&pending)?;
+          outcomes.push(SendOutcome::Failed {
+            txn_id: pending.txn_id,
+            error: message,
+          });
+        }
+      }
+    }
+
+    Ok(outcomes)
+  }
+
+  // Drop pending events whose remote echo arrived in sync, in case the response to the
+  // send itself was lost. Only the sending device sees the transaction id of an event.
+  // Returns the transaction and event ids which were matched.
+  pub fn reconcile(
+    &mut self,
+    store: &mut dyn StateStore,
+    response: &SyncResponse,
+  ) -> store::Result<Vec<(String, String)>> {
+    let pending: HashSet<String> = store
+      .pending_events()?
+      .into_iter()
+      .map(|p| p.txn_id)
+      .collect();
+    let mut matched = Vec::new();
+
+    for room in response.rooms.join.values() {
+      for event in &room.timeline.events {
+        let txn_id = match event
+          .unsigned
+          .as_ref()
+          .and_then(|u| u.transaction_id.as_ref())
+        {
+          Some(txn_id) if pending.contains(txn_id) => txn_id,
+          _ => continue,
+        };
+        store.remove_pending_event(txn_id)?;
+        self.next_attempt.remove(txn_id);
+        matched.push((txn_id.clone(), event.event_id.clone()));
+      }
+    }
+
+    Ok(matched)
+  }
+
+  // Clear a failure so the event is sent on the next `process`
+  pub fn retry(&mut self, store: &mut dyn StateStore, txn_id: &str) -> store::Result<()> {
+    if let Some(mut pending) = store
+      .pending_events()?
+      .into_iter()
+      .find(|p| p.txn_id == txn_id)
+    {
+      pending.failure = None;
+      pending.attempts = 0;
+      self.next_attempt.remove(txn_id);
+      store.save_pending_event(&pending)?;
+    }
+    Ok(())
+  }
+
+  // Give up on an event, e.g. after the user deletes a failed message
+  pub fn discard(&mut self, store: &mut dyn StateStore, txn_id: &str) -> store::Result<()> {
+    self.next_attempt.remove(txn_id);
+    store.remove_pending_event(txn_id)
+  }
+}
+
+// Share the room key with the members' devices and send the event encrypted with it
+fn send_encrypted(
+  client: &MatrixClient,
+  crypto: Option<&mut OlmMachine>,
+  pending: &PendingEvent,
+  encryption: Value,
+  members: &[String],
+) -> crypto::Result<SendResponse> {
+  let machine = crypto.ok_or(CryptoError::NotSetUp)?;
+  let settings: EncryptionSettings = serde_json::from_value(encryption)?;
+
+  machine.share_room_key(client, &pending.room_id, members, &settings)?;
+  let content =
+    machine.encrypt_room_event(&pending.room_id, &pending.event_type, &pending.content)?;
+  let response = send::send_event(
+    client,
+    &pending.room_id,
+    "m.room.encrypted",
+    &pending.txn_id,
+    &content,
+  )?;
+  Ok(response)
+}
+
+enum Retry {
+  // Not counted towards MAX_ATTEMPTS, with the delay the server asked for if any
+  Transient(Option<Duration>),
+  Counted,
+  Never,
+}
+
+fn retry_kind(error: &CryptoError) -> Retry {
+  match error {
+    CryptoError::Api(error) => api_retry_kind(error),
+    // A device had no one-time key left to claim or a session couldn't be made from it, the
+    // device may upload more. Counted, as a key which can't be read never will be.
+    CryptoError::MissingSession(_) | CryptoError::Olm(_) => Retry::Counted,
+    // Other encryption errors won't go away by trying again
+    _ => Retry::Never,
+  }
+}
+
+fn api_retry_kind(error: &ApiError) -> Retry {
+  match error {
+    ApiError::Network { .. } | ApiError::Unknown => Retry::Transient(None),
+    ApiError::Response(429, response) => {
+      Retry::Transient(response.retry_after_ms().map(Duration::from_millis))
+    }
+    ApiError::Http(429, _) => Retry::Transient(None),
+    ApiError::Response(status, _) | ApiError::Http(status, _) if *status >= 500 => Retry::Counted,
+    _ => Retry::Never,
+  }
+}
+
+// Doubles with each attempt, up to MAX_BACKOFF
+fn backoff(attempts: u32) -> Duration {
+  let factor = 1u32 << attempts.saturating_sub(1).min(16);
+  (INITIAL_BACKOFF * factor).min(MAX_BACKOFF)
+}
+
+fn new_txn_id() -> String {
+  let random: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(8)
+    .collect();
+  format!("{}.{}", now_ms(), random)
+}
+
+fn now_ms() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or(0)
+}
@@ -0,0 +1,123 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::api;
+use crate::api::{ApiError, Kind, Result};
+use crate::client::MatrixClient;
+
+/*
+Single Sign-On
+The user logs in with the identity provider in a browser and is redirected back with a
+login token, which is exchanged for an access token using m.login.token
+
+docs: https://matrix.org/docs/spec/client_server/latest#sso-client-login
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/login/sso/redirect";
+
+// How long a connection may stay idle before it's dropped, browsers open some speculatively
+static READ_TIMEOUT: Duration = Duration::from_secs(5);
+static ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+// URL to open in the browser, optionally skipping the provider selection by giving its id
+pub fn redirect_url(client: &MatrixClient, redirect_url: &str, idp_id: Option<&str>) -> String {
+  let endpoint = match idp_id {
+    Some(idp_id) => format!("{}/{}", ENDPOINT, api::encode(idp_id)),
+    None => ENDPOINT.to_string(),
+  };
+
+  format!(
+    "{}{}?redirectUrl={}",
+    client.get_base_url(),
+    endpoint,
+    utf8_percent_encode(redirect_url, NON_ALPHANUMERIC)
+  )
+}
+
+// Local HTTP listener the browser is redirected back to once SSO has completed
+pub struct LoopbackListener {
+  listener: TcpListener,
+}
+
+impl LoopbackListener {
+  // Listen on a free port on the loopback interface
+  pub fn bind() -> Result<LoopbackListener> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(LoopbackListener { listener })
+  }
+
+  pub fn redirect_url(&self) -> Result<String> {
+    let address = self.listener.local_addr()?;
+    Ok(format!("http://{}/", address))
+  }
+
+  // Block until the browser is redirected back with a loginToken, ignoring any other requests.
+  // Fails once timeout has passed, e.g. when the user gave up on the SSO page.
+  pub fn wait_for_token(&self, timeout: Duration) -> Result<String> {
+    let deadline = Instant::now() + timeout;
+    // std has no accept timeout, so the listener is polled
+    self.listener.set_nonblocking(true)?;
+    loop {
+      let mut stream = match self.listener.accept() {
+        Ok((stream, _)) => stream,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+          if Instant::now() >= deadline {
+            return Err(ApiError::Network {
+              kind: Kind::Timeout,
+              message: String::from("The browser wasn't redirected back in time"),
+            });
+          }
+          thread::sleep(ACCEPT_INTERVAL);
+          continue;
+        }
+        Err(e) => return Err(e.into()),
+      };
+      stream.set_nonblocking(false)?;
+      stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+      let mut request_line = String::new();
+      match BufReader::new(&stream).read_line(&mut request_line) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+          continue
+        }
+        Err(e) => return Err(e.into()),
+      }
+
+      match login_token(&request_line) {
+        Some(token) => {
+          stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n\
+              Login complete, you can close this window.",
+          )?;
+          return Ok(token);
+        }
+        // The browser may have gone already, the token can still come
+        None => {
+          stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")
+            .ok();
+        }
+      }
+    }
+  }
+}
+
+// Pull the loginToken query parameter out of a request line, e.g. `GET /?loginToken=abc HTTP/1.1`
+fn login_token(request_line: &str) -> Option<String> {
+  let path = request_line.split_whitespace().nth(1)?;
+  let (_, query) = path.split_once('?')?;
+
+  query
+    .split('&')
+    .filter_map(|pair| match pair.split_once('=') {
+      Some(("loginToken", value)) => Some(value),
+      _ => None,
+    })
+    .next()
+    .map(|value| percent_decode_str(value).decode_utf8_lossy().into_owned())
+}
@@ -0,0 +1,227 @@
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+use crate::events::RoomEvent;
+use crate::send_queue::PendingEvent;
+use crate::store;
+use crate::store::{Result, StateStore, DEFAULT_TIMELINE_LIMIT};
+use crate::sync::{RoomSummary, SyncResponse, UnreadNotificationCounts};
+
+#[derive(Debug, Default)]
+struct RoomData {
+  // Keyed by event type and state key
+  state: HashMap<(String, String), RoomEvent>,
+  summary: RoomSummary,
+  unread_counts: UnreadNotificationCounts,
+  account_data: HashMap<String, Value>,
+  timeline: VecDeque<RoomEvent>,
+}
+
+// Store which keeps everything in memory, lost when the client exits
+#[derive(Debug)]
+pub struct MemoryStore {
+  sync_token: Option<String>,
+  rooms: HashMap<String, RoomData>,
+  account_data: HashMap<String, Value>,
+  pending_events: Vec<PendingEvent>,
+  timeline_limit: usize,
+}
+
+impl MemoryStore {
+  pub fn new() -> MemoryStore {
+    MemoryStore::with_timeline_limit(DEFAULT_TIMELINE_LIMIT)
+  }
+
+  pub fn with_timeline_limit(timeline_limit: usize) -> MemoryStore {
+    MemoryStore {
+      sync_token: None,
+      rooms: HashMap::new(),
+      account_data: HashMap::new(),
+      pending_events: Vec::new(),
+      timeline_limit,
+    }
+  }
+
+  fn room(&mut self, room_id: &str) -> &mut RoomData {
+    self.rooms.entry(room_id.to_string()).or_default()
+  }
+}
+
+impl Default for MemoryStore {
+  fn default() -> MemoryStore {
+    MemoryStore::new()
+  }
+}
+
+impl StateStore for MemoryStore {
+  fn set_sync_token(&mut self, token: &str) -> Result<()> {
+    self.sync_token = Some(token.to_string());
+    Ok(())
+  }
+
+  fn set_state_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()> {
+    if let Some(state_key) = &event.state_key {
+      let key = (event.r#type.clone(), state_key.clone());
+      self.room(room_id).state.insert(key, event.clone());
+    }
+    Ok(())
+  }
+
+  fn set_summary(&mut self, room_id: &str, summary: &RoomSummary) -> Result<()> {
+    self.room(room_id).summary = summary.clone();
+    Ok(())
+  }
+
+  fn set_unread_counts(&mut self, room_id: &str, counts: UnreadNotificationCounts) -> Result<()> {
+    self.room(room_id).unread_counts = counts;
+    Ok(())
+  }
+
+  fn set_account_data(
+    &mut self,
+    room_id: Option<&str>,
+    event_type: &str,
+    content: &Value,
+  ) -> Result<()> {
+    let account_data = match room_id {
+      Some(room_id) => &mut self.room(room_id).account_data,
+      None => &mut self.account_data,
+    };
+    account_data.insert(event_type.to_string(), content.clone());
+    Ok(())
+  }
+
+  fn push_timeline_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()> {
+    let limit = self.timeline_limit;
+    let timeline = &mut self.room(room_id).timeline;
+    if timeline
+      .iter()
+      .any(|stored| stored.event_id == event.event_id)
+    {
+      return Ok(());
+    }
+    timeline.push_back(event.clone());
+    while timeline.len() > limit {
+      timeline.pop_front();
+    }
+    Ok(())
+  }
+
+  fn clear_timeline(&mut self, room_id: &str) -> Result<()> {
+    self.room(room_id).timeline.clear();
+    Ok(())
+  }
+
+  fn remove_room(&mut self, room_id: &str) -> Result<()> {
+    self.rooms.remove(room_id);
+    Ok(())
+  }
+
+  fn save_pending_event(&mut self, event: &PendingEvent) -> Result<()> {
+    match self
+      .pending_events
+      .iter_mut()
+      .find(|pending| pending.txn_id == event.txn_id)
+    {
+      Some(pending) => *pending = event.clone(),
+      None => self.pending_events.push(event.clone()),
+    }
+    Ok(())
+  }
+
+  fn remove_pending_event(&mut self, txn_id: &str) -> Result<()> {
+    self
+      .pending_events
+      .retain(|pending| pending.txn_id != txn_id);
+    Ok(())
+  }
+
+  fn sync_token(&self) -> Result<Option<String>> {
+    Ok(self.sync_token.clone())
+  }
+
+  fn joined_rooms(&self) -> Result<Vec<String>> {
+    let mut room_ids: Vec<String> = self.rooms.keys().cloned().collect();
+    room_ids.sort();
+    Ok(room_ids)
+  }
+
+  fn state_event(
+    &self,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+  ) -> Result<Option<RoomEvent>> {
+    let key = (event_type.to_string(), state_key.to_string());
+    Ok(
+      self
+        .rooms
+        .get(room_id)
+        .and_then(|room| room.state.get(&key))
+        .cloned(),
+    )
+  }
+
+  fn state_events(&self, room_id: &str, event_type: &str) -> Result<Vec<RoomEvent>> {
+    let events = match self.rooms.get(room_id) {
+      Some(room) => room
+        .state
+        .values()
+        .filter(|event| event.r#type == event_type)
+        .cloned()
+        .collect(),
+      None => Vec::new(),
+    };
+    Ok(events)
+  }
+
+  fn summary(&self, room_id: &str) -> Result<RoomSummary> {
+    Ok(
+      self
+        .rooms
+        .get(room_id)
+        .map(|room| room.summary.clone())
+        .unwrap_or_default(),
+    )
+  }
+
+  fn unread_counts(&self, room_id: &str) -> Result<UnreadNotificationCounts> {
+    Ok(
+      self
+        .rooms
+        .get(room_id)
+        .map(|room| room.unread_counts)
+        .unwrap_or_default(),
+    )
+  }
+
+  fn account_data(&self, room_id: Option<&str>, event_type: &str) -> Result<Option<Value>> {
+    let account_data = match room_id {
+      Some(room_id) => match self.rooms.get(room_id) {
+        Some(room) => &room.account_data,
+        None => return Ok(None),
+      },
+      None => &self.account_data,
+    };
+    Ok(account_data.get(event_type).cloned())
+  }
+
+  fn timeline(&self, room_id: &str) -> Result<Vec<RoomEvent>> {
+    Ok(
+      self
+        .rooms
+        .get(room_id)
+        .map(|room| room.timeline.iter().cloned().collect())
+        .unwrap_or_default(),
+    )
+  }
+
+  fn pending_events(&self) -> Result<Vec<PendingEvent>> {
+    Ok(self.pending_events.clone())
+  }
+
+  // None of the primitives can fail, so there is nothing to roll back
+  fn save_sync(&mut self, response: &SyncResponse) -> Result<()> {
+    store::apply_sync(self, response)
+  }
+}
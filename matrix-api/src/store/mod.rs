@@ -0,0 +1,198 @@
+use serde_json::Value;
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::events::{Membership, RoomEvent};
+use crate::rooms::display::RoomState;
+use crate::send_queue::PendingEvent;
+use crate::sync::{RoomSummary, SyncResponse, UnreadNotificationCounts};
+
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/*
+State Store
+Keeps the client's view of its rooms between syncs, so screens don't need to hit the server
+
+Stores only need to implement the storage primitives and save_sync, which feeds them a sync
+response with apply_sync inside whatever transaction the store has. The queries built on top
+are shared by every store.
+*/
+
+// Number of timeline events kept per room unless a store is configured otherwise
+pub static DEFAULT_TIMELINE_LIMIT: usize = 100;
+
+// State event types which go into a room's name and avatar
+static DISPLAY_STATE_TYPES: &[&str] = &[
+  "m.room.name",
+  "m.room.canonical_alias",
+  "m.room.avatar",
+  "m.room.member",
+];
+
+#[derive(Debug)]
+pub enum StoreError {
+  Database(String),
+  Serialization(String),
+  Io(String),
+  // Wrong passphrase, or the stored data was tampered with
+  Decryption,
+}
+
+impl fmt::Display for StoreError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StoreError::Database(message) => write!(f, "Store database error: {}", message),
+      StoreError::Serialization(message) => write!(f, "Store serialization error: {}", message),
+      StoreError::Io(message) => write!(f, "Store IO error: {}", message),
+      StoreError::Decryption => write!(f, "Store couldn't be decrypted, check the passphrase"),
+    }
+  }
+}
+
+impl error::Error for StoreError {}
+
+impl From<serde_json::Error> for StoreError {
+  fn from(error: serde_json::Error) -> StoreError {
+    StoreError::Serialization(error.to_string())
+  }
+}
+
+impl From<io::Error> for StoreError {
+  fn from(error: io::Error) -> StoreError {
+    StoreError::Io(error.to_string())
+  }
+}
+
+pub type Result<T> = ::std::result::Result<T, StoreError>;
+
+pub trait StateStore {
+  fn set_sync_token(&mut self, token: &str) -> Result<()>;
+  // Replace the state event with the same type and state key
+  fn set_state_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()>;
+  fn set_summary(&mut self, room_id: &str, summary: &RoomSummary) -> Result<()>;
+  fn set_unread_counts(&mut self, room_id: &str, counts: UnreadNotificationCounts) -> Result<()>;
+  // Global account data when room_id is None
+  fn set_account_data(
+    &mut self,
+    room_id: Option<&str>,
+    event_type: &str,
+    content: &Value,
+  ) -> Result<()>;
+  // Append to the room's timeline unless an event with the same id is already in it, dropping
+  // the oldest events past the store's limit
+  fn push_timeline_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()>;
+  fn clear_timeline(&mut self, room_id: &str) -> Result<()>;
+  // Forget everything about a room, e.g. after leaving it
+  fn remove_room(&mut self, room_id: &str) -> Result<()>;
+  // Add an outgoing event or update the one with the same transaction id, keeping its place
+  fn save_pending_event(&mut self, event: &PendingEvent) -> Result<()>;
+  fn remove_pending_event(&mut self, txn_id: &str) -> Result<()>;
+
+  fn sync_token(&self) -> Result<Option<String>>;
+  fn joined_rooms(&self) -> Result<Vec<String>>;
+  fn state_event(
+    &self,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+  ) -> Result<Option<RoomEvent>>;
+  fn state_events(&self, room_id: &str, event_type: &str) -> Result<Vec<RoomEvent>>;
+  fn summary(&self, room_id: &str) -> Result<RoomSummary>;
+  fn unread_counts(&self, room_id: &str) -> Result<UnreadNotificationCounts>;
+  fn account_data(&self, room_id: Option<&str>, event_type: &str) -> Result<Option<Value>>;
+  // Oldest event first
+  fn timeline(&self, room_id: &str) -> Result<Vec<RoomEvent>>;
+  // Outgoing events which haven't been sent yet, oldest first
+  fn pending_events(&self) -> Result<Vec<PendingEvent>>;
+
+  // Apply a sync response, which should be the one following the stored sync token. Either
+  // all of it is saved or none of it, so a failed sync can be retried from the stored token.
+  fn save_sync(&mut self, response: &SyncResponse) -> Result<()>;
+
+  // Member events of the room with the given membership
+  fn members(&self, room_id: &str, membership: Membership) -> Result<Vec<RoomEvent>> {
+    let members = self
+      .state_events(room_id, "m.room.member")?
+      .into_iter()
+      .filter(|event| match event.member_content() {
+        Some(content) => content.membership == membership,
+        None => false,
+      })
+      .collect();
+
+    Ok(members)
+  }
+
+  // The state needed to display the room and its members
+  fn room_state(&self, room_id: &str) -> Result<RoomState> {
+    let mut events = Vec::new();
+    for event_type in DISPLAY_STATE_TYPES {
+      events.extend(self.state_events(room_id, event_type)?);
+    }
+
+    Ok(RoomState::new(room_id, events, self.summary(room_id)?))
+  }
+
+  fn room_display_name(&self, room_id: &str, own_user_id: &str) -> Result<String> {
+    Ok(self.room_state(room_id)?.display_name(own_user_id))
+  }
+}
+
+// Feed a sync response to the store's primitives, for stores to call from save_sync
+pub fn apply_sync<S: StateStore + ?Sized>(store: &mut S, response: &SyncResponse) -> Result<()> {
+  for (room_id, room) in &response.rooms.join {
+    for event in &room.state.events {
+      store.set_state_event(room_id, &with_room_id(event, room_id))?;
+    }
+
+    // A gap means the stored events are no longer followed by the new ones
+    if room.timeline.limited {
+      store.clear_timeline(room_id)?;
+    }
+    for event in &room.timeline.events {
+      let event = with_room_id(event, room_id);
+      if event.is_state() {
+        store.set_state_event(room_id, &event)?;
+      }
+      store.push_timeline_event(room_id, &event)?;
+    }
+
+    // Summary fields are only sent when they change
+    let mut summary = store.summary(room_id)?;
+    if let Some(heroes) = &room.summary.heroes {
+      summary.heroes = Some(heroes.clone());
+    }
+    if let Some(count) = room.summary.joined_member_count {
+      summary.joined_member_count = Some(count);
+    }
+    if let Some(count) = room.summary.invited_member_count {
+      summary.invited_member_count = Some(count);
+    }
+    store.set_summary(room_id, &summary)?;
+    store.set_unread_counts(room_id, room.unread_notifications)?;
+
+    for event in &room.account_data.events {
+      store.set_account_data(Some(room_id), &event.r#type, &event.content)?;
+    }
+  }
+
+  for room_id in response.rooms.leave.keys() {
+    store.remove_room(room_id)?;
+  }
+
+  for event in &response.account_data.events {
+    store.set_account_data(None, &event.r#type, &event.content)?;
+  }
+
+  store.set_sync_token(&response.next_batch)
+}
+
+// Events in a sync response leave out the room id, fill it in before storing them
+fn with_room_id(event: &RoomEvent, room_id: &str) -> RoomEvent {
+  let mut event = event.clone();
+  event.room_id = Some(room_id.to_string());
+  event
+}
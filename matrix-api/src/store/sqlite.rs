@@ -0,0 +1,379 @@
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde_json::Value;
+use std::path::Path;
+
+use crate::events::RoomEvent;
+use crate::send_queue::PendingEvent;
+use crate::store;
+use crate::store::{Result, StateStore, StoreError, DEFAULT_TIMELINE_LIMIT};
+use crate::sync::{RoomSummary, SyncResponse, UnreadNotificationCounts};
+
+/*
+SQLite Store
+Keeps the state in a SQLite database so it survives restarts and the next sync can carry on
+from the stored token. Events are stored as their JSON.
+
+Only built with the "sqlite" feature.
+*/
+
+static SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS sync_token (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    token TEXT NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS state (
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    state_key TEXT NOT NULL,
+    event TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_type, state_key)
+  );
+  CREATE TABLE IF NOT EXISTS summaries (
+    room_id TEXT PRIMARY KEY,
+    summary TEXT NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS unread_counts (
+    room_id TEXT PRIMARY KEY,
+    highlight_count INTEGER NOT NULL,
+    notification_count INTEGER NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS account_data (
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_type)
+  );
+  CREATE TABLE IF NOT EXISTS timeline (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    UNIQUE (room_id, event_id)
+  );
+  CREATE INDEX IF NOT EXISTS timeline_room ON timeline (room_id, id);
+  CREATE TABLE IF NOT EXISTS pending_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    txn_id TEXT NOT NULL UNIQUE,
+    event TEXT NOT NULL
+  );
+";
+
+// Global account data is stored under an empty room id
+static GLOBAL_ROOM: &str = "";
+
+impl From<rusqlite::Error> for StoreError {
+  fn from(error: rusqlite::Error) -> StoreError {
+    StoreError::Database(error.to_string())
+  }
+}
+
+pub struct SqliteStore {
+  connection: Connection,
+  timeline_limit: usize,
+}
+
+impl SqliteStore {
+  // Open or create the database at path
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+    SqliteStore::from_connection(Connection::open(path)?)
+  }
+
+  // Database which is thrown away when the store is dropped
+  pub fn open_in_memory() -> Result<SqliteStore> {
+    SqliteStore::from_connection(Connection::open_in_memory()?)
+  }
+
+  pub fn set_timeline_limit(&mut self, timeline_limit: usize) {
+    self.timeline_limit = timeline_limit;
+  }
+
+  fn from_connection(connection: Connection) -> Result<SqliteStore> {
+    connection.execute_batch(SCHEMA)?;
+
+    Ok(SqliteStore {
+      connection,
+      timeline_limit: DEFAULT_TIMELINE_LIMIT,
+    })
+  }
+}
+
+impl StateStore for SqliteStore {
+  fn set_sync_token(&mut self, token: &str) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR REPLACE INTO sync_token (id, token) VALUES (0, ?1)",
+      params![token],
+    )?;
+    Ok(())
+  }
+
+  fn set_state_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()> {
+    if let Some(state_key) = &event.state_key {
+      self.connection.execute(
+        "INSERT OR REPLACE INTO state (room_id, event_type, state_key, event)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+          room_id,
+          event.r#type,
+          state_key,
+          serde_json::to_string(event)?
+        ],
+      )?;
+    }
+    Ok(())
+  }
+
+  fn set_summary(&mut self, room_id: &str, summary: &RoomSummary) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR REPLACE INTO summaries (room_id, summary) VALUES (?1, ?2)",
+      params![room_id, serde_json::to_string(summary)?],
+    )?;
+    Ok(())
+  }
+
+  fn set_unread_counts(&mut self, room_id: &str, counts: UnreadNotificationCounts) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR REPLACE INTO unread_counts (room_id, highlight_count, notification_count)
+       VALUES (?1, ?2, ?3)",
+      params![
+        room_id,
+        counts.highlight_count as i64,
+        counts.notification_count as i64
+      ],
+    )?;
+    Ok(())
+  }
+
+  fn set_account_data(
+    &mut self,
+    room_id: Option<&str>,
+    event_type: &str,
+    content: &Value,
+  ) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR REPLACE INTO account_data (room_id, event_type, content) VALUES (?1, ?2, ?3)",
+      params![
+        room_id.unwrap_or(GLOBAL_ROOM),
+        event_type,
+        serde_json::to_string(content)?
+      ],
+    )?;
+    Ok(())
+  }
+
+  fn push_timeline_event(&mut self, room_id: &str, event: &RoomEvent) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR IGNORE INTO timeline (room_id, event_id, event) VALUES (?1, ?2, ?3)",
+      params![room_id, event.event_id, serde_json::to_string(event)?],
+    )?;
+    self.connection.execute(
+      "DELETE FROM timeline WHERE room_id = ?1 AND id NOT IN (
+         SELECT id FROM timeline WHERE room_id = ?1 ORDER BY id DESC LIMIT ?2
+       )",
+      params![room_id, self.timeline_limit as i64],
+    )?;
+    Ok(())
+  }
+
+  fn clear_timeline(&mut self, room_id: &str) -> Result<()> {
+    self
+      .connection
+      .execute("DELETE FROM timeline WHERE room_id = ?1", params![room_id])?;
+    Ok(())
+  }
+
+  fn remove_room(&mut self, room_id: &str) -> Result<()> {
+    // A savepoint rather than a transaction, as this also runs inside save_sync's
+    let transaction = self.connection.savepoint()?;
+    for table in &[
+      "state",
+      "summaries",
+      "unread_counts",
+      "account_data",
+      "timeline",
+    ] {
+      transaction.execute(
+        &format!("DELETE FROM {} WHERE room_id = ?1", table),
+        params![room_id],
+      )?;
+    }
+    transaction.commit()?;
+    Ok(())
+  }
+
+  fn save_pending_event(&mut self, event: &PendingEvent) -> Result<()> {
+    let json = serde_json::to_string(event)?;
+    // Update in place rather than replace, so the event keeps its id and place in the queue
+    let updated = self.connection.execute(
+      "UPDATE pending_events SET event = ?2 WHERE txn_id = ?1",
+      params![event.txn_id, json],
+    )?;
+    if updated == 0 {
+      self.connection.execute(
+        "INSERT INTO pending_events (txn_id, event) VALUES (?1, ?2)",
+        params![event.txn_id, json],
+      )?;
+    }
+    Ok(())
+  }
+
+  fn remove_pending_event(&mut self, txn_id: &str) -> Result<()> {
+    self.connection.execute(
+      "DELETE FROM pending_events WHERE txn_id = ?1",
+      params![txn_id],
+    )?;
+    Ok(())
+  }
+
+  fn sync_token(&self) -> Result<Option<String>> {
+    let token = self
+      .connection
+      .query_row(
+        "SELECT token FROM sync_token WHERE id = 0",
+        NO_PARAMS,
+        |row| row.get(0),
+      )
+      .optional()?;
+    Ok(token)
+  }
+
+  fn joined_rooms(&self) -> Result<Vec<String>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT DISTINCT room_id FROM state ORDER BY room_id")?;
+    let room_ids = statement
+      .query_map(NO_PARAMS, |row| row.get(0))?
+      .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(room_ids)
+  }
+
+  fn state_event(
+    &self,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+  ) -> Result<Option<RoomEvent>> {
+    let event: Option<String> = self
+      .connection
+      .query_row(
+        "SELECT event FROM state WHERE room_id = ?1 AND event_type = ?2 AND state_key = ?3",
+        params![room_id, event_type, state_key],
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    match event {
+      Some(event) => Ok(Some(serde_json::from_str(&event)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn state_events(&self, room_id: &str, event_type: &str) -> Result<Vec<RoomEvent>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT event FROM state WHERE room_id = ?1 AND event_type = ?2")?;
+    let events = statement
+      .query_map(params![room_id, event_type], |row| row.get(0))?
+      .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut parsed = Vec::with_capacity(events.len());
+    for event in events {
+      parsed.push(serde_json::from_str(&event)?);
+    }
+    Ok(parsed)
+  }
+
+  fn summary(&self, room_id: &str) -> Result<RoomSummary> {
+    let summary: Option<String> = self
+      .connection
+      .query_row(
+        "SELECT summary FROM summaries WHERE room_id = ?1",
+        params![room_id],
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    match summary {
+      Some(summary) => Ok(serde_json::from_str(&summary)?),
+      None => Ok(RoomSummary::default()),
+    }
+  }
+
+  fn unread_counts(&self, room_id: &str) -> Result<UnreadNotificationCounts> {
+    let counts = self
+      .connection
+      .query_row(
+        "SELECT highlight_count, notification_count FROM unread_counts WHERE room_id = ?1",
+        params![room_id],
+        |row| {
+          let highlight_count: i64 = row.get(0)?;
+          let notification_count: i64 = row.get(1)?;
+          Ok(UnreadNotificationCounts {
+            highlight_count: highlight_count as u64,
+            notification_count: notification_count as u64,
+          })
+        },
+      )
+      .optional()?;
+    Ok(counts.unwrap_or_default())
+  }
+
+  fn account_data(&self, room_id: Option<&str>, event_type: &str) -> Result<Option<Value>> {
+    let content: Option<String> = self
+      .connection
+      .query_row(
+        "SELECT content FROM account_data WHERE room_id = ?1 AND event_type = ?2",
+        params![room_id.unwrap_or(GLOBAL_ROOM), event_type],
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    match content {
+      Some(content) => Ok(Some(serde_json::from_str(&content)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn timeline(&self, room_id: &str) -> Result<Vec<RoomEvent>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT event FROM timeline WHERE room_id = ?1 ORDER BY id")?;
+    let events = statement
+      .query_map(params![room_id], |row| row.get(0))?
+      .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut parsed = Vec::with_capacity(events.len());
+    for event in events {
+      parsed.push(serde_json::from_str(&event)?);
+    }
+    Ok(parsed)
+  }
+
+  fn pending_events(&self) -> Result<Vec<PendingEvent>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT event FROM pending_events ORDER BY id")?;
+    let events = statement
+      .query_map(NO_PARAMS, |row| row.get(0))?
+      .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut parsed = Vec::with_capacity(events.len());
+    for event in events {
+      parsed.push(serde_json::from_str(&event)?);
+    }
+    Ok(parsed)
+  }
+
+  fn save_sync(&mut self, response: &SyncResponse) -> Result<()> {
+    self.connection.execute_batch("BEGIN")?;
+    match store::apply_sync(self, response) {
+      Ok(()) => {
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+      }
+      Err(error) => {
+        self.connection.execute_batch("ROLLBACK")?;
+        Err(error)
+      }
+    }
+  }
+}
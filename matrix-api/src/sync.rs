@@ -0,0 +1,209 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::events::RoomEvent;
+use crate::handlers;
+
+/*
+Sync
+Fetch the user's rooms and everything that has changed since the last sync
+
+docs: https://matrix.org/docs/spec/client_server/latest#get-matrix-client-r0-sync
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/sync";
+
+#[derive(Serialize, Debug)]
+pub enum Presence {
+  #[serde(rename = "offline")]
+  Offline,
+  #[serde(rename = "online")]
+  Online,
+  #[serde(rename = "unavailable")]
+  Unavailable,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct SyncQuery {
+  // Filter id, or a JSON encoded filter
+  pub filter: Option<String>,
+  // The next_batch of the previous sync, None for an initial sync
+  pub since: Option<String>,
+  pub full_state: Option<bool>,
+  pub set_presence: Option<Presence>,
+  // Milliseconds to wait for new events before returning
+  pub timeout: Option<i64>,
+}
+
+// Events without a room or event id, such as account data, ephemeral and to-device events
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BasicEvent {
+  pub r#type: String,
+  pub content: Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventList<T> {
+  #[serde(default = "Vec::new")]
+  pub events: Vec<T>,
+}
+
+impl<T> Default for EventList<T> {
+  fn default() -> EventList<T> {
+    EventList { events: Vec::new() }
+  }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Timeline {
+  #[serde(default)]
+  pub events: Vec<RoomEvent>,
+  // Whether there was a gap between this and the previous sync's timeline
+  #[serde(default)]
+  pub limited: bool,
+  pub prev_batch: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomSummary {
+  #[serde(rename = "m.heroes")]
+  pub heroes: Option<Vec<String>>,
+  #[serde(rename = "m.joined_member_count")]
+  pub joined_member_count: Option<u64>,
+  #[serde(rename = "m.invited_member_count")]
+  pub invited_member_count: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct UnreadNotificationCounts {
+  #[serde(default)]
+  pub highlight_count: u64,
+  #[serde(default)]
+  pub notification_count: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct JoinedRoom {
+  #[serde(default)]
+  pub summary: RoomSummary,
+  #[serde(default)]
+  pub state: EventList<RoomEvent>,
+  #[serde(default)]
+  pub timeline: Timeline,
+  #[serde(default)]
+  pub ephemeral: EventList<BasicEvent>,
+  #[serde(default)]
+  pub account_data: EventList<BasicEvent>,
+  #[serde(default)]
+  pub unread_notifications: UnreadNotificationCounts,
+}
+
+// State events of an invite, stripped down to what is needed to show it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StrippedStateEvent {
+  pub r#type: String,
+  pub state_key: String,
+  pub content: Value,
+  pub sender: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct InvitedRoom {
+  #[serde(default)]
+  pub invite_state: EventList<StrippedStateEvent>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct LeftRoom {
+  #[serde(default)]
+  pub state: EventList<RoomEvent>,
+  #[serde(default)]
+  pub timeline: Timeline,
+  #[serde(default)]
+  pub account_data: EventList<BasicEvent>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Rooms {
+  #[serde(default)]
+  pub join: HashMap<String, JoinedRoom>,
+  #[serde(default)]
+  pub invite: HashMap<String, InvitedRoom>,
+  #[serde(default)]
+  pub leave: HashMap<String, LeftRoom>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeviceLists {
+  #[serde(default)]
+  pub changed: Vec<String>,
+  #[serde(default)]
+  pub left: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SyncResponse {
+  pub next_batch: String,
+  #[serde(default)]
+  pub rooms: Rooms,
+  #[serde(default)]
+  pub presence: EventList<BasicEvent>,
+  #[serde(default)]
+  pub account_data: EventList<BasicEvent>,
+  #[serde(default)]
+  pub to_device: EventList<BasicEvent>,
+  #[serde(default)]
+  pub device_lists: DeviceLists,
+  #[serde(default)]
+  pub device_one_time_keys_count: HashMap<String, u64>,
+}
+
+pub fn sync(client: &MatrixClient, query: SyncQuery) -> Result<SyncResponse> {
+  let mut response = api::get_query(client, ENDPOINT, &query)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// Keep syncing from since, passing each response to the client's event handlers and then to
+// on_response, until on_response returns false. Returns the token to carry on from.
+// Stops at the first error, on_response has seen every response before it so it can keep track.
+pub fn sync_loop<F>(
+  client: &MatrixClient,
+  since: Option<String>,
+  timeout: i64,
+  mut on_response: F,
+) -> Result<String>
+where
+  F: FnMut(&SyncResponse) -> bool,
+{
+  let mut since = since;
+  loop {
+    let query = SyncQuery {
+      since,
+      timeout: Some(timeout),
+      ..Default::default()
+    };
+    let response = sync(client, query)?;
+    handlers::dispatch(client, &response);
+
+    if !on_response(&response) {
+      return Ok(response.next_batch);
+    }
+    since = Some(response.next_batch);
+  }
+}
@@ -0,0 +1,311 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::account::UnbindResult;
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::auth;
+use crate::auth::AuthData;
+use crate::client::MatrixClient;
+use crate::login::ThirdPartyMedium;
+
+/*
+Third Party Identifiers
+Adding, binding and removing the email addresses and phone numbers of an account
+
+docs: https://matrix.org/docs/spec/client_server/latest#adding-account-administrative-contact-information
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/account/3pid";
+pub static ADD_ENDPOINT: &str = "/_matrix/client/r0/account/3pid/add";
+pub static BIND_ENDPOINT: &str = "/_matrix/client/r0/account/3pid/bind";
+pub static DELETE_ENDPOINT: &str = "/_matrix/client/r0/account/3pid/delete";
+pub static UNBIND_ENDPOINT: &str = "/_matrix/client/r0/account/3pid/unbind";
+
+// What a validation token is being requested for, each has its own requestToken endpoints
+#[derive(Debug, Clone, Copy)]
+pub enum TokenPurpose {
+  // Adding a 3PID to an existing account
+  Account,
+  // Adding a 3PID while registering
+  Registration,
+  // Proving ownership of a 3PID to reset the password
+  PasswordReset,
+}
+
+impl TokenPurpose {
+  fn endpoint(self, medium: &ThirdPartyMedium) -> String {
+    let prefix = match self {
+      TokenPurpose::Account => "/_matrix/client/r0/account/3pid",
+      TokenPurpose::Registration => "/_matrix/client/r0/register",
+      TokenPurpose::PasswordReset => "/_matrix/client/r0/account/password",
+    };
+    let medium = match medium {
+      ThirdPartyMedium::Email => "email",
+      ThirdPartyMedium::MSISDN => "msisdn",
+    };
+    format!("{}/{}/requestToken", prefix, medium)
+  }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EmailTokenRequest {
+  pub client_secret: String,
+  pub email: String,
+  pub send_attempt: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_link: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_server: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_access_token: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MsisdnTokenRequest {
+  pub client_secret: String,
+  // Two letter ISO-3166-1 alpha-2 country code the number is from
+  pub country: String,
+  pub phone_number: String,
+  pub send_attempt: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_link: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_server: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_access_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenResponse {
+  pub sid: String,
+  // Where the token can be submitted, when the homeserver handles validation itself
+  pub submit_url: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ThreepidCredentials {
+  pub sid: String,
+  pub client_secret: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_server: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_access_token: Option<String>,
+}
+
+// Tracks a validation session across token requests, retries and the final add/bind
+#[derive(Debug, Clone)]
+pub struct ThreepidSession {
+  pub medium: ThirdPartyMedium,
+  pub client_secret: String,
+  pub send_attempt: u32,
+  pub sid: Option<String>,
+  pub submit_url: Option<String>,
+}
+
+impl ThreepidSession {
+  pub fn new(medium: ThirdPartyMedium) -> ThreepidSession {
+    let client_secret = rand::thread_rng()
+      .sample_iter(&Alphanumeric)
+      .take(32)
+      .collect();
+
+    ThreepidSession {
+      medium,
+      client_secret,
+      send_attempt: 0,
+      sid: None,
+      submit_url: None,
+    }
+  }
+
+  // Build the next email token request, the server only resends when send_attempt increases
+  pub fn email_request(&mut self, email: &str, next_link: Option<String>) -> EmailTokenRequest {
+    self.send_attempt += 1;
+    EmailTokenRequest {
+      client_secret: self.client_secret.clone(),
+      email: email.to_string(),
+      send_attempt: self.send_attempt,
+      next_link,
+      id_server: None,
+      id_access_token: None,
+    }
+  }
+
+  pub fn msisdn_request(&mut self, country: &str, phone_number: &str) -> MsisdnTokenRequest {
+    self.send_attempt += 1;
+    MsisdnTokenRequest {
+      client_secret: self.client_secret.clone(),
+      country: country.to_string(),
+      phone_number: phone_number.to_string(),
+      send_attempt: self.send_attempt,
+      next_link: None,
+      id_server: None,
+      id_access_token: None,
+    }
+  }
+
+  pub fn update(&mut self, response: TokenResponse) {
+    self.sid = Some(response.sid);
+    self.submit_url = response.submit_url;
+  }
+
+  // Credentials proving ownership, available once a token has been requested
+  pub fn credentials(&self) -> Option<ThreepidCredentials> {
+    self.sid.as_ref().map(|sid| ThreepidCredentials {
+      sid: sid.to_owned(),
+      client_secret: self.client_secret.clone(),
+      id_server: None,
+      id_access_token: None,
+    })
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Threepid {
+  pub medium: ThirdPartyMedium,
+  pub address: String,
+  pub validated_at: i64,
+  pub added_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThreepidsResponse {
+  pub threepids: Vec<Threepid>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AddThreepidRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub auth: Option<AuthData>,
+  pub client_secret: String,
+  pub sid: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BindThreepidRequest {
+  pub client_secret: String,
+  pub id_server: String,
+  pub id_access_token: String,
+  pub sid: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RemoveThreepidRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_server: Option<String>,
+  pub medium: ThirdPartyMedium,
+  pub address: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveThreepidResponse {
+  pub id_server_unbind_result: UnbindResult,
+}
+
+pub fn get_threepids(client: &MatrixClient) -> Result<ThreepidsResponse> {
+  let mut response = api::get(client, ENDPOINT)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::UNAUTHORIZED => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn request_email_token(
+  client: &MatrixClient,
+  purpose: TokenPurpose,
+  request: EmailTokenRequest,
+) -> Result<TokenResponse> {
+  let endpoint = purpose.endpoint(&ThirdPartyMedium::Email);
+  let mut response = api::post(client, &endpoint, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn request_msisdn_token(
+  client: &MatrixClient,
+  purpose: TokenPurpose,
+  request: MsisdnTokenRequest,
+) -> Result<TokenResponse> {
+  let endpoint = purpose.endpoint(&ThirdPartyMedium::MSISDN);
+  let mut response = api::post(client, &endpoint, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn add_threepid(client: &MatrixClient, request: AddThreepidRequest) -> Result<()> {
+  let response = api::post(client, ADD_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::UNAUTHORIZED => Err(auth::interactive_auth_error(response)),
+    StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {
+      Err(ApiError::from(response))
+    }
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn bind_threepid(client: &MatrixClient, request: BindThreepidRequest) -> Result<()> {
+  let response = api::post(client, BIND_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn delete_threepid(
+  client: &MatrixClient,
+  request: RemoveThreepidRequest,
+) -> Result<RemoveThreepidResponse> {
+  let mut response = api::post(client, DELETE_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+pub fn unbind_threepid(
+  client: &MatrixClient,
+  request: RemoveThreepidRequest,
+) -> Result<RemoveThreepidResponse> {
+  let mut response = api::post(client, UNBIND_ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::BAD_REQUEST | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,181 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+use crate::sync::{BasicEvent, SyncResponse};
+
+/*
+Send To Device
+Send events straight to other users' devices, outside of any room. They arrive in the
+to_device section of the recipient's sync, see `ToDeviceEvent` for reading them.
+
+docs: https://matrix.org/docs/spec/client_server/latest#put-matrix-client-r0-sendtodevice-eventtype-txnid
+*/
+
+pub fn endpoint(event_type: &str, txn_id: &str) -> String {
+  format!(
+    "/_matrix/client/r0/sendToDevice/{}/{}",
+    api::encode(event_type),
+    api::encode(txn_id)
+  )
+}
+
+// Device id which sends the message to every device of the user
+pub static ALL_DEVICES: &str = "*";
+
+// Content for each device, keyed by user id then device id
+pub type DeviceMessages = HashMap<String, HashMap<String, Value>>;
+
+pub fn add_message(messages: &mut DeviceMessages, user_id: &str, device_id: &str, content: Value) {
+  messages
+    .entry(user_id.to_string())
+    .or_default()
+    .insert(device_id.to_string(), content);
+}
+
+// Send the content to every device of the user, replacing messages to single devices
+pub fn add_all_devices(messages: &mut DeviceMessages, user_id: &str, content: Value) {
+  let devices = messages.entry(user_id.to_string()).or_default();
+  devices.clear();
+  devices.insert(ALL_DEVICES.to_string(), content);
+}
+
+#[derive(Serialize, Debug)]
+pub struct SendToDeviceRequest<'a> {
+  pub messages: &'a DeviceMessages,
+}
+
+pub fn send_to_device(
+  client: &MatrixClient,
+  event_type: &str,
+  txn_id: &str,
+  messages: &DeviceMessages,
+) -> Result<()> {
+  let request = SendToDeviceRequest { messages };
+  let response = api::put(client, &endpoint(event_type, txn_id), &request)?;
+
+  match response.status() {
+    StatusCode::OK => Ok(()),
+    StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
+
+// https://matrix.org/docs/spec/client_server/latest#m-room-encrypted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedContent {
+  pub algorithm: String,
+  pub sender_key: String,
+  // Olm ciphertexts keyed by the recipient device's curve25519 key
+  pub ciphertext: Value,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#m-room-key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomKeyContent {
+  pub algorithm: String,
+  pub room_id: String,
+  pub session_id: String,
+  pub session_key: String,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#m-room-key-request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomKeyRequestContent {
+  // "request" or "request_cancellation"
+  pub action: String,
+  pub requesting_device_id: String,
+  pub request_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<RequestedKeyInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestedKeyInfo {
+  pub algorithm: String,
+  pub room_id: String,
+  pub session_id: String,
+  pub sender_key: String,
+}
+
+// https://matrix.org/docs/spec/client_server/latest#m-forwarded-room-key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardedRoomKeyContent {
+  pub algorithm: String,
+  pub room_id: String,
+  pub sender_key: String,
+  pub session_id: String,
+  pub session_key: String,
+  pub sender_claimed_ed25519_key: String,
+  #[serde(default)]
+  pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ToDeviceContent {
+  Encrypted(EncryptedContent),
+  // Only trusted when decrypted from an Olm message
+  RoomKey(RoomKeyContent),
+  RoomKeyRequest(RoomKeyRequestContent),
+  ForwardedRoomKey(ForwardedRoomKeyContent),
+  // Sent to create a new Olm session, without any content
+  Dummy,
+  // m.key.verification.* events, handled by the verification module
+  KeyVerification(Value),
+  // Anything else, including known events whose content didn't parse
+  Custom(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct ToDeviceEvent {
+  pub r#type: String,
+  pub sender: Option<String>,
+  pub content: ToDeviceContent,
+}
+
+impl From<&BasicEvent> for ToDeviceEvent {
+  fn from(event: &BasicEvent) -> ToDeviceEvent {
+    fn parse<T: serde::de::DeserializeOwned>(
+      content: &Value,
+      typed: fn(T) -> ToDeviceContent,
+    ) -> ToDeviceContent {
+      match serde_json::from_value(content.clone()) {
+        Ok(content) => typed(content),
+        Err(_) => ToDeviceContent::Custom(content.clone()),
+      }
+    }
+
+    let content = match event.r#type.as_ref() {
+      "m.room.encrypted" => parse(&event.content, ToDeviceContent::Encrypted),
+      "m.room_key" => parse(&event.content, ToDeviceContent::RoomKey),
+      "m.room_key_request" => parse(&event.content, ToDeviceContent::RoomKeyRequest),
+      "m.forwarded_room_key" => parse(&event.content, ToDeviceContent::ForwardedRoomKey),
+      "m.dummy" => ToDeviceContent::Dummy,
+      t if t.starts_with("m.key.verification.") => {
+        ToDeviceContent::KeyVerification(event.content.clone())
+      }
+      _ => ToDeviceContent::Custom(event.content.clone()),
+    };
+    ToDeviceEvent {
+      r#type: event.r#type.clone(),
+      sender: event.sender.clone(),
+      content,
+    }
+  }
+}
+
+// The to-device events in a sync response. Pass the response through the OlmMachine first
+// to see the decrypted events.
+pub fn events(response: &SyncResponse) -> Vec<ToDeviceEvent> {
+  response
+    .to_device
+    .events
+    .iter()
+    .map(ToDeviceEvent::from)
+    .collect()
+}
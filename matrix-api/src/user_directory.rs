@@ -0,0 +1,53 @@
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Result;
+use crate::client::MatrixClient;
+
+/*
+User Directory
+Search for users by user id or display name
+
+docs: https://matrix.org/docs/spec/client_server/latest#post-matrix-client-r0-user-directory-search
+*/
+
+pub static ENDPOINT: &str = "/_matrix/client/r0/user_directory/search";
+
+#[derive(Serialize, Debug)]
+pub struct UserDirectoryRequest {
+  pub search_term: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+  pub user_id: String,
+  pub display_name: Option<String>,
+  pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserDirectoryResponse {
+  pub results: Vec<User>,
+  // Whether the results were truncated by the limit
+  pub limited: bool,
+}
+
+pub fn search_users(
+  client: &MatrixClient,
+  request: UserDirectoryRequest,
+) -> Result<UserDirectoryResponse> {
+  let mut response = api::post(client, ENDPOINT, &request)?;
+
+  match response.status() {
+    StatusCode::OK => {
+      let success = response.json()?;
+      Ok(success)
+    }
+    StatusCode::TOO_MANY_REQUESTS => Err(ApiError::from(response)),
+    s => Err(ApiError::from(s)),
+  }
+}
@@ -0,0 +1,159 @@
+use matrix_api::api::ApiError;
+use matrix_api::auth::{AuthData, UserInteractiveAuthResponse};
+use matrix_api::client::MatrixClient;
+use matrix_api::login::ThirdPartyMedium;
+use matrix_api::threepid::ThreepidSession;
+use matrix_api::*;
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+
+use crate::encryption::file_error;
+use crate::io::request_input;
+
+// Where refreshed tokens are kept, the old ones stop working once they've been refreshed
+static SESSION_FILE: &str = "session.json";
+
+// Ask for the current password whenever the server requires it
+pub fn password_auth(user_id: &str, uia: &UserInteractiveAuthResponse) -> Option<AuthData> {
+  if let Some(error) = &uia.error {
+    println!("Authentication failed: {}", error);
+  }
+
+  let mut password = String::new();
+  request_input("Current password (blank to cancel)", &mut password);
+  if password.is_empty() {
+    return None;
+  }
+
+  Some(AuthData::password(uia, user_id, password))
+}
+
+pub fn whoami(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let response = account::whoami(matrix_client)?;
+
+  println!("User ID: {}", response.user_id);
+  if let Some(device_id) = response.device_id {
+    println!("Device ID: {}", device_id);
+  }
+
+  Ok(())
+}
+
+pub fn restore_session(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
+  let mut access_token = String::new();
+  request_input(
+    "Access Token (blank for the saved session)",
+    &mut access_token,
+  );
+  if access_token.is_empty() {
+    let session: serde_json::Value =
+      serde_json::from_str(&fs::read_to_string(SESSION_FILE).map_err(file_error)?)
+        .map_err(|_| ApiError::Serialization)?;
+    access_token = session["access_token"]
+      .as_str()
+      .unwrap_or_default()
+      .to_string();
+    // Set first, so an expired access token can be refreshed
+    matrix_client.set_refresh_token(session["refresh_token"].as_str().map(String::from));
+  }
+
+  let response = account::restore_session(matrix_client, access_token)?;
+  println!("Logged in as {}", response.user_id);
+
+  Ok(())
+}
+
+// Keep the tokens for restore_session, called whenever the client refreshes them
+pub fn save_session(access_token: &str, refresh_token: Option<&str>) {
+  let session = json!({
+    "access_token": access_token,
+    "refresh_token": refresh_token,
+  });
+  if let Err(e) = fs::write(SESSION_FILE, session.to_string()) {
+    println!("Couldn't save the refreshed tokens: {}", e);
+  }
+}
+
+pub fn change_password(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let user_id = account::whoami(matrix_client)?.user_id;
+
+  let mut new_password = String::new();
+  request_input("New password", &mut new_password);
+
+  let mut logout = String::new();
+  request_input("Log out other devices? (y/n)", &mut logout);
+
+  auth::with_interactive_auth(
+    |auth| {
+      let request = account::ChangePasswordRequest {
+        new_password: new_password.clone(),
+        logout_devices: Some(logout == "y" || logout == "Y"),
+        auth,
+      };
+      account::change_password(matrix_client, request)
+    },
+    |uia| password_auth(&user_id, uia),
+  )
+}
+
+pub fn deactivate(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
+  let user_id = account::whoami(matrix_client)?.user_id;
+
+  let mut confirm = String::new();
+  request_input(
+    &format!("Deactivate {}? This cannot be undone (y/n)", user_id),
+    &mut confirm,
+  );
+  if confirm != "y" && confirm != "Y" {
+    return Ok(());
+  }
+
+  let mut erase = String::new();
+  request_input("Erase messages? (y/n)", &mut erase);
+
+  auth::with_interactive_auth(
+    |auth| {
+      let request = account::DeactivateRequest {
+        auth,
+        id_server: None,
+        erase: Some(erase == "y" || erase == "Y"),
+      };
+      account::deactivate(matrix_client, request)
+    },
+    |uia| password_auth(&user_id, uia),
+  )?;
+
+  matrix_client.remove_access_token();
+
+  Ok(())
+}
+
+pub fn forgot_password(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let mut email = String::new();
+  request_input("Email", &mut email);
+
+  let mut session = ThreepidSession::new(ThirdPartyMedium::Email);
+  account::request_password_reset(matrix_client, &mut session, &email)?;
+
+  let mut new_password = String::new();
+  request_input("New password", &mut new_password);
+
+  let creds = match session.credentials() {
+    Some(creds) => creds,
+    None => return Ok(()),
+  };
+
+  println!(
+    "Follow the link sent to {}, waiting for validation...",
+    email
+  );
+  account::reset_password(
+    matrix_client,
+    creds,
+    new_password,
+    Some(true),
+    Duration::from_secs(5),
+    Duration::from_secs(600),
+  )
+}
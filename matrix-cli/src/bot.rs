@@ -0,0 +1,62 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::events::Membership;
+use matrix_api::handlers::EventHandler;
+use matrix_api::*;
+use std::cell::Cell;
+use std::rc::Rc;
+
+// Reply to "!ping" in any joined room until someone says "!stop"
+pub fn run(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
+  let own_user_id = account::whoami(matrix_client)?.user_id;
+  let stop = Rc::new(Cell::new(false));
+
+  let stop_handler = stop.clone();
+  let message_handler = matrix_client.add_event_handler(EventHandler::Message(Box::new(
+    move |client, room, event| {
+      if event.r#type != "m.room.message" || event.sender == own_user_id {
+        return;
+      }
+      match event.content["body"].as_str() {
+        Some("!ping") => {
+          println!("{} pinged in {}", event.sender, room.room_id);
+          // Deriving the transaction id from the event means a resent reply is ignored
+          let txn_id = format!("pong{}", event.event_id);
+          let content = rooms::send::text_content("pong");
+          if let Err(e) =
+            rooms::send::send_event(client, room.room_id, "m.room.message", &txn_id, &content)
+          {
+            println!("Couldn't reply: {}", e);
+          }
+        }
+        Some("!stop") => stop_handler.set(true),
+        _ => (),
+      }
+    },
+  )));
+
+  let member_handler =
+    matrix_client.add_event_handler(EventHandler::Member(Box::new(|_, room, event, content| {
+      let user_id = event.state_key.as_deref().unwrap_or_default();
+      match content.membership {
+        Membership::Join => println!("{} joined {}", user_id, room.room_id),
+        Membership::Invite => println!("{} invited {} to {}", event.sender, user_id, room.room_id),
+        Membership::Leave => println!("{} left {}", user_id, room.room_id),
+        _ => (),
+      }
+    })));
+
+  // Skip over the history, only events from now on are handled
+  let query = sync::SyncQuery {
+    timeout: Some(0),
+    ..Default::default()
+  };
+  let since = sync::sync(matrix_client, query)?.next_batch;
+
+  println!("Bot running, send !stop to stop it");
+  let result = sync::sync_loop(matrix_client, Some(since), 30000, |_| !stop.get());
+
+  matrix_client.remove_event_handler(message_handler);
+  matrix_client.remove_event_handler(member_handler);
+  result.map(|_| ())
+}
@@ -4,6 +4,51 @@ use matrix_api::*;
 
 use crate::io::request_input;
 
+// Search the user directory and collect the users to invite, until a blank search
+fn select_invites(matrix_client: &MatrixClient) -> Result<Vec<String>, ApiError> {
+  let mut invites = Vec::new();
+
+  loop {
+    let mut search_term = String::new();
+    request_input("Search users to invite (blank to finish)", &mut search_term);
+    if search_term.is_empty() {
+      return Ok(invites);
+    }
+
+    let request = user_directory::UserDirectoryRequest {
+      search_term,
+      limit: Some(10),
+    };
+    let response = user_directory::search_users(matrix_client, request)?;
+
+    if response.results.is_empty() {
+      println!("No users found");
+      continue;
+    }
+
+    for (i, user) in response.results.iter().enumerate() {
+      match &user.display_name {
+        Some(name) => println!("({}) - {} ({})", i, name, user.user_id),
+        None => println!("({}) - {}", i, user.user_id),
+      }
+    }
+    if response.limited {
+      println!("More users matched, refine the search to see them");
+    }
+
+    let mut selected = String::new();
+    request_input("Select user (blank to skip)", &mut selected);
+    match selected.parse::<usize>() {
+      Ok(i) if i < response.results.len() => {
+        let user_id = response.results[i].user_id.to_owned();
+        println!("Inviting {}", user_id);
+        invites.push(user_id);
+      }
+      _ => println!("No user selected"),
+    }
+  }
+}
+
 pub fn create(matrix_client: &MatrixClient) -> Result<(), ApiError> {
   let mut room_name = String::new();
   request_input("Room Name (e.g. General Fun)", &mut room_name);
@@ -14,25 +59,47 @@ pub fn create(matrix_client: &MatrixClient) -> Result<(), ApiError> {
   let mut room_topic = String::new();
   request_input("Room Topic (e.g. To have fun)", &mut room_topic);
 
+  let mut space = String::new();
+  request_input("Space? (y/n)", &mut space);
+  let room_type = match space.as_ref() {
+    "Y" | "y" => Some(rooms::spaces::SPACE_TYPE.to_string()),
+    _ => None,
+  };
+
+  let invites = select_invites(matrix_client)?;
+
+  let mut encrypted = String::new();
+  request_input("Encrypt messages? (y/n)", &mut encrypted);
+  let initial_state = match encrypted.as_ref() {
+    "Y" | "y" => Some(vec![crypto::encryption_state_event()]),
+    _ => None,
+  };
+
   let request = rooms::create::CreateRoomRequest {
     visibility: Some(rooms::create::VisibilityType::Public),
     room_alias_name: Some(room_name_alias),
     name: Some(room_name),
     topic: Some(room_topic),
-    invite: None,
+    invite: if invites.is_empty() {
+      None
+    } else {
+      Some(invites)
+    },
     invite_3pid: None,
     room_version: None,
     creation_content: Some(rooms::create::CreationContent {
       federate: Some(false),
       predecessor: None,
+      room_type,
     }),
-    initial_state: None,
+    initial_state,
     preset: Some(rooms::create::PresetType::PublicChat),
     is_direct: Some(false),
     power_level_content_override: None,
   };
 
-  rooms::create::create_room(&matrix_client, request)?;
+  let response = rooms::create::create_room(&matrix_client, request)?;
+  println!("Created {}", response.room_id);
 
   Ok(())
 }
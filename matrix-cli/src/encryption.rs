@@ -0,0 +1,288 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::crypto::backup::{BackupKey, BACKUP_SECRET};
+use matrix_api::crypto::key_export;
+use matrix_api::crypto::machine::OlmMachine;
+use matrix_api::crypto::secret_storage;
+use matrix_api::crypto::secret_storage::SecretStorageKey;
+use matrix_api::crypto::store::file;
+use matrix_api::crypto::store::file::FileCryptoStore;
+use matrix_api::crypto::{backup, CryptoError};
+use matrix_api::*;
+use std::fs;
+
+use crate::account::password_auth;
+use crate::io::request_input;
+use crate::rooms::store_error;
+
+pub struct Crypto {
+  pub machine: OlmMachine,
+  pub store: FileCryptoStore,
+}
+
+impl Crypto {
+  pub fn save(&mut self) -> Result<(), ApiError> {
+    self.machine.save(&mut self.store).map_err(store_error)
+  }
+}
+
+pub fn crypto_error(error: CryptoError) -> ApiError {
+  match error {
+    CryptoError::Api(error) => error,
+    error => {
+      println!("{}", error);
+      ApiError::Unknown
+    }
+  }
+}
+
+// The logged in device's OlmMachine, loaded from the crypto store the first time it's needed
+pub fn setup<'a>(
+  matrix_client: &MatrixClient,
+  crypto: &'a mut Option<Crypto>,
+) -> Result<&'a mut Crypto, ApiError> {
+  if crypto.is_none() {
+    let (user_id, device_id) = match (matrix_client.user_id(), matrix_client.device_id()) {
+      (Some(user_id), Some(device_id)) => (user_id.to_string(), Some(device_id.to_string())),
+      _ => {
+        let whoami = account::whoami(matrix_client)?;
+        (whoami.user_id, whoami.device_id)
+      }
+    };
+    let device_id = match device_id {
+      Some(device_id) => device_id,
+      None => {
+        println!("Encryption needs a device, log in again to get one");
+        return Err(ApiError::Unknown);
+      }
+    };
+
+    let mut passphrase = String::new();
+    request_input("Crypto store passphrase", &mut passphrase);
+    let store = FileCryptoStore::open(file::file_name(&user_id, &device_id), &passphrase)
+      .map_err(store_error)?;
+
+    // Saving a new machine would replace another login's keys, which can't be recovered
+    let mut machine = match OlmMachine::load(&store).map_err(store_error)? {
+      Some(machine) if machine.user_id() == user_id && machine.device_id() == device_id => machine,
+      Some(machine) => {
+        println!(
+          "The crypto store holds the keys of {} ({}), not replacing them",
+          machine.user_id(),
+          machine.device_id()
+        );
+        return Err(ApiError::Unknown);
+      }
+      None => OlmMachine::new(&user_id, &device_id),
+    };
+    // Keep backing up room keys if there's a backup we trust
+    if let Ok(Some(version)) = backup::latest_version(matrix_client) {
+      machine.enable_backup(&version).ok();
+    }
+    *crypto = Some(Crypto { machine, store });
+  }
+
+  Ok(crypto.as_mut().expect("crypto was set up above"))
+}
+
+// Create and publish cross-signing keys, so devices verified from here are trusted everywhere
+pub fn setup_cross_signing(
+  matrix_client: &MatrixClient,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = setup(matrix_client, crypto)?;
+  if crypto.machine.has_cross_signing_keys() {
+    println!("Cross-signing is already set up on this device");
+    return Ok(());
+  }
+
+  let user_id = crypto.machine.user_id().to_string();
+  crypto
+    .machine
+    .bootstrap_cross_signing(matrix_client, |uia| password_auth(&user_id, uia))
+    .map_err(crypto_error)?;
+  // The published keys can't be replaced without them
+  crypto.save()?;
+
+  let device_id = crypto.machine.device_id().to_string();
+  if let Err(e) = crypto.machine.cross_sign_device(matrix_client, &device_id) {
+    println!(
+      "Couldn't sign this device, verify it from another one: {}",
+      e
+    );
+  }
+  crypto.save()
+}
+
+// Create a key backup, or restore room keys from one
+pub fn key_backup(
+  matrix_client: &MatrixClient,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = setup(matrix_client, crypto)?;
+
+  let mut action = String::new();
+  request_input(
+    "Create a backup (c), restore from the backup (r) or delete the backup (d)",
+    &mut action,
+  );
+  match action.as_ref() {
+    "c" => create_backup(matrix_client, crypto)?,
+    "r" => restore_backup(matrix_client, crypto)?,
+    "d" => {
+      if let Some(version) = backup::latest_version(matrix_client)? {
+        backup::delete_version(matrix_client, &version.version)?;
+        crypto.machine.disable_backup();
+      }
+    }
+    _ => return Err(ApiError::Unknown),
+  }
+  crypto.save()
+}
+
+// A new backup whose key is kept in secret storage, so the recovery key or passphrase
+// restores it on any device
+fn create_backup(matrix_client: &MatrixClient, crypto: &mut Crypto) -> Result<(), ApiError> {
+  let user_id = crypto.machine.user_id().to_string();
+  // Replacing the default key would leave the secrets already stored under it unreadable by
+  // other clients, so an existing one is reused
+  let existing = secret_storage::default_key(matrix_client, &user_id).map_err(crypto_error)?;
+  let (storage_key, created) = match existing {
+    Some((key_id, description)) => {
+      let mut secret = String::new();
+      request_input("Recovery key or passphrase for secret storage", &mut secret);
+      let storage_key = SecretStorageKey::from_recovery_key(&key_id, description.clone(), &secret)
+        .or_else(|_| SecretStorageKey::from_passphrase(&key_id, description, &secret))
+        .map_err(crypto_error)?;
+      (storage_key, false)
+    }
+    None => {
+      let mut passphrase = String::new();
+      request_input(
+        "Passphrase to protect the backup (leave empty to only use a recovery key)",
+        &mut passphrase,
+      );
+      let storage_key = if passphrase.is_empty() {
+        SecretStorageKey::new(None)
+      } else {
+        SecretStorageKey::new(Some(&passphrase))
+      };
+      secret_storage::set_default_key(matrix_client, &user_id, &storage_key)
+        .map_err(crypto_error)?;
+      (storage_key, true)
+    }
+  };
+
+  let backup_key = BackupKey::new();
+  secret_storage::store_secret(
+    matrix_client,
+    &user_id,
+    &storage_key,
+    BACKUP_SECRET,
+    &backup_key.to_base64(),
+  )
+  .map_err(crypto_error)?;
+
+  let version = crypto
+    .machine
+    .create_backup(matrix_client, &backup_key, None)
+    .map_err(crypto_error)?;
+  println!("Created key backup version {}", version);
+  if created {
+    println!("Recovery key: {}", storage_key.recovery_key());
+    println!("Keep it somewhere safe, it's needed to restore the backup");
+  } else {
+    println!("The backup can be restored with the existing recovery key or passphrase");
+  }
+  Ok(())
+}
+
+fn restore_backup(matrix_client: &MatrixClient, crypto: &mut Crypto) -> Result<(), ApiError> {
+  let mut secret = String::new();
+  request_input("Recovery key or passphrase", &mut secret);
+
+  let backup_key = backup_key(matrix_client, crypto, &secret).map_err(crypto_error)?;
+  let imported = crypto
+    .machine
+    .restore_backup(matrix_client, &backup_key)
+    .map_err(crypto_error)?;
+  println!("Restored {} room keys", imported);
+  Ok(())
+}
+
+// The backup's key from secret storage, or for backups made without secret storage, the
+// recovery key or passphrase itself
+fn backup_key(
+  matrix_client: &MatrixClient,
+  crypto: &Crypto,
+  secret: &str,
+) -> Result<BackupKey, CryptoError> {
+  let user_id = crypto.machine.user_id();
+  if let Some((key_id, description)) = secret_storage::default_key(matrix_client, user_id)? {
+    let storage_key = SecretStorageKey::from_recovery_key(&key_id, description.clone(), secret)
+      .or_else(|_| SecretStorageKey::from_passphrase(&key_id, description, secret))?;
+    if let Some(key) =
+      secret_storage::get_secret(matrix_client, user_id, &storage_key, BACKUP_SECRET)?
+    {
+      return BackupKey::from_base64(&key);
+    }
+  }
+
+  if let Ok(key) = BackupKey::from_recovery_key(secret) {
+    return Ok(key);
+  }
+  let auth_data = match backup::latest_version(matrix_client)? {
+    Some(version) => version.auth_data,
+    None => return Err(CryptoError::Key(String::from("There is no key backup"))),
+  };
+  match (auth_data.private_key_salt, auth_data.private_key_iterations) {
+    (Some(salt), Some(iterations)) => Ok(BackupKey::from_passphrase(secret, &salt, iterations)),
+    _ => Err(CryptoError::Key(String::from(
+      "Not a recovery key for the backup",
+    ))),
+  }
+}
+
+// Write room keys to a passphrase protected file in the format other clients use
+pub fn export_room_keys(
+  matrix_client: &MatrixClient,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = setup(matrix_client, crypto)?;
+  let (path, passphrase) = request_key_file();
+
+  let keys = crypto.machine.export_room_keys();
+  let export = key_export::export_keys(&keys, &passphrase, key_export::DEFAULT_ROUNDS)
+    .map_err(crypto_error)?;
+  fs::write(&path, export).map_err(file_error)?;
+  println!("Exported {} room keys", keys.len());
+  Ok(())
+}
+
+// Read room keys exported by this or another client
+pub fn import_room_keys(
+  matrix_client: &MatrixClient,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = setup(matrix_client, crypto)?;
+  let (path, passphrase) = request_key_file();
+
+  let export = fs::read_to_string(&path).map_err(file_error)?;
+  let keys = key_export::import_keys(&export, &passphrase).map_err(crypto_error)?;
+  let imported = crypto.machine.import_room_keys(&keys);
+  println!("Imported {} of {} room keys", imported, keys.len());
+  crypto.save()
+}
+
+fn request_key_file() -> (String, String) {
+  let mut path = String::new();
+  request_input("File", &mut path);
+  let mut passphrase = String::new();
+  request_input("File passphrase", &mut passphrase);
+  (path, passphrase)
+}
+
+pub fn file_error(error: std::io::Error) -> ApiError {
+  println!("{}", error);
+  ApiError::Unknown
+}
@@ -0,0 +1,104 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::crypto::attachments;
+use matrix_api::crypto::attachments::EncryptedFile;
+use matrix_api::send_queue::SendQueue;
+use matrix_api::store::StateStore;
+use matrix_api::*;
+use serde_json::json;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+use crate::encryption::{crypto_error, file_error, Crypto};
+use crate::io::request_input;
+use crate::messages;
+use crate::rooms::store_error;
+
+// Upload a file and send it to a room, encrypted if the room is
+pub fn send_file(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  send_queue: &mut SendQueue,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+  let mut path = String::new();
+  request_input("File", &mut path);
+
+  let file = File::open(&path).map_err(file_error)?;
+  let size = file.metadata().map_err(file_error)?.len();
+  let name = Path::new(&path)
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_else(|| path.clone());
+
+  let encrypted = store
+    .state_event(&room_id, "m.room.encryption", "")
+    .map_err(store_error)?
+    .is_some();
+  let content = if encrypted {
+    let encrypted_file = attachments::upload_encrypted(matrix_client, file, size)?;
+    json!({ "msgtype": "m.file", "body": name, "file": encrypted_file, "info": { "size": size } })
+  } else {
+    let url = media::upload_reader(
+      matrix_client,
+      "application/octet-stream",
+      Some(&name),
+      file,
+      size,
+    )?;
+    json!({ "msgtype": "m.file", "body": name, "url": url, "info": { "size": size } })
+  };
+
+  let sender = messages::own_user_id(matrix_client)?;
+  send_queue
+    .enqueue(store, &sender, &room_id, "m.room.message", content)
+    .map_err(store_error)?;
+  messages::process_queue(matrix_client, store, send_queue, crypto)
+}
+
+// Download the file sent in a synced event, decrypting it if it was encrypted
+pub fn download_file(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+  let mut event_id = String::new();
+  request_input("Event ID", &mut event_id);
+  let mut path = String::new();
+  request_input("Save as", &mut path);
+
+  let timeline = store.timeline(&room_id).map_err(store_error)?;
+  let content = match timeline.iter().find(|e| e.event_id == event_id) {
+    Some(event) => event.content.clone(),
+    None => {
+      println!("The event hasn't been synced");
+      return Err(ApiError::Unknown);
+    }
+  };
+
+  match serde_json::from_value::<EncryptedFile>(content["file"].clone()) {
+    // Nothing is written until the hash has been checked, so a changed file never reaches
+    // the disk
+    Ok(encrypted_file) => {
+      let data =
+        attachments::download_verified(matrix_client, &encrypted_file).map_err(crypto_error)?;
+      fs::write(&path, data).map_err(file_error)?;
+    }
+    Err(_) => {
+      let url = content["url"].as_str().unwrap_or_default();
+      // Created once the download has started, so a failed one doesn't replace the file
+      let mut response = media::download(matrix_client, url)?;
+      let mut output = File::create(&path).map_err(file_error)?;
+      if let Err(error) = std::io::copy(&mut response, &mut output) {
+        fs::remove_file(&path).ok();
+        return Err(file_error(error));
+      }
+    }
+  }
+  println!("Saved to {}", path);
+  Ok(())
+}
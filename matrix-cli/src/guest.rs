@@ -0,0 +1,67 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::*;
+
+use crate::io::request_input;
+
+pub fn register_guest(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
+  let model = registration::GuestRegistrationModel {
+    initial_device_display_name: Some(String::from("cli")),
+  };
+  let response = registration::register_guest(matrix_client, model)?;
+
+  println!("Guest ID: {}", response.user_id);
+  matrix_client.set_access_token(response.access_token);
+  matrix_client.set_refresh_token(response.refresh_token);
+  matrix_client.set_user(response.user_id, Some(response.device_id));
+
+  Ok(())
+}
+
+pub fn peek(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+
+  let room = rooms::peek::initial_sync(matrix_client, &room_id)?;
+  let messages = match room.messages {
+    Some(messages) => messages.chunk,
+    None => Vec::new(),
+  };
+
+  for event in messages {
+    if let Some(body) = event.content["body"].as_str() {
+      println!("{}: {}", event.sender, body);
+    }
+  }
+
+  Ok(())
+}
+
+pub fn upgrade(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
+  let interactive_auth_model = registration::auth_request(matrix_client)?;
+  let auth = registration::auth_select_flow(interactive_auth_model);
+
+  let mut username = String::new();
+  request_input("Username", &mut username);
+
+  let mut password = String::new();
+  request_input("Password", &mut password);
+
+  let body = registration::RegistrationModel {
+    auth,
+    kind: registration::RegistrationKind::User,
+    username,
+    password,
+    initial_device_display_name: String::from("cli"),
+    refresh_token: None,
+    guest_access_token: None,
+  };
+
+  let response = registration::upgrade_guest(matrix_client, body)?;
+  println!("Upgraded to {}", response.user_id);
+  matrix_client.set_access_token(response.access_token);
+  matrix_client.set_refresh_token(response.refresh_token);
+  matrix_client.set_user(response.user_id, Some(response.device_id));
+
+  Ok(())
+}
@@ -11,7 +11,7 @@ pub fn list_rooms(matrix_client: &MatrixClient) -> Result<(), ApiError> {
   let response = rooms::public::list_public_rooms(&matrix_client, query)?;
 
   // Test to see if matrix client has set the access token
-  match &matrix_client.access_token {
+  match matrix_client.get_access_token() {
     Some(access_token) => println!("Access Token: {}", access_token),
     None => println!("No access token"),
   }
@@ -20,8 +20,10 @@ pub fn list_rooms(matrix_client: &MatrixClient) -> Result<(), ApiError> {
 
   for room in response.chunk {
     println!("----");
-    println!("Alias: {}", room.canonical_alias);
-    println!("Name: {}", room.name);
+    println!("Name: {}", room.display_name());
+    if let Some(alias) = &room.canonical_alias {
+      println!("Alias: {}", alias);
+    }
     println!("Members: {}", room.num_joined_members);
     println!("Room ID: {}", room.room_id);
     println!("----");
@@ -1,16 +1,20 @@
 use matrix_api::api::ApiError;
 use matrix_api::client::MatrixClient;
 use matrix_api::*;
+use std::time::Duration;
 
 use crate::io::request_input;
 
+// How long to wait for the browser to come back from SSO
+static SSO_TIMEOUT: Duration = Duration::from_secs(300);
+
 fn select_flow(flow_count: usize) -> usize {
   let mut selected_flow = String::new();
   request_input("Select flow", &mut selected_flow);
 
   match selected_flow.parse::<usize>() {
     Ok(i) => {
-      if i > flow_count {
+      if i >= flow_count {
         println!("invalid selection");
         return select_flow(flow_count);
       }
@@ -34,17 +38,61 @@ fn login_password(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
 
   let body = login::LoginModel {
     r#type: login_type,
-    identifier,
+    identifier: Some(identifier),
     device_id: String::from("0001"),
     initial_device_display_name: String::from("cli"),
+    refresh_token: Some(true),
   };
 
   let response = login::login(&matrix_client, body)?;
   matrix_client.set_access_token(response.access_token);
+  matrix_client.set_refresh_token(response.refresh_token);
+  matrix_client.set_user(response.user_id, Some(response.device_id));
 
   Ok(())
 }
 
+fn login_token(matrix_client: &mut MatrixClient, token: String) -> Result<(), ApiError> {
+  let body = login::LoginModel {
+    r#type: login::LoginType::Token { token },
+    identifier: None,
+    device_id: String::from("0001"),
+    initial_device_display_name: String::from("cli"),
+    refresh_token: Some(true),
+  };
+
+  let response = login::login(matrix_client, body)?;
+  matrix_client.set_access_token(response.access_token);
+  matrix_client.set_refresh_token(response.refresh_token);
+  matrix_client.set_user(response.user_id, Some(response.device_id));
+
+  Ok(())
+}
+
+fn login_sso(
+  matrix_client: &mut MatrixClient,
+  providers: &Option<Vec<login::IdentityProvider>>,
+) -> Result<(), ApiError> {
+  let idp_id = match providers {
+    Some(providers) if !providers.is_empty() => {
+      println!("Identity providers: ");
+      for (i, provider) in providers.iter().enumerate() {
+        println!("({}) - {}", i, provider.name);
+      }
+      Some(providers[select_flow(providers.len())].id.as_str())
+    }
+    _ => None,
+  };
+
+  let listener = sso::LoopbackListener::bind()?;
+  let url = sso::redirect_url(matrix_client, &listener.redirect_url()?, idp_id);
+  println!("Open this URL in your browser to log in:");
+  println!("{}", url);
+
+  let token = listener.wait_for_token(SSO_TIMEOUT)?;
+  login_token(matrix_client, token)
+}
+
 pub fn login_flow(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
   let flows = login::get_login_flows(&matrix_client)?;
   let flow_count = flows.flows.len();
@@ -53,10 +101,16 @@ pub fn login_flow(matrix_client: &mut MatrixClient) -> Result<(), ApiError> {
     println!("({}) - {}", i, flows.flows[i].r#type);
   }
 
-  let selected_flow = &flows.flows[select_flow(flow_count)].r#type;
+  let selected_flow = &flows.flows[select_flow(flow_count)];
 
-  match selected_flow {
+  match selected_flow.r#type {
     auth::AuthenticationTypes::Password => login_password(matrix_client)?,
+    auth::AuthenticationTypes::Sso => login_sso(matrix_client, &selected_flow.identity_providers)?,
+    auth::AuthenticationTypes::Token => {
+      let mut token = String::new();
+      request_input("Login token", &mut token);
+      login_token(matrix_client, token)?
+    }
     _ => {
       println!("Unsupported");
       login_flow(matrix_client)?
@@ -1,20 +1,57 @@
 extern crate matrix_api;
 use matrix_api::client::MatrixClient;
+use matrix_api::send_queue::SendQueue;
+use matrix_api::store::memory::MemoryStore;
 
+use crate::encryption::Crypto;
+
+mod account;
+mod bot;
 mod create_room;
+mod encryption;
+mod files;
+mod guest;
 mod io;
 mod list_public_rooms;
 mod login;
+mod messages;
+mod notifications;
 mod register;
+mod rooms;
+mod search;
+mod spaces;
+mod verify;
 
 pub static MATRIX_API_URL: &str = "http://my.matrix.host:8008";
 
 fn request_action() -> String {
     println!("Select ation:");
     println!("- register (r)");
+    println!("- register as guest (g)");
+    println!("- upgrade guest account (u)");
     println!("- login (l)");
+    println!("- restore session (t)");
+    println!("- who am i (i)");
+    println!("- change password (w)");
+    println!("- forgot password (f)");
+    println!("- deactivate account (x)");
     println!("- list public rooms (p)");
+    println!("- peek into room (k)");
     println!("- create room (c)");
+    println!("- manage spaces (j)");
+    println!("- search (s)");
+    println!("- notifications (n)");
+    println!("- sync rooms (y)");
+    println!("- send message (m)");
+    println!("- send to-device message (d)");
+    println!("- send file (a)");
+    println!("- download file (h)");
+    println!("- bot mode (b)");
+    println!("- verify device (v)");
+    println!("- set up cross-signing (o)");
+    println!("- key backup (e)");
+    println!("- export room keys (keys export)");
+    println!("- import room keys (keys import)");
     let mut action = String::new();
     io::request_input("", &mut action);
     action
@@ -22,14 +59,39 @@ fn request_action() -> String {
 
 fn select_action(
     matrix_client: &mut MatrixClient,
+    store: &mut MemoryStore,
+    send_queue: &mut SendQueue,
+    crypto: &mut Option<Crypto>,
     action: String,
 ) -> Result<(), matrix_api::api::ApiError> {
     match action.as_ref() {
         "r" => register::register_flow(matrix_client),
+        "g" => guest::register_guest(matrix_client),
+        "u" => guest::upgrade(matrix_client),
         "l" => login::login_flow(matrix_client),
+        "t" => account::restore_session(matrix_client),
+        "i" => account::whoami(matrix_client),
+        "w" => account::change_password(matrix_client),
+        "f" => account::forgot_password(matrix_client),
+        "x" => account::deactivate(matrix_client),
         "p" => list_public_rooms::list_rooms(matrix_client),
+        "k" => guest::peek(matrix_client),
         "c" => create_room::create(matrix_client),
-        _ => select_action(matrix_client, request_action()),
+        "j" => spaces::manage_spaces(matrix_client),
+        "s" => search::search(matrix_client),
+        "n" => notifications::list_notifications(matrix_client),
+        "y" => rooms::sync_rooms(matrix_client, store, send_queue, crypto),
+        "m" => messages::send_message(matrix_client, store, send_queue, crypto),
+        "d" => messages::send_to_device(matrix_client),
+        "a" => files::send_file(matrix_client, store, send_queue, crypto),
+        "h" => files::download_file(matrix_client, store),
+        "b" => bot::run(matrix_client),
+        "v" => verify::verify(matrix_client, store, crypto),
+        "o" => encryption::setup_cross_signing(matrix_client, crypto),
+        "e" => encryption::key_backup(matrix_client, crypto),
+        "keys export" => encryption::export_room_keys(matrix_client, crypto),
+        "keys import" => encryption::import_room_keys(matrix_client, crypto),
+        _ => select_action(matrix_client, store, send_queue, crypto, request_action()),
     }
 }
 
@@ -46,9 +108,16 @@ fn continue_check() -> bool {
 
 fn main() {
     let matrix_client = &mut MatrixClient::new(MATRIX_API_URL);
+    matrix_client.set_token_refresh_callback(Box::new(account::save_session));
+    let store = &mut MemoryStore::new();
+    let send_queue = &mut SendQueue::new();
+    let crypto = &mut None;
 
     loop {
-        match select_action(matrix_client, request_action()) {
+        match select_action(matrix_client, store, send_queue, crypto, request_action()) {
+            Err(ref e) if e.is_guest_access_forbidden() => {
+                println!("Guests can't do that, upgrade the account first (u)")
+            }
             Err(e) => println!("Error: {}", e),
             Ok(_) => println!("Success"),
         }
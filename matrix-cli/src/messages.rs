@@ -0,0 +1,112 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::send_queue::{SendOutcome, SendQueue};
+use matrix_api::store::StateStore;
+use matrix_api::to_device::DeviceMessages;
+use matrix_api::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::encryption;
+use crate::encryption::Crypto;
+use crate::io::request_input;
+use crate::rooms::store_error;
+
+pub fn print_outcomes(outcomes: Vec<SendOutcome>) {
+  for outcome in outcomes {
+    match outcome {
+      SendOutcome::Sent { txn_id, event_id } => println!("Sent {} as {}", txn_id, event_id),
+      SendOutcome::Retrying {
+        txn_id,
+        error,
+        delay,
+      } => println!(
+        "Couldn't send {} ({}), retrying in {}s",
+        txn_id,
+        error,
+        delay.as_secs()
+      ),
+      SendOutcome::Failed { txn_id, error } => println!("Failed to send {}: {}", txn_id, error),
+    }
+  }
+}
+
+// Queue a text message and try to send everything waiting in the queue
+pub fn send_message(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  send_queue: &mut SendQueue,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+  let mut body = String::new();
+  request_input("Message", &mut body);
+
+  let sender = own_user_id(matrix_client)?;
+  let content = rooms::send::text_content(&body);
+  let echo = send_queue
+    .enqueue(store, &sender, &room_id, "m.room.message", content)
+    .map_err(store_error)?;
+  println!("{} (sending): {}", echo.sender, body);
+
+  process_queue(matrix_client, store, send_queue, crypto)
+}
+
+// The logged in user, without a request when the client already knows, so messages can be
+// queued while offline
+pub fn own_user_id(matrix_client: &MatrixClient) -> Result<String, ApiError> {
+  match matrix_client.user_id() {
+    Some(user_id) => Ok(user_id.to_string()),
+    None => Ok(account::whoami(matrix_client)?.user_id),
+  }
+}
+
+// Try to send everything waiting in the queue. Queued events stay queued when the server
+// can't be reached, to be sent next time.
+pub fn process_queue(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  send_queue: &mut SendQueue,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = match encryption::setup(matrix_client, crypto) {
+    Ok(crypto) => crypto,
+    Err(e) => {
+      println!("Queued to send later, encryption couldn't be set up: {}", e);
+      return Ok(());
+    }
+  };
+
+  let outcomes = send_queue
+    .process(matrix_client, store, Some(&mut crypto.machine))
+    .map_err(store_error)?;
+  print_outcomes(outcomes);
+  // Room keys and sessions from this round, which can't be recovered if lost
+  crypto.save()
+}
+
+// Send an event with JSON content straight to one of a user's devices, or all of them
+pub fn send_to_device(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let mut event_type = String::new();
+  request_input("Event type", &mut event_type);
+  let mut user_id = String::new();
+  request_input("User id", &mut user_id);
+  let mut device_id = String::new();
+  request_input("Device id (leave empty for all devices)", &mut device_id);
+  let mut content = String::new();
+  request_input("Content (JSON)", &mut content);
+  let content = serde_json::from_str(&content).map_err(|_| ApiError::Serialization)?;
+
+  let mut messages = DeviceMessages::new();
+  if device_id.is_empty() {
+    to_device::add_all_devices(&mut messages, &user_id, content);
+  } else {
+    to_device::add_message(&mut messages, &user_id, &device_id, content);
+  }
+  let txn_id = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis())
+    .unwrap_or(0)
+    .to_string();
+  to_device::send_to_device(matrix_client, &event_type, &txn_id, &messages)
+}
@@ -0,0 +1,28 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::*;
+
+pub fn list_notifications(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let query = push::notifications::NotificationsQuery {
+    from: None,
+    limit: Some(20),
+    only: None,
+  };
+  let response = push::notifications::get_notifications(matrix_client, query)?;
+
+  for notification in response.notifications {
+    let event = notification.event;
+    println!("----");
+    println!("Room ID: {}", notification.room_id);
+    println!("Sender: {}", event.sender);
+    if let Some(body) = event.content["body"].as_str() {
+      println!("{}", body);
+    }
+    if !notification.read {
+      println!("(unread)");
+    }
+    println!("----");
+  }
+
+  Ok(())
+}
@@ -4,14 +4,51 @@ use matrix_api::*;
 
 use crate::io::request_input;
 
+// Keep asking for a username until one is free
+fn request_username(matrix_client: &MatrixClient) -> Result<String, ApiError> {
+  let mut username = String::new();
+  request_input("Username", &mut username);
+
+  match registration::check_username(matrix_client, &username)? {
+    registration::UsernameAvailability::Available => Ok(username),
+    registration::UsernameAvailability::InUse => {
+      println!("{} is already taken", username);
+      request_username(matrix_client)
+    }
+    registration::UsernameAvailability::Invalid => {
+      println!("{} is not a valid username", username);
+      request_username(matrix_client)
+    }
+    registration::UsernameAvailability::Exclusive => {
+      println!("{} is reserved", username);
+      request_username(matrix_client)
+    }
+  }
+}
+
+// Keep asking for a registration token until the server accepts one
+fn request_registration_token(matrix_client: &MatrixClient) -> Result<String, ApiError> {
+  let mut token = String::new();
+  request_input("Registration token", &mut token);
+
+  if registration::check_registration_token(matrix_client, &token)? {
+    Ok(token)
+  } else {
+    println!("{} is not a valid registration token", token);
+    request_registration_token(matrix_client)
+  }
+}
+
 pub fn register_flow(matrix_client: &MatrixClient) -> Result<(), ApiError> {
   let interactive_auth_model = registration::auth_request(&matrix_client)?;
 
   println!("step 1");
-  let auth = registration::auth_select_flow(interactive_auth_model);
+  let mut auth = registration::auth_select_flow(interactive_auth_model);
+  if auth.stage() == matrix_api::auth::REGISTRATION_TOKEN_STAGE {
+    auth.token = Some(request_registration_token(matrix_client)?);
+  }
 
-  let mut username = String::new();
-  request_input("Username", &mut username);
+  let username = request_username(matrix_client)?;
 
   let mut password = String::new();
   request_input("Password", &mut password);
@@ -24,6 +61,8 @@ pub fn register_flow(matrix_client: &MatrixClient) -> Result<(), ApiError> {
     username,
     password,
     initial_device_display_name: String::from("cli"),
+    refresh_token: None,
+    guest_access_token: None,
   };
 
   registration::register(&matrix_client, body)?;
@@ -0,0 +1,99 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::send_queue::SendQueue;
+use matrix_api::store::{StateStore, StoreError};
+use matrix_api::*;
+
+use crate::encryption;
+use crate::encryption::crypto_error;
+use crate::encryption::Crypto;
+use crate::messages::print_outcomes;
+
+pub fn store_error(error: StoreError) -> ApiError {
+  println!("{}", error);
+  ApiError::Unknown
+}
+
+// Sync into the store, carrying on from the last sync, and list the joined rooms
+pub fn sync_rooms(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  send_queue: &mut SendQueue,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = encryption::setup(matrix_client, crypto)?;
+  let query = sync::SyncQuery {
+    since: store.sync_token().map_err(store_error)?,
+    timeout: Some(0),
+    ..Default::default()
+  };
+  let mut response = sync::sync(matrix_client, query)?;
+  // Decrypt before storing so the timeline holds the plain events
+  crypto
+    .machine
+    .receive_sync(matrix_client, &mut response)
+    .map_err(crypto_error)?;
+  // Room keys and sessions from the sync, which can't be recovered if lost
+  crypto.save()?;
+  store.save_sync(&response).map_err(store_error)?;
+  send_queue
+    .reconcile(store, &response)
+    .map_err(store_error)?;
+  // Back online, so try anything which is still waiting to be sent
+  let outcomes = send_queue
+    .process(matrix_client, store, Some(&mut crypto.machine))
+    .map_err(store_error)?;
+  print_outcomes(outcomes);
+  // Sessions made to send the queue
+  crypto.save()?;
+  let own_user_id = crypto.machine.user_id().to_string();
+
+  // Events from other applications, the rest are handled by the OlmMachine
+  for event in to_device::events(&response) {
+    if let to_device::ToDeviceContent::Custom(content) = event.content {
+      let sender = event.sender.unwrap_or_default();
+      println!("{} sent {}: {}", sender, event.r#type, content);
+    }
+  }
+
+  for room_id in store.joined_rooms().map_err(store_error)? {
+    let state = store.room_state(&room_id).map_err(store_error)?;
+    let unread = store.unread_counts(&room_id).map_err(store_error)?;
+    println!("----");
+    println!("{}", state.display_name(&own_user_id));
+    println!("Room ID: {}", room_id);
+    if let Some(avatar_url) = state.avatar_url(&own_user_id) {
+      println!("Avatar: {}", avatar_url);
+    }
+    if unread.notification_count > 0 {
+      println!(
+        "Unread: {} ({} highlighted)",
+        unread.notification_count, unread.highlight_count
+      );
+    }
+    let timeline = store.timeline(&room_id).map_err(store_error)?;
+    if let Some(event) = timeline.iter().rev().find(|e| e.r#type == "m.room.message") {
+      if let Some(body) = event.content["body"].as_str() {
+        println!("{}: {}", state.member_display_name(&event.sender), body);
+      }
+    }
+    for pending in store.pending_events().map_err(store_error)? {
+      if pending.room_id != room_id {
+        continue;
+      }
+      let status = match &pending.failure {
+        Some(error) => format!("failed: {}", error),
+        None => String::from("sending"),
+      };
+      println!(
+        "{} ({}): {}",
+        state.member_display_name(&pending.sender),
+        status,
+        pending.content["body"].as_str().unwrap_or_default()
+      );
+    }
+    println!("----");
+  }
+
+  Ok(())
+}
@@ -0,0 +1,99 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::events::RoomEvent;
+use matrix_api::*;
+use std::collections::HashMap;
+
+use crate::io::request_input;
+
+fn room_name(state: &Option<HashMap<String, Vec<RoomEvent>>>, room_id: &str) -> String {
+  let events = match state {
+    Some(state) => state.get(room_id),
+    None => None,
+  };
+
+  events
+    .and_then(|events| events.iter().find(|e| e.r#type == "m.room.name"))
+    .and_then(|e| e.content["name"].as_str())
+    .map(String::from)
+    .unwrap_or_else(|| room_id.to_string())
+}
+
+// Wrap any highlighted words in the message in bold
+fn highlight(body: &str, highlights: &[String]) -> String {
+  let highlights: Vec<String> = highlights.iter().map(|h| h.to_lowercase()).collect();
+
+  body
+    .split(' ')
+    .map(|word| {
+      let bare = word
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase();
+      if highlights.contains(&bare) {
+        format!("\x1b[1m{}\x1b[0m", word)
+      } else {
+        word.to_string()
+      }
+    })
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+pub fn search(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let mut search_term = String::new();
+  request_input("Search term", &mut search_term);
+
+  let mut next_batch = None;
+
+  loop {
+    let request = search::SearchRequest {
+      search_categories: search::SearchCategories {
+        room_events: search::RoomEventsCriteria {
+          search_term: search_term.clone(),
+          keys: Some(vec![search::SearchKey::ContentBody]),
+          filter: None,
+          order_by: Some(search::OrderBy::Recent),
+          event_context: None,
+          include_state: Some(true),
+          groupings: None,
+        },
+      },
+    };
+    let query = search::SearchQuery { next_batch };
+    let response = search::search(matrix_client, query, request)?;
+
+    let results = match response.search_categories.room_events {
+      Some(results) => results,
+      None => return Ok(()),
+    };
+
+    if let Some(count) = results.count {
+      println!("total count: {}", count);
+    }
+
+    for hit in &results.results {
+      let event = &hit.result;
+      let room_id = event.room_id.as_ref().map_or("", String::as_str);
+      let body = event.content["body"].as_str().unwrap_or("");
+
+      println!("----");
+      println!("Room: {}", room_name(&results.state, room_id));
+      println!("Sender: {}", event.sender);
+      println!("{}", highlight(body, &results.highlights));
+      println!("----");
+    }
+
+    next_batch = results.next_batch;
+    if next_batch.is_none() {
+      break;
+    }
+
+    let mut more = String::new();
+    request_input("More results? (y/n)", &mut more);
+    if more != "y" && more != "Y" {
+      break;
+    }
+  }
+
+  Ok(())
+}
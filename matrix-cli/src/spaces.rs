@@ -0,0 +1,94 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::rooms::spaces;
+use matrix_api::rooms::spaces::{SpaceChildContent, SpaceParentContent};
+
+use crate::io::request_input;
+
+// Add rooms to a space, remove them or show everything in it
+pub fn manage_spaces(matrix_client: &MatrixClient) -> Result<(), ApiError> {
+  let mut space_id = String::new();
+  request_input("Space ID", &mut space_id);
+
+  let mut action = String::new();
+  request_input(
+    "Add a room (a), remove a room (r) or show the hierarchy (h)",
+    &mut action,
+  );
+  match action.as_ref() {
+    "a" => add_room(matrix_client, &space_id),
+    "r" => remove_room(matrix_client, &space_id),
+    "h" => show_hierarchy(matrix_client, &space_id),
+    _ => Err(ApiError::Unknown),
+  }
+}
+
+fn add_room(matrix_client: &MatrixClient, space_id: &str) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+  let mut suggested = String::new();
+  request_input("Suggest it to members? (y/n)", &mut suggested);
+
+  let via: Vec<String> = spaces::server_name(&room_id)
+    .map(String::from)
+    .into_iter()
+    .collect();
+  let child = SpaceChildContent {
+    via: via.clone(),
+    order: None,
+    suggested: suggested == "y" || suggested == "Y",
+  };
+  spaces::add_child(matrix_client, space_id, &room_id, &child)?;
+
+  // The parent event needs power in the room too, the space still lists it without one
+  let parent = SpaceParentContent {
+    via: spaces::server_name(space_id)
+      .map(String::from)
+      .into_iter()
+      .collect(),
+    canonical: true,
+  };
+  if let Err(e) = spaces::set_parent(matrix_client, &room_id, space_id, &parent) {
+    println!("Couldn't point the room back to the space: {}", e);
+  }
+  Ok(())
+}
+
+fn remove_room(matrix_client: &MatrixClient, space_id: &str) -> Result<(), ApiError> {
+  let mut room_id = String::new();
+  request_input("Room ID", &mut room_id);
+
+  spaces::remove_child(matrix_client, space_id, &room_id)?;
+  if let Err(e) = spaces::remove_parent(matrix_client, &room_id, space_id) {
+    println!("Couldn't remove the space from the room: {}", e);
+  }
+  Ok(())
+}
+
+fn show_hierarchy(matrix_client: &MatrixClient, space_id: &str) -> Result<(), ApiError> {
+  let mut max_depth = String::new();
+  request_input("Max depth (blank for no limit)", &mut max_depth);
+
+  let rooms = spaces::full_hierarchy(matrix_client, space_id, max_depth.parse().ok())?;
+  for room in rooms {
+    let name = room
+      .name
+      .clone()
+      .or_else(|| room.canonical_alias.clone())
+      .unwrap_or_else(|| room.room_id.clone());
+    if room.is_space() {
+      println!(
+        "{} (space, {} rooms) - {}",
+        name,
+        room.children().len(),
+        room.room_id
+      );
+    } else {
+      println!(
+        "{} ({} members) - {}",
+        name, room.num_joined_members, room.room_id
+      );
+    }
+  }
+  Ok(())
+}
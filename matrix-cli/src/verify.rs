@@ -0,0 +1,191 @@
+use matrix_api::api::ApiError;
+use matrix_api::client::MatrixClient;
+use matrix_api::crypto::verification::cancel_code;
+use matrix_api::crypto::verification::sas::SasState;
+use matrix_api::store::StateStore;
+use matrix_api::*;
+
+use crate::encryption;
+use crate::encryption::{crypto_error, Crypto};
+use crate::io::request_input;
+use crate::rooms::store_error;
+
+// Verify one of another user's devices, or one of our own, by comparing emoji
+pub fn verify(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  crypto: &mut Option<Crypto>,
+) -> Result<(), ApiError> {
+  let crypto = encryption::setup(matrix_client, crypto)?;
+
+  let mut action = String::new();
+  request_input(
+    "Request verification of a device (d), in a room (r) or wait for a request (w)",
+    &mut action,
+  );
+  let mut flow_id = match action.as_ref() {
+    "d" => {
+      let mut user_id = String::new();
+      request_input("User id", &mut user_id);
+      let mut device_id = String::new();
+      request_input("Device id", &mut device_id);
+      let flow_id = crypto
+        .machine
+        .request_verification(matrix_client, &user_id, &device_id)
+        .map_err(crypto_error)?;
+      Some(flow_id)
+    }
+    "r" => {
+      let mut room_id = String::new();
+      request_input("Room id", &mut room_id);
+      let mut user_id = String::new();
+      request_input("User id", &mut user_id);
+      let flow_id = crypto
+        .machine
+        .request_room_verification(matrix_client, &room_id, &user_id)
+        .map_err(crypto_error)?;
+      Some(flow_id)
+    }
+    _ => None,
+  };
+  println!("Waiting for the other side...");
+
+  loop {
+    sync_once(matrix_client, store, crypto)?;
+
+    let id = match &flow_id {
+      Some(id) => id.clone(),
+      None => match accept_incoming(matrix_client, crypto)? {
+        Some(id) => {
+          flow_id = Some(id.clone());
+          id
+        }
+        None => continue,
+      },
+    };
+
+    let state = match crypto.machine.verification(&id) {
+      Some(sas) => sas.state().clone(),
+      None => return Err(ApiError::Unknown),
+    };
+    match state {
+      SasState::KeysExchanged => compare(matrix_client, crypto, &id)?,
+      SasState::Done => {
+        println!("Device verified");
+        break;
+      }
+      SasState::Cancelled { code, reason } => {
+        println!("Verification cancelled: {} ({})", reason, code);
+        break;
+      }
+      _ => {}
+    }
+  }
+
+  crypto.save()
+}
+
+// Sync once, waiting a while for the other side to answer
+fn sync_once(
+  matrix_client: &MatrixClient,
+  store: &mut dyn StateStore,
+  crypto: &mut Crypto,
+) -> Result<(), ApiError> {
+  let query = sync::SyncQuery {
+    since: store.sync_token().map_err(store_error)?,
+    timeout: Some(30000),
+    ..Default::default()
+  };
+  let mut response = sync::sync(matrix_client, query)?;
+  crypto
+    .machine
+    .receive_sync(matrix_client, &mut response)
+    .map_err(crypto_error)?;
+  // Saved first, the sync is fetched again if storing it fails but its keys aren't sent again
+  crypto.save()?;
+  store.save_sync(&response).map_err(store_error)
+}
+
+// Ask the user about the first request which came in, if any
+fn accept_incoming(
+  matrix_client: &MatrixClient,
+  crypto: &mut Crypto,
+) -> Result<Option<String>, ApiError> {
+  let request = crypto
+    .machine
+    .verifications()
+    .find(|sas| *sas.state() == SasState::RequestReceived)
+    .map(|sas| {
+      (
+        sas.flow_id().to_string(),
+        sas.other_user_id().to_string(),
+        sas
+          .other_device_id()
+          .unwrap_or("unknown device")
+          .to_string(),
+      )
+    });
+  let (flow_id, user_id, device_id) = match request {
+    Some(request) => request,
+    None => return Ok(None),
+  };
+
+  let mut answer = String::new();
+  request_input(
+    &format!("Verify with {} ({})? (y/n)", user_id, device_id),
+    &mut answer,
+  );
+  if answer == "y" {
+    crypto
+      .machine
+      .accept_verification(matrix_client, &flow_id)
+      .map_err(crypto_error)?;
+    Ok(Some(flow_id))
+  } else {
+    crypto
+      .machine
+      .cancel_verification(matrix_client, &flow_id, cancel_code::USER, "Declined")
+      .map_err(crypto_error)?;
+    Ok(None)
+  }
+}
+
+// Show the SAS and ask whether the other device shows the same
+fn compare(
+  matrix_client: &MatrixClient,
+  crypto: &mut Crypto,
+  flow_id: &str,
+) -> Result<(), ApiError> {
+  let sas = match crypto.machine.verification(flow_id) {
+    Some(sas) => sas,
+    None => return Err(ApiError::Unknown),
+  };
+  if let Some(emoji) = sas.emoji() {
+    for e in emoji {
+      print!("{} {}  ", e.symbol, e.name);
+    }
+    println!();
+  }
+  if let Some((first, second, third)) = sas.decimals() {
+    println!("{} {} {}", first, second, third);
+  }
+
+  let mut answer = String::new();
+  request_input("Does the other device show the same? (y/n)", &mut answer);
+  if answer == "y" {
+    crypto
+      .machine
+      .confirm_verification(matrix_client, flow_id)
+      .map_err(crypto_error)
+  } else {
+    crypto
+      .machine
+      .cancel_verification(
+        matrix_client,
+        flow_id,
+        cancel_code::MISMATCHED_SAS,
+        "The short authentication strings didn't match",
+      )
+      .map_err(crypto_error)
+  }
+}
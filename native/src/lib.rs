@@ -2,19 +2,67 @@
 extern crate neon;
 extern crate matrix_api;
 extern crate reqwest;
+extern crate serde_json;
 
 use matrix_api::api::ApiError;
 use matrix_api::client::MatrixClient;
+use matrix_api::crypto::key_export;
+use matrix_api::crypto::machine::OlmMachine;
+use matrix_api::crypto::store::file;
+use matrix_api::crypto::store::file::FileCryptoStore;
+use matrix_api::crypto::verification;
+use matrix_api::crypto::verification::cancel_code;
+use matrix_api::crypto::verification::emoji::Emoji;
+use matrix_api::crypto::verification::sas::SasState;
+use matrix_api::crypto::CryptoError;
+use matrix_api::handlers::EventHandler;
 use matrix_api::*;
 use neon::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub static MATRIX_API_URL: &str = "http://my.matrix.host:8008";
 
-fn register_flow(username: String, password: String) -> Result<(), matrix_api::api::ApiError> {
+fn register_flow(
+    username: String,
+    password: String,
+    registration_token: Option<String>,
+) -> Result<(), String> {
     let matrix_client = MatrixClient::new(MATRIX_API_URL);
-    let interactive_auth_model = registration::auth_request(&matrix_client)?;
 
-    let auth = registration::auth_select_flow(interactive_auth_model);
+    match registration::check_username(&matrix_client, &username).map_err(api_error_message)? {
+        registration::UsernameAvailability::Available => (),
+        registration::UsernameAvailability::InUse => {
+            return Err(format!("The username {} is already taken.", username))
+        }
+        registration::UsernameAvailability::Invalid => {
+            return Err(format!("The username {} is not valid.", username))
+        }
+        registration::UsernameAvailability::Exclusive => {
+            return Err(format!("The username {} is reserved.", username))
+        }
+    }
+
+    if let Some(ref token) = registration_token {
+        if !registration::check_registration_token(&matrix_client, token)
+            .map_err(api_error_message)?
+        {
+            return Err(format!("The registration token {} is not valid.", token));
+        }
+    }
+
+    let interactive_auth_model =
+        registration::auth_request(&matrix_client).map_err(api_error_message)?;
+    let mut auth = registration::auth_select_flow(interactive_auth_model);
+    if auth.stage() == matrix_api::auth::REGISTRATION_TOKEN_STAGE {
+        if registration_token.is_none() {
+            return Err(String::from("A registration token is needed to register."));
+        }
+        auth.token = registration_token;
+    }
 
     println!("{}, {}", username, password);
 
@@ -24,9 +72,11 @@ fn register_flow(username: String, password: String) -> Result<(), matrix_api::a
         username,
         password,
         initial_device_display_name: String::from("cli"),
+        refresh_token: None,
+        guest_access_token: None,
     };
 
-    registration::register(&matrix_client, body)?;
+    registration::register(&matrix_client, body).map_err(api_error_message)?;
 
     Ok(())
 }
@@ -45,36 +95,959 @@ fn cx_response(
     (cx, response_obj)
 }
 
+fn api_error_message(e: ApiError) -> String {
+    match e {
+        ApiError::Network { kind: _, message } => message,
+        ApiError::Http(status_code, message) => match message {
+            Some(m) => String::from(m),
+            None => status_code.to_string(),
+        },
+        ApiError::Response(_, m) => m.message,
+        ApiError::InteractiveAuth(_) => String::from("Additional authentication is required."),
+        ApiError::Serialization => String::from("There was a serialization error m8."),
+        ApiError::Unknown => String::from("This error is unknown, please panic."),
+    }
+}
+
+// Registers the user, the third argument is the registration token if the server asks for one
 fn register_user(mut cx: FunctionContext) -> JsResult<JsObject> {
     let username = cx.argument::<JsString>(0)?.value();
     let password = cx.argument::<JsString>(1)?.value();
+    let registration_token = match cx.argument_opt(2) {
+        Some(token) => token.downcast::<JsString>().ok().map(|token| token.value()),
+        None => None,
+    };
 
-    match register_flow(username, password) {
+    match register_flow(username, password, registration_token) {
         Err(e) => {
-            let (mut cx, response) = match e {
-                ApiError::Network { kind: _, message } => cx_response(cx, false, message),
-                ApiError::Http(status_code, message) => match message {
-                    Some(m) => cx_response(cx, false, String::from(m)),
-                    None => cx_response(cx, false, status_code.to_string()),
-                },
-                ApiError::Response(_, m) => cx_response(cx, false, m.message),
-                ApiError::Serialization => cx_response(
-                    cx,
-                    false,
-                    String::from("There was a serialization error m8."),
-                ),
-                ApiError::Unknown => cx_response(
-                    cx,
-                    false,
-                    String::from("This error is unknown, please panic."),
-                ),
-            };
+            let (mut cx, response) = cx_response(cx, false, e);
             return cx.throw(response);
         }
         Ok(_) => Ok(cx_response(cx, true, format!("")).1),
     }
 }
 
+// Waits in the background for the user to follow the reset link, then sets the new password
+struct ResetPasswordTask {
+    creds: threepid::ThreepidCredentials,
+    new_password: String,
+}
+
+impl Task for ResetPasswordTask {
+    type Output = ();
+    type Error = String;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<(), String> {
+        let matrix_client = MatrixClient::new(MATRIX_API_URL);
+        account::reset_password(
+            &matrix_client,
+            self.creds.clone(),
+            self.new_password.clone(),
+            Some(true),
+            Duration::from_secs(5),
+            Duration::from_secs(600),
+        )
+        .map_err(api_error_message)
+    }
+
+    fn complete(self, mut cx: TaskContext, result: Result<(), String>) -> JsResult<JsUndefined> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(message) => cx.throw_error(message),
+        }
+    }
+}
+
+// Emails a reset link and returns straight away, `callback(err)` is called once the
+// password has been changed or the link was not followed in time
+fn reset_password(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let email = cx.argument::<JsString>(0)?.value();
+    let new_password = cx.argument::<JsString>(1)?.value();
+    let callback = cx.argument::<JsFunction>(2)?;
+
+    let matrix_client = MatrixClient::new(MATRIX_API_URL);
+    let mut session = threepid::ThreepidSession::new(login::ThirdPartyMedium::Email);
+    if let Err(e) = account::request_password_reset(&matrix_client, &mut session, &email) {
+        let (mut cx, response) = cx_response(cx, false, api_error_message(e));
+        return cx.throw(response);
+    }
+
+    if let Some(creds) = session.credentials() {
+        let task = ResetPasswordTask {
+            creds,
+            new_password,
+        };
+        task.schedule(callback);
+    }
+
+    Ok(cx_response(cx, true, format!("A reset link has been sent to {}", email)).1)
+}
+
+// Name and avatar for a room, from its state events and sync summary as JSON strings
+fn room_display(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let room_id = cx.argument::<JsString>(0)?.value();
+    let state_json = cx.argument::<JsString>(1)?.value();
+    let summary_json = cx.argument::<JsString>(2)?.value();
+    let own_user_id = cx.argument::<JsString>(3)?.value();
+
+    let events: Vec<events::RoomEvent> = match serde_json::from_str(&state_json) {
+        Ok(events) => events,
+        Err(e) => return cx.throw_error(format!("Invalid room state: {}", e)),
+    };
+    let summary: sync::RoomSummary = match serde_json::from_str(&summary_json) {
+        Ok(summary) => summary,
+        Err(e) => return cx.throw_error(format!("Invalid room summary: {}", e)),
+    };
+    let state = rooms::display::RoomState::new(&room_id, events, summary);
+
+    let response_obj = JsObject::new(&mut cx);
+    let name = cx.string(state.display_name(&own_user_id));
+    response_obj.set(&mut cx, "name", name)?;
+    if let Some(avatar_url) = state.avatar_url(&own_user_id) {
+        let avatar_url = cx.string(avatar_url);
+        response_obj.set(&mut cx, "avatar_url", avatar_url)?;
+    }
+    Ok(response_obj)
+}
+
+// An event picked up by the event handlers, passed to JS as JSON
+struct SyncedEvent {
+    kind: &'static str,
+    room_id: Option<String>,
+    event: String,
+}
+
+type SyncedEvents = Rc<RefCell<Vec<SyncedEvent>>>;
+
+fn collect(events: &SyncedEvents, kind: &'static str, room_id: Option<&str>, event: String) {
+    events.borrow_mut().push(SyncedEvent {
+        kind,
+        room_id: room_id.map(String::from),
+        event,
+    });
+}
+
+// The tokens JS signs in with, passed as `{ access_token, refresh_token }`. Functions taking
+// one add a new `session` to their result, or to the error, when the client refreshed the
+// tokens. Save it in place of the old one, which no longer works.
+struct Session {
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+// A task's result along with the session, if it was refreshed
+type Refreshed<T> = (T, Option<Session>);
+
+fn session_argument(cx: &mut FunctionContext, i: i32) -> NeonResult<Session> {
+    let session = cx.argument::<JsObject>(i)?;
+    let access_token = session
+        .get(cx, "access_token")?
+        .downcast_or_throw::<JsString, _>(cx)?
+        .value();
+    let refresh_token = match session.get(cx, "refresh_token")?.downcast::<JsString>() {
+        Ok(refresh_token) => Some(refresh_token.value()),
+        Err(_) => None,
+    };
+    Ok(Session {
+        access_token,
+        refresh_token,
+    })
+}
+
+// Runs f with a client signed in with the session, noting any tokens it's given meanwhile
+fn with_session<T, F>(session: &Session, f: F) -> Result<Refreshed<T>, Refreshed<String>>
+where
+    F: FnOnce(&mut MatrixClient) -> Result<T, String>,
+{
+    let refreshed: Rc<RefCell<Option<Session>>> = Rc::new(RefCell::new(None));
+    let mut matrix_client = MatrixClient::new(MATRIX_API_URL);
+    matrix_client.set_access_token(session.access_token.clone());
+    matrix_client.set_refresh_token(session.refresh_token.clone());
+    let on_refresh = refreshed.clone();
+    matrix_client.set_token_refresh_callback(Box::new(move |access_token, refresh_token| {
+        *on_refresh.borrow_mut() = Some(Session {
+            access_token: access_token.to_string(),
+            refresh_token: refresh_token.map(String::from),
+        });
+    }));
+
+    let result = f(&mut matrix_client);
+    let refreshed = refreshed.replace(None);
+    match result {
+        Ok(output) => Ok((output, refreshed)),
+        Err(message) => Err((message, refreshed)),
+    }
+}
+
+fn set_session<'a, C: Context<'a>, O: Object>(
+    cx: &mut C,
+    obj: Handle<O>,
+    session: Option<Session>,
+) -> NeonResult<()> {
+    if let Some(session) = session {
+        let session_obj = JsObject::new(cx);
+        let access_token = cx.string(session.access_token);
+        session_obj.set(cx, "access_token", access_token)?;
+        if let Some(refresh_token) = session.refresh_token {
+            let refresh_token = cx.string(refresh_token);
+            session_obj.set(cx, "refresh_token", refresh_token)?;
+        }
+        obj.set(cx, "session", session_obj)?;
+    }
+    Ok(())
+}
+
+// Throws a task's error, with the refreshed session on it
+fn throw_refreshed<'a, T: Value>(
+    mut cx: TaskContext<'a>,
+    (message, session): Refreshed<String>,
+) -> JsResult<'a, T> {
+    let error = cx.error(message)?;
+    set_session(&mut cx, error, session)?;
+    cx.throw(error)
+}
+
+// Syncs once, decrypting and collecting the events from the client's handlers
+struct SyncTask {
+    session: Session,
+    passphrase: String,
+    since: Option<String>,
+}
+
+impl Task for SyncTask {
+    type Output = Refreshed<(String, Vec<SyncedEvent>)>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            let events: SyncedEvents = Rc::new(RefCell::new(Vec::new()));
+            let collected = events.clone();
+            matrix_client.add_event_handler(EventHandler::Message(Box::new(
+                move |_, room, event| {
+                    let json = serde_json::to_string(event).unwrap_or_default();
+                    collect(&collected, "message", Some(room.room_id), json)
+                },
+            )));
+            let collected = events.clone();
+            matrix_client.add_event_handler(EventHandler::Member(Box::new(
+                move |_, room, event, _| {
+                    let json = serde_json::to_string(event).unwrap_or_default();
+                    collect(&collected, "member", Some(room.room_id), json)
+                },
+            )));
+            let collected = events.clone();
+            // Member events also reach the State handlers, they're passed on once as "member"
+            matrix_client.add_event_handler(EventHandler::State(Box::new(
+                move |_, room, event| {
+                    if event.r#type == "m.room.member" {
+                        return;
+                    }
+                    let json = serde_json::to_string(event).unwrap_or_default();
+                    collect(&collected, "state", Some(room.room_id), json)
+                },
+            )));
+            let collected = events.clone();
+            matrix_client.add_event_handler(EventHandler::Ephemeral(Box::new(
+                move |_, room, event| {
+                    let json = serde_json::to_string(event).unwrap_or_default();
+                    collect(&collected, "ephemeral", Some(room.room_id), json)
+                },
+            )));
+            let collected = events.clone();
+            matrix_client.add_event_handler(EventHandler::ToDevice(Box::new(move |_, event| {
+                let json = serde_json::to_string(event).unwrap_or_default();
+                collect(&collected, "to_device", None, json)
+            })));
+            let collected = events.clone();
+            matrix_client.add_event_handler(EventHandler::AccountData(Box::new(
+                move |_, room, event| {
+                    let json = serde_json::to_string(event).unwrap_or_default();
+                    collect(&collected, "account_data", room.map(|r| r.room_id), json)
+                },
+            )));
+
+            let query = sync::SyncQuery {
+                since: self.since.clone(),
+                timeout: Some(30000),
+                ..Default::default()
+            };
+            let mut response = sync::sync(matrix_client, query).map_err(api_error_message)?;
+            // Decrypt before dispatching so the handlers get the plain events
+            with_crypto(matrix_client, Some(&self.passphrase), |crypto| {
+                crypto
+                    .machine
+                    .receive_sync(matrix_client, &mut response)
+                    .map_err(crypto_error_message)?;
+                // Room keys and sessions from the sync, which can't be recovered if lost
+                crypto
+                    .machine
+                    .save(&mut crypto.store)
+                    .map_err(|e| e.to_string())
+            })?;
+            handlers::dispatch(matrix_client, &response);
+
+            let synced = events.replace(Vec::new());
+            Ok((response.next_batch, synced))
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let ((next_batch, synced), session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+
+        let events = JsArray::new(&mut cx, synced.len() as u32);
+        for (i, synced_event) in synced.into_iter().enumerate() {
+            let event_obj = JsObject::new(&mut cx);
+            let kind = cx.string(synced_event.kind);
+            event_obj.set(&mut cx, "kind", kind)?;
+            if let Some(room_id) = synced_event.room_id {
+                let room_id = cx.string(room_id);
+                event_obj.set(&mut cx, "room_id", room_id)?;
+            }
+            let event = cx.string(synced_event.event);
+            event_obj.set(&mut cx, "event", event)?;
+            events.set(&mut cx, i as u32, event_obj)?;
+        }
+
+        let response_obj = JsObject::new(&mut cx);
+        let next_batch = cx.string(next_batch);
+        response_obj.set(&mut cx, "next_batch", next_batch)?;
+        response_obj.set(&mut cx, "events", events)?;
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+// Long polls for new events, `callback(err, { next_batch, events })` is called with each
+// event's kind, room and JSON. Pass next_batch back in as since for the next sync. The
+// passphrase opens the device's crypto store, encrypted events are passed on decrypted.
+fn sync_events(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let passphrase = cx.argument::<JsString>(1)?.value();
+    let since = match cx.argument::<JsValue>(2)?.downcast::<JsString>() {
+        Ok(since) => Some(since.value()),
+        Err(_) => None,
+    };
+    let callback = cx.argument::<JsFunction>(3)?;
+
+    let task = SyncTask {
+        session,
+        passphrase,
+        since,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// The device's crypto, kept between the sync and verification tasks
+struct DeviceCrypto {
+    machine: OlmMachine,
+    store: FileCryptoStore,
+}
+
+static DEVICE_CRYPTO: Mutex<Option<DeviceCrypto>> = Mutex::new(None);
+
+fn crypto_error_message(e: CryptoError) -> String {
+    match e {
+        CryptoError::Api(e) => api_error_message(e),
+        e => e.to_string(),
+    }
+}
+
+impl DeviceCrypto {
+    // Load the logged in device's keys from the crypto store
+    fn open(matrix_client: &MatrixClient, passphrase: &str) -> Result<DeviceCrypto, String> {
+        let whoami = account::whoami(matrix_client).map_err(api_error_message)?;
+        let device_id = match whoami.device_id {
+            Some(device_id) => device_id,
+            None => return Err(String::from("Log in again to get a device.")),
+        };
+        let store = FileCryptoStore::open(file::file_name(&whoami.user_id, &device_id), passphrase)
+            .map_err(|e| e.to_string())?;
+        // Saving a new machine would replace another login's keys, which can't be recovered
+        let machine = match OlmMachine::load(&store).map_err(|e| e.to_string())? {
+            Some(machine)
+                if machine.user_id() == whoami.user_id && machine.device_id() == device_id =>
+            {
+                machine
+            }
+            Some(machine) => {
+                return Err(format!(
+                    "The crypto store holds the keys of {} ({}).",
+                    machine.user_id(),
+                    machine.device_id()
+                ))
+            }
+            None => OlmMachine::new(&whoami.user_id, &device_id),
+        };
+        Ok(DeviceCrypto { machine, store })
+    }
+}
+
+// The lock is only held for a step at a time, so a verification waiting on the other side
+// doesn't block the other tasks
+fn with_crypto<T, F>(
+    matrix_client: &MatrixClient,
+    passphrase: Option<&str>,
+    f: F,
+) -> Result<T, String>
+where
+    F: FnOnce(&mut DeviceCrypto) -> Result<T, String>,
+{
+    let mut crypto = DEVICE_CRYPTO
+        .lock()
+        .map_err(|_| String::from("Encryption failed part way through."))?;
+    if crypto.is_none() {
+        match passphrase {
+            Some(passphrase) => *crypto = Some(DeviceCrypto::open(matrix_client, passphrase)?),
+            None => return Err(String::from("There is no verification in progress.")),
+        }
+    }
+    f(crypto.as_mut().unwrap())
+}
+
+// How often a verification looks for what the sync task has received
+static POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// How long to wait on the other side, as long as the verification itself may take
+fn deadline() -> Instant {
+    Instant::now() + Duration::from_millis(verification::TIMEOUT_MS as u64)
+}
+
+// Wait until the verification reaches a state `until` accepts. The events come in through
+// the sync task, which has to keep running meanwhile.
+fn wait_for(
+    matrix_client: &MatrixClient,
+    flow_id: &str,
+    until: fn(&SasState) -> bool,
+) -> Result<SasState, String> {
+    let deadline = deadline();
+    loop {
+        let state = with_crypto(matrix_client, None, |crypto| {
+            match crypto.machine.verification(flow_id) {
+                Some(sas) => Ok(sas.state().clone()),
+                None => Err(String::from("The verification was not found.")),
+            }
+        })?;
+        if until(&state) {
+            return Ok(state);
+        }
+        if Instant::now() >= deadline {
+            return Err(String::from("The other device stopped answering."));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Wait until someone asks to verify, then accept
+fn accept_incoming(matrix_client: &MatrixClient) -> Result<String, String> {
+    let deadline = deadline();
+    loop {
+        let accepted = with_crypto(matrix_client, None, |crypto| {
+            let request = crypto
+                .machine
+                .verifications()
+                .find(|sas| *sas.state() == SasState::RequestReceived)
+                .map(|sas| sas.flow_id().to_string());
+            if let Some(ref flow_id) = request {
+                crypto
+                    .machine
+                    .accept_verification(matrix_client, flow_id)
+                    .map_err(crypto_error_message)?;
+            }
+            Ok(request)
+        })?;
+        if let Some(flow_id) = accepted {
+            return Ok(flow_id);
+        }
+        if Instant::now() >= deadline {
+            return Err(String::from("Nobody asked to verify."));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+enum VerifyWith {
+    Device { user_id: String, device_id: String },
+    Incoming,
+}
+
+// Runs a verification until the short authentication string can be shown
+struct VerifyTask {
+    session: Session,
+    passphrase: String,
+    with: VerifyWith,
+}
+
+impl Task for VerifyTask {
+    type Output = Refreshed<(String, Option<Vec<Emoji>>, Option<(u16, u16, u16)>)>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            let passphrase = Some(self.passphrase.as_str());
+
+            let flow_id = match self.with {
+                VerifyWith::Device {
+                    ref user_id,
+                    ref device_id,
+                } => with_crypto(matrix_client, passphrase, |crypto| {
+                    crypto
+                        .machine
+                        .request_verification(matrix_client, user_id, device_id)
+                        .map_err(crypto_error_message)
+                })?,
+                VerifyWith::Incoming => {
+                    with_crypto(matrix_client, passphrase, |_| Ok(()))?;
+                    accept_incoming(matrix_client)?
+                }
+            };
+
+            let state = wait_for(matrix_client, &flow_id, |state| {
+                matches!(state, SasState::KeysExchanged | SasState::Cancelled { .. })
+            })?;
+            if let SasState::Cancelled { reason, .. } = state {
+                return Err(format!("The verification was cancelled: {}", reason));
+            }
+
+            with_crypto(matrix_client, None, |crypto| {
+                match crypto.machine.verification(&flow_id) {
+                    Some(sas) => Ok((flow_id.clone(), sas.emoji(), sas.decimals())),
+                    None => Err(String::from("The verification was not found.")),
+                }
+            })
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let ((flow_id, emoji, decimals), session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+
+        let response_obj = JsObject::new(&mut cx);
+        let flow_id = cx.string(flow_id);
+        response_obj.set(&mut cx, "flow_id", flow_id)?;
+        if let Some(emoji) = emoji {
+            let emoji_array = JsArray::new(&mut cx, emoji.len() as u32);
+            for (i, e) in emoji.into_iter().enumerate() {
+                let emoji_obj = JsObject::new(&mut cx);
+                let symbol = cx.string(e.symbol);
+                emoji_obj.set(&mut cx, "symbol", symbol)?;
+                let name = cx.string(e.name);
+                emoji_obj.set(&mut cx, "name", name)?;
+                emoji_array.set(&mut cx, i as u32, emoji_obj)?;
+            }
+            response_obj.set(&mut cx, "emoji", emoji_array)?;
+        }
+        if let Some((first, second, third)) = decimals {
+            let decimals_array = JsArray::new(&mut cx, 3);
+            for (i, decimal) in [first, second, third].iter().enumerate() {
+                let decimal = cx.number(*decimal as f64);
+                decimals_array.set(&mut cx, i as u32, decimal)?;
+            }
+            response_obj.set(&mut cx, "decimals", decimals_array)?;
+        }
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+// Sends the user's answer to whether the SAS matched and waits for the other side
+struct VerifyConfirmTask {
+    session: Session,
+    flow_id: String,
+    matches: bool,
+}
+
+impl Task for VerifyConfirmTask {
+    type Output = Refreshed<bool>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            with_crypto(matrix_client, None, |crypto| {
+                if self.matches {
+                    crypto
+                        .machine
+                        .confirm_verification(matrix_client, &self.flow_id)
+                        .map_err(crypto_error_message)
+                } else {
+                    crypto
+                        .machine
+                        .cancel_verification(
+                            matrix_client,
+                            &self.flow_id,
+                            cancel_code::MISMATCHED_SAS,
+                            "The short authentication strings didn't match",
+                        )
+                        .map_err(crypto_error_message)
+                }
+            })?;
+
+            let state = wait_for(matrix_client, &self.flow_id, |state| {
+                matches!(state, SasState::Done | SasState::Cancelled { .. })
+            })?;
+            with_crypto(matrix_client, None, |crypto| {
+                crypto
+                    .machine
+                    .save(&mut crypto.store)
+                    .map_err(|e| e.to_string())
+            })?;
+            Ok(state == SasState::Done)
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let (verified, session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+        let response_obj = JsObject::new(&mut cx);
+        let verified = cx.boolean(verified);
+        response_obj.set(&mut cx, "verified", verified)?;
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+enum RoomKeysAction {
+    Export,
+    Import(String),
+}
+
+// Exports or imports room keys in the armored format other clients use
+struct RoomKeysTask {
+    session: Session,
+    passphrase: String,
+    file_passphrase: String,
+    action: RoomKeysAction,
+}
+
+impl Task for RoomKeysTask {
+    type Output = Refreshed<(Option<String>, usize)>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            with_crypto(
+                matrix_client,
+                Some(self.passphrase.as_str()),
+                |crypto| match self.action {
+                    RoomKeysAction::Export => {
+                        let keys = crypto.machine.export_room_keys();
+                        let export = key_export::export_keys(
+                            &keys,
+                            &self.file_passphrase,
+                            key_export::DEFAULT_ROUNDS,
+                        )
+                        .map_err(crypto_error_message)?;
+                        Ok((Some(export), keys.len()))
+                    }
+                    RoomKeysAction::Import(ref export) => {
+                        let keys = key_export::import_keys(export, &self.file_passphrase)
+                            .map_err(crypto_error_message)?;
+                        let imported = crypto.machine.import_room_keys(&keys);
+                        crypto
+                            .machine
+                            .save(&mut crypto.store)
+                            .map_err(|e| e.to_string())?;
+                        Ok((None, imported))
+                    }
+                },
+            )
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let ((export, count), session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+
+        let response_obj = JsObject::new(&mut cx);
+        if let Some(export) = export {
+            let export = cx.string(export);
+            response_obj.set(&mut cx, "export", export)?;
+        }
+        let count = cx.number(count as f64);
+        response_obj.set(&mut cx, "count", count)?;
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+// Asks one of a user's devices to verify, `callback(err, { flow_id, emoji, decimals })` is
+// called once the SAS can be compared. Answer with verify_confirm. The other device's
+// answers arrive through sync, so keep syncing until the callback is called.
+fn verify_request(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let passphrase = cx.argument::<JsString>(1)?.value();
+    let user_id = cx.argument::<JsString>(2)?.value();
+    let device_id = cx.argument::<JsString>(3)?.value();
+    let callback = cx.argument::<JsFunction>(4)?;
+
+    let task = VerifyTask {
+        session,
+        passphrase,
+        with: VerifyWith::Device { user_id, device_id },
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Waits for another device to ask to verify and accepts, `callback(err, { flow_id, emoji,
+// decimals })` is called once the SAS can be compared. The request arrives through sync.
+fn verify_incoming(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let passphrase = cx.argument::<JsString>(1)?.value();
+    let callback = cx.argument::<JsFunction>(2)?;
+
+    let task = VerifyTask {
+        session,
+        passphrase,
+        with: VerifyWith::Incoming,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Whether the user saw the same SAS on both devices, `callback(err, { verified })` is called
+// when the verification finishes, which takes a sync to hear from the other device
+fn verify_confirm(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let flow_id = cx.argument::<JsString>(1)?.value();
+    let matches = cx.argument::<JsBoolean>(2)?.value();
+    let callback = cx.argument::<JsFunction>(3)?;
+
+    let task = VerifyConfirmTask {
+        session,
+        flow_id,
+        matches,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Encrypts every room key with file_passphrase, `callback(err, { export, count })` is called
+// with the armored export to write to a file
+fn export_room_keys(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let passphrase = cx.argument::<JsString>(1)?.value();
+    let file_passphrase = cx.argument::<JsString>(2)?.value();
+    let callback = cx.argument::<JsFunction>(3)?;
+
+    let task = RoomKeysTask {
+        session,
+        passphrase,
+        file_passphrase,
+        action: RoomKeysAction::Export,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Imports the room keys in an export made here or by another client, `callback(err, {
+// count })` is called with how many were new
+fn import_room_keys(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let passphrase = cx.argument::<JsString>(1)?.value();
+    let export = cx.argument::<JsString>(2)?.value();
+    let file_passphrase = cx.argument::<JsString>(3)?.value();
+    let callback = cx.argument::<JsFunction>(4)?;
+
+    let task = RoomKeysTask {
+        session,
+        passphrase,
+        file_passphrase,
+        action: RoomKeysAction::Import(export),
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Every room in a space down to max_depth, paging through the whole hierarchy
+struct SpaceHierarchyTask {
+    session: Session,
+    room_id: String,
+    max_depth: Option<u32>,
+}
+
+impl Task for SpaceHierarchyTask {
+    type Output = Refreshed<Vec<String>>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            let rooms = rooms::spaces::full_hierarchy(matrix_client, &self.room_id, self.max_depth)
+                .map_err(api_error_message)?;
+            rooms
+                .iter()
+                .map(|room| serde_json::to_string(room).map_err(|e| e.to_string()))
+                .collect()
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let (rooms, session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+
+        let rooms_array = JsArray::new(&mut cx, rooms.len() as u32);
+        for (i, room) in rooms.into_iter().enumerate() {
+            let room = cx.string(room);
+            rooms_array.set(&mut cx, i as u32, room)?;
+        }
+        let response_obj = JsObject::new(&mut cx);
+        response_obj.set(&mut cx, "rooms", rooms_array)?;
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+// Adds a room to a space, or removes it when suggested is None
+struct SpaceChildTask {
+    session: Session,
+    space_id: String,
+    room_id: String,
+    suggested: Option<bool>,
+}
+
+impl Task for SpaceChildTask {
+    type Output = Refreshed<()>;
+    type Error = Refreshed<String>;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        with_session(&self.session, |matrix_client| {
+            match self.suggested {
+                Some(suggested) => {
+                    let content = rooms::spaces::SpaceChildContent {
+                        via: rooms::spaces::server_name(&self.room_id)
+                            .map(String::from)
+                            .into_iter()
+                            .collect(),
+                        order: None,
+                        suggested,
+                    };
+                    rooms::spaces::add_child(matrix_client, &self.space_id, &self.room_id, &content)
+                }
+                None => rooms::spaces::remove_child(matrix_client, &self.space_id, &self.room_id),
+            }
+            .map(|_| ())
+            .map_err(api_error_message)
+        })
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<JsObject> {
+        let ((), session) = match result {
+            Ok(output) => output,
+            Err(error) => return throw_refreshed(cx, error),
+        };
+        let response_obj = JsObject::new(&mut cx);
+        set_session(&mut cx, response_obj, session)?;
+        Ok(response_obj)
+    }
+}
+
+// Lists a space's rooms, `callback(err, { rooms })` is called with each room's JSON, the
+// space itself first. Pass null as maxDepth to let the server decide.
+fn space_hierarchy(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let room_id = cx.argument::<JsString>(1)?.value();
+    let max_depth = match cx.argument::<JsValue>(2)?.downcast::<JsNumber>() {
+        Ok(max_depth) => Some(max_depth.value() as u32),
+        Err(_) => None,
+    };
+    let callback = cx.argument::<JsFunction>(3)?;
+
+    let task = SpaceHierarchyTask {
+        session,
+        room_id,
+        max_depth,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+// Adds the room to the space, `callback(err)` is called once it's listed
+fn space_add_child(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let space_id = cx.argument::<JsString>(1)?.value();
+    let room_id = cx.argument::<JsString>(2)?.value();
+    let suggested = cx.argument::<JsBoolean>(3)?.value();
+    let callback = cx.argument::<JsFunction>(4)?;
+
+    let task = SpaceChildTask {
+        session,
+        space_id,
+        room_id,
+        suggested: Some(suggested),
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
+fn space_remove_child(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let session = session_argument(&mut cx, 0)?;
+    let space_id = cx.argument::<JsString>(1)?.value();
+    let room_id = cx.argument::<JsString>(2)?.value();
+    let callback = cx.argument::<JsFunction>(3)?;
+
+    let task = SpaceChildTask {
+        session,
+        space_id,
+        room_id,
+        suggested: None,
+    };
+    task.schedule(callback);
+    Ok(cx.undefined())
+}
+
 register_module!(mut cx, {
-    cx.export_function("register_user", register_user)
+    cx.export_function("register_user", register_user)?;
+    cx.export_function("reset_password", reset_password)?;
+    cx.export_function("room_display", room_display)?;
+    cx.export_function("sync", sync_events)?;
+    cx.export_function("verify_request", verify_request)?;
+    cx.export_function("verify_incoming", verify_incoming)?;
+    cx.export_function("verify_confirm", verify_confirm)?;
+    cx.export_function("export_room_keys", export_room_keys)?;
+    cx.export_function("import_room_keys", import_room_keys)?;
+    cx.export_function("space_hierarchy", space_hierarchy)?;
+    cx.export_function("space_add_child", space_add_child)?;
+    cx.export_function("space_remove_child", space_remove_child)
 });